@@ -1,8 +1,9 @@
 use futures::stream::{BoxStream, SplitStream};
 use prost::Message;
+use std::collections::HashMap;
 use std::error::Error;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio_tungstenite::{
     connect_async,
     tungstenite::http::Request,
@@ -20,6 +21,7 @@ use reqwest::Client;
 use serde::Deserialize;
 use serde_json::json;
 use tokio::net::TcpStream;
+use tokio::sync::Mutex;
 use tokio::time::sleep;
 use tokio_retry::strategy::{jitter, ExponentialBackoff};
 use tokio_retry::Retry;
@@ -58,8 +60,13 @@ pub struct ClickPlanetRestClient {
     host: String,
     port: u16,
     secure: bool,
+    /// Sessions by country, as clicks must be for the country of their session
+    sessions: Mutex<HashMap<String, SessionResponse>>,
 }
 
+/// Sessions are renewed this long before they expire
+const SESSION_RENEWAL_MARGIN_SECS: u64 = 3600;
+
 pub const CLIENT_NAME: &'static str = "clickplanet client owned by valdo404";
 
 impl ClickPlanetRestClient {
@@ -77,9 +84,54 @@ impl ClickPlanetRestClient {
             host: base_url.to_string(),
             port,
             secure: secure,
+            sessions: Mutex::new(HashMap::new()),
         }
     }
 
+    fn base_url(&self) -> String {
+        format!("{}://{}:{}", if self.secure { "https" } else { "http" }, self.host, self.port)
+    }
+
+    /// Token of the session bound to `country_id`, opened or renewed when needed.
+    async fn session_token(&self, country_id: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut sessions = self.sessions.lock().await;
+        let now_secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        if let Some(session) = sessions.get(country_id) {
+            if session.expires_at_secs > now_secs + SESSION_RENEWAL_MARGIN_SECS {
+                return Ok(session.token.clone());
+            }
+        }
+
+        let request = clicks::SessionRequest {
+            token: sessions.get(country_id).map(|session| session.token.clone()).unwrap_or_default(),
+            country_id: country_id.to_string(),
+        };
+
+        let response: serde_json::Value = self.client
+            .post(format!("{}/v2/rpc/session", self.base_url()))
+            .header("User-Agent", CLIENT_NAME)
+            .header("Content-Type", "application/json")
+            .json(&json!({
+                "data": request.encode_to_vec(),
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let data = response["data"].as_str().ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid or missing data field in response"
+        ))?;
+        let session = SessionResponse::decode(STANDARD.decode(data)?.as_slice())?;
+        let token = session.token.clone();
+        sessions.insert(country_id.to_string(), session);
+
+        Ok(token)
+    }
+
 
     pub async fn click_tile(&self, tile_id: u32, country_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let request = clicks::ClickRequest {
@@ -100,10 +152,12 @@ impl ClickPlanetRestClient {
 
         let client = self.client.clone();
 
-        let result = Retry::spawn(retry_strategy, || async {
+        Retry::spawn(retry_strategy, || async {
+            let token = self.session_token(country_id).await?;
             let response = client
-                .post(format!("{}://{}:{}/v2/rpc/click", if self.secure { "https" } else { "http" }, base_url, self.port))
+                .post(format!("{}/v2/rpc/click", self.base_url()))
                 .header("User-Agent", CLIENT_NAME)
+                .header("Authorization", format!("Bearer {}", token))
                 .header("Content-Type", "application/json")
                 .header("Origin", format!("https://{}", base_url))
                 .header("Referer", format!("https://{}/", base_url))
@@ -113,13 +167,13 @@ impl ClickPlanetRestClient {
                 .send()
                 .await?;
 
-            response.error_for_status().map(|_| ())
-        }).await;
+            // Sessions signed by a server that restarted since are refused, open a new one
+            if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+                self.sessions.lock().await.remove(country_id);
+            }
 
-        match result {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Box::new(e)),
-        }
+            response.error_for_status().map(|_| ()).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)
+        }).await
     }
 
     pub async fn get_ownerships_by_batch(
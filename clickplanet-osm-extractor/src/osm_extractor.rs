@@ -1,6 +1,7 @@
 use std::fs::File;
 use std::io::BufReader;
 use clap::Parser;
+use clickplanet_telemetry::{init_telemetry, TelemetryArgs};
 use deadpool_postgres::{Client, PoolError};
 use osmpbf::{Blob, BlobDecode, BlobReader, Node, TagIter, Way};
 use serde_json;
@@ -10,7 +11,6 @@ use tokio_postgres::{Config as PgConfig, NoTls};
 use log::{error, warn, info};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Registry};
 use futures::stream::unfold;
 use futures::StreamExt;
 use rayon::iter::{IntoParallelRefIterator, ParallelBridge};
@@ -31,25 +31,6 @@ struct Road {
     nodes: Vec<i64>, // List of node IDs forming the road
 }
 
-fn init_logger() {
-    let fmt_layer = fmt::layer()
-        .with_target(false) // Disable target display
-        .with_thread_ids(true) // Include thread IDs
-        .with_thread_names(true) // Include thread names
-        .with_file(true) // Include file names
-        .with_line_number(true); // Include line numbers
-
-    let env_filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new("info"));
-
-    let subscriber = Registry::default()
-        .with(env_filter)
-        .with(fmt_layer);
-
-    tracing_log::LogTracer::init().unwrap();
-    tracing::subscriber::set_global_default(subscriber).unwrap();
-}
-
 #[derive(Parser)]
 #[command(name = "OSM Extractor")]
 #[command(author = "Laurent Valdes")]
@@ -61,15 +42,20 @@ struct Args {
 
     #[arg(short, long, default_value = "test")]
     db_url: String,
+
+    #[command(flatten)]
+    telemetry: TelemetryArgs,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    init_logger();
-
     let args = Args::parse();
+    let telemetry = init_telemetry(&args.telemetry, "osm-extractor")?;
+
+    let result = extract_osm_to_db(&args.osm_file, &args.db_url).await;
 
-    extract_osm_to_db(&args.osm_file, &args.db_url).await?;
+    telemetry.shutdown().await;
+    result?;
 
     println!("Extraction complete. Data saved to Postgres/PostGIS database.");
     Ok(())
@@ -14,6 +14,7 @@ use rayon::ThreadPool;
 use tokio::runtime::Runtime;
 use tokio::time::{sleep, timeout};
 use clickplanet_proto::clicks::{OwnershipState, UpdateNotification};
+use tracing::{error, info};
 
 #[derive(Clone)]
 pub struct CountryWatchguard {
@@ -65,14 +66,14 @@ impl CountryWatchguard {
             .map(move |update| {
                 let this = this.clone();
 
-                println!(
+                info!(
                     "Detected unauthorized change on tile {}: {} -> {}. Reclaiming...",
                     update.tile_id, update.previous_country_id, update.country_id
                 );
 
                 async move {
                     if let Err(e) = this.claim_tile(&(update.tile_id as u32)).await {
-                        eprintln!("Error processing tile: {}", e);
+                        error!("Error processing tile: {}", e);
                     }
                 }
             })
@@ -85,22 +86,21 @@ impl CountryWatchguard {
 
     async fn periodic_claim_check(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         loop {
-            println!("Performing periodic claim check...");
+            info!("Performing periodic claim check...");
             self.claim_all_tiles().await?;
-            println!();
-            println!("Checker task completed");
+            info!("Checker task completed");
 
             sleep(Duration::from_secs(120)).await;
         }
     }
 
     pub async fn run(self, handle: tokio::runtime::Handle) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        println!(
+        info!(
             "Starting watchguard for target country: {} / wanted country: {}",
             self.target_country,
             self.wanted_country
         );
-        println!("Monitoring {} tiles", self.country_tiles.len());
+        info!("Monitoring {} tiles", self.country_tiles.len());
 
 
         // Clone self for the second task
@@ -116,7 +116,7 @@ impl CountryWatchguard {
 
         tokio::select! {
             res = monitor => {
-                println!("Monitor task completed: {:?}", res);
+                info!("Monitor task completed: {:?}", res);
                 res.unwrap_or_else(|e| Err(Box::new(e) as Box<dyn Error + Send + Sync>))
             }
             res = checker => res.unwrap_or_else(|e| Err(Box::new(e) as Box<dyn Error + Send + Sync>))
@@ -134,15 +134,15 @@ impl CountryWatchguard {
         let ownerships: OwnershipState = self.client.get_ownerships(&(arc)).await?;
 
         let tiles_to_claim: HashSet<_> = self.find_tile_to_claim(ownerships);
-        println!("Need to claim {} tiles", tiles_to_claim.len());
+        info!("Need to claim {} tiles", tiles_to_claim.len());
 
         futures::stream::iter(
             tiles_to_claim.into_par_iter()
                 .map(|tile_id| async move {
-                    println!("Claiming tile {}", tile_id);
+                    info!("Claiming tile {}", tile_id);
 
                     if let Err(e) = self.claim_tile(&tile_id).await {
-                        eprintln!("Failed to claim tile {}: {}", tile_id, e);
+                        error!("Failed to claim tile {}: {}", tile_id, e);
                     }
                 })
                 .collect::<Vec<_>>()
@@ -161,15 +161,15 @@ impl CountryWatchguard {
         ).await {
             Ok(result) => match result {
                 Ok(_) => {
-                    println!("Claimed tile {}", tile_id);
+                    info!("Claimed tile {}", tile_id);
                 }
                 Err(e) => {
-                    eprintln!("Failed to claim tile {}: {}", tile_id, e);
+                    error!("Failed to claim tile {}: {}", tile_id, e);
                     return Err(e);
                 }
             },
             Err(_) => {
-                eprintln!("Timeout while claiming tile {}", tile_id);
+                error!("Timeout while claiming tile {}", tile_id);
                 return Err("Operation timed out after 5 seconds".into());
             }
         }
@@ -7,9 +7,11 @@ use crate::coordinates::{read_coordinates_from_file, CoordinatesData, TileCoordi
 use crate::country_watchguard::CountryWatchguard;
 use crate::geolookup::{CountryTilesMap, GeoLookup};
 use clap::Parser;
+use clickplanet_telemetry::{init_telemetry, TelemetryArgs};
 use futures::StreamExt;
 use std::error::Error;
 use std::sync::Arc;
+use tracing::info;
 
 use clickplanet_client::ClickPlanetRestClient;
 
@@ -38,15 +40,26 @@ struct Args {
     /// Path to geojson file
     #[arg(long, default_value = "countries.geojson")]
     geojson_file: String,
+
+    #[command(flatten)]
+    telemetry: TelemetryArgs,
 }
 
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
+    let telemetry = init_telemetry(&args.telemetry, "country-watchguard")?;
     rustls::crypto::ring::default_provider().install_default()
         .expect("Failed to install crypto provider");
 
+    let result = watch(&args).await;
+
+    telemetry.shutdown().await;
+    result
+}
+
+async fn watch(args: &Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let runtime_handle = tokio::runtime::Handle::current();
     let coordinates: CoordinatesData = read_coordinates_from_file(&args.coordinates_file)?;
     let index_coordinates: TileCoordinatesMap = coordinates.into();
@@ -55,7 +68,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let country_tile_map = CountryTilesMap::load_or_build(&geolookup, &index_coordinates)?;
     let client: ClickPlanetRestClient = ClickPlanetRestClient::new(&args.host, args.port, !args.unsecure);
 
-    println!("Initializing watchguard for {} -> {}", args.target_country, args.wanted_country);
+    info!("Initializing watchguard for {} -> {}", args.target_country, args.wanted_country);
 
     let watchguard: CountryWatchguard = CountryWatchguard::new(
         Arc::new(client),
@@ -6,6 +6,7 @@ use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use tokio::time::sleep;
 use clickplanet_proto::clicks::{UpdateNotification};
 use clickplanet_client::TileCount;
+use tracing::{error, info};
 
 #[derive(Clone)]
 pub struct TileSyncer {
@@ -67,15 +68,15 @@ impl TileSyncer {
 
     async fn sync_tiles(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let diffs = self.compute_ownership_diff().await?;
-        println!("Found {} tiles with different ownership", diffs.len());
+        info!("Found {} tiles with different ownership", diffs.len());
 
         futures::stream::iter(
             diffs.into_par_iter()
                 .map(|diff| async move {
                     if let Err(e) = self.local_client.click_tile(diff.tile_id, &diff.prod_country).await {
-                        eprintln!("Failed to sync tile {}: {}", diff.tile_id, e);
+                        error!("Failed to sync tile {}: {}", diff.tile_id, e);
                     } else {
-                        println!(
+                        info!(
                             "Successfully synced tile {} from {:?} to {}",
                             diff.tile_id, diff.local_country, diff.prod_country
                         );
@@ -91,15 +92,15 @@ impl TileSyncer {
     }
 
     async fn handle_update(&self, update: UpdateNotification) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        println!(
+        info!(
             "Received update for tile {}: applying new ownership {}",
             update.tile_id, update.country_id
         );
 
         if let Err(e) = self.local_client.click_tile(update.tile_id.try_into().unwrap(), &update.country_id).await {
-            eprintln!("Failed to sync update for tile {}: {}", update.tile_id, e);
+            error!("Failed to sync update for tile {}: {}", update.tile_id, e);
         } else {
-            println!("Successfully synced tile {} to {}", update.tile_id, update.country_id);
+            info!("Successfully synced tile {} to {}", update.tile_id, update.country_id);
         }
 
         Ok(())
@@ -111,7 +112,7 @@ impl TileSyncer {
         updates
             .for_each_concurrent(8, |update| async {
                 if let Err(e) = self.handle_update(update).await {
-                    eprintln!("Error handling update: {}", e);
+                    error!("Error handling update: {}", e);
                 }
             })
             .await;
@@ -120,11 +121,11 @@ impl TileSyncer {
     }
 
     pub async fn run(self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        println!("Starting diff-based tile sync between production and local");
+        info!("Starting diff-based tile sync between production and local");
 
         // Do an initial full diff and sync
         if let Err(e) = self.sync_tiles().await {
-            eprintln!("Error during initial sync: {}", e);
+            error!("Error during initial sync: {}", e);
         }
 
         // Clone for the periodic task
@@ -135,7 +136,7 @@ impl TileSyncer {
             loop {
                 sleep(Duration::from_secs(300)).await; // Run every 5 minutes
                 if let Err(e) = periodic_syncer.sync_tiles().await {
-                    eprintln!("Error during periodic sync: {}", e);
+                    error!("Error during periodic sync: {}", e);
                 }
             }
         });
@@ -144,7 +145,7 @@ impl TileSyncer {
         let update_handle = tokio::spawn(async move {
             loop {
                 if let Err(e) = self.monitor_updates().await {
-                    eprintln!("Error in update monitoring: {}. Reconnecting...", e);
+                    error!("Error in update monitoring: {}. Reconnecting...", e);
                     sleep(Duration::from_secs(1)).await;
                 }
             }
@@ -153,10 +154,10 @@ impl TileSyncer {
         // Wait for either task to finish (they shouldn't under normal circumstances)
         tokio::select! {
             result = periodic_handle => {
-                println!("Periodic sync task ended: {:?}", result);
+                info!("Periodic sync task ended: {:?}", result);
             }
             result = update_handle => {
-                println!("Update monitor task ended: {:?}", result);
+                info!("Update monitor task ended: {:?}", result);
             }
         }
 
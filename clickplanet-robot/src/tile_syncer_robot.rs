@@ -4,8 +4,10 @@ mod model;
 
 use std::sync::Arc;
 use clap::Parser;
+use clickplanet_telemetry::{init_telemetry, TelemetryArgs};
 use clickplanet_client::ClickPlanetRestClient;
 use crate::tile_syncer::TileSyncer;
+use tracing::info;
 use crate::coordinates::{read_coordinates_from_file, CoordinatesData, TileCoordinatesMap};
 
 #[derive(Parser, Debug)]
@@ -37,15 +39,26 @@ struct Args {
     /// Disable TLS for local server (unsecure by default)
     #[arg(long, default_value_t = true)]
     local_unsecure: bool,
+
+    #[command(flatten)]
+    telemetry: TelemetryArgs,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
+    let telemetry = init_telemetry(&args.telemetry, "tile-syncer")?;
 
     rustls::crypto::ring::default_provider().install_default()
         .expect("Failed to install crypto provider");
 
+    let result = sync(&args).await;
+
+    telemetry.shutdown().await;
+    result
+}
+
+async fn sync(args: &Args) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let prod_client = ClickPlanetRestClient::new(
         &args.prod_host,
         args.prod_port,
@@ -57,7 +70,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         !args.local_unsecure,
     );
 
-    println!("Initializing tile sync between {} and {}", args.prod_host, args.local_host);
+    info!("Initializing tile sync between {} and {}", args.prod_host, args.local_host);
 
     let coordinates: CoordinatesData = read_coordinates_from_file(&args.coordinates_file)?;
     let index_coordinates: TileCoordinatesMap = coordinates.into();
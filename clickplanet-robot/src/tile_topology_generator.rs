@@ -0,0 +1,66 @@
+mod coordinates;
+mod model;
+
+use crate::coordinates::{read_coordinates_from_file, CoordinatesData};
+use clap::Parser;
+use clickplanet_topology::{TileTopology, TopologyBuilder};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Generates the tile neighbour graph", long_about = None)]
+struct Args {
+    #[arg(long, default_value = "coordinates.json")]
+    coordinates_file: String,
+
+    /// Path of the binary topology file to write
+    #[arg(long, default_value = "tile_topology.bin")]
+    output_file: String,
+
+    /// Maximum number of neighbours considered per tile
+    #[arg(long, default_value_t = 6)]
+    max_neighbours: usize,
+
+    /// Neighbours further than this factor times the closest neighbour are discarded
+    #[arg(long, default_value_t = 1.5)]
+    distance_tolerance: f64,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let args = Args::parse();
+
+    let coordinates: CoordinatesData = read_coordinates_from_file(&args.coordinates_file)?;
+    println!("Building neighbour graph for {} tiles", coordinates.length());
+
+    let topology = TopologyBuilder::new()
+        .with_max_neighbours(args.max_neighbours)
+        .with_distance_tolerance(args.distance_tolerance)
+        .build(&coordinates.positions)?;
+
+    let components = topology.connected_components();
+    println!(
+        "Graph has {} edges and {} connected components (largest: {} tiles)",
+        topology.edge_count(),
+        components.len(),
+        components.first().map_or(0, |c| c.len())
+    );
+
+    let isolated = (0..topology.tile_count() as u32)
+        .filter(|&tile_id| topology.degree(tile_id) == 0)
+        .count();
+    if isolated > 0 {
+        eprintln!("Warning: {} tiles have no neighbours", isolated);
+    }
+
+    topology.write_to_file(&args.output_file)?;
+
+    // Sanity check that what we wrote reads back identically
+    let written = TileTopology::read_from_file(&args.output_file)?;
+    written.verify_source(&coordinates.positions)?;
+
+    println!(
+        "Wrote {} (checksum {:016x})",
+        args.output_file,
+        written.source_checksum()
+    );
+
+    Ok(())
+}
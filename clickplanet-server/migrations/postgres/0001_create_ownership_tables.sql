@@ -0,0 +1,27 @@
+-- Current owner of every claimed tile
+CREATE TABLE tile_ownerships (
+    tile_id INTEGER PRIMARY KEY,
+    country_id TEXT NOT NULL,
+    timestamp_ns BIGINT NOT NULL
+);
+
+CREATE INDEX tile_ownerships_country_id_idx ON tile_ownerships (country_id);
+
+-- Append-only log of every applied ownership change
+CREATE TABLE ownership_changes (
+    id BIGSERIAL PRIMARY KEY,
+    tile_id INTEGER NOT NULL,
+    country_id TEXT NOT NULL,
+    previous_country_id TEXT,
+    timestamp_ns BIGINT NOT NULL,
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX ownership_changes_tile_id_idx ON ownership_changes (tile_id, timestamp_ns);
+CREATE INDEX ownership_changes_timestamp_ns_idx ON ownership_changes (timestamp_ns);
+
+-- Tile count per country, maintained with tile_ownerships
+CREATE TABLE country_scores (
+    country_id TEXT PRIMARY KEY,
+    score INTEGER NOT NULL CHECK (score >= 0)
+);
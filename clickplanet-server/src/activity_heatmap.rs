@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clickplanet_proto::clicks::{Click, CountryActivity, MapDensityResponse};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::invasion_flows::FlowWindow;
+use crate::metrics::METRICS;
+
+const WINDOWS: [FlowWindow; 3] = [FlowWindow::FiveMinutes, FlowWindow::Hour, FlowWindow::Day];
+
+// Countries whose activity decayed below this are left out of snapshots
+const MIN_COUNTRY_ACTIVITY: f64 = 0.001;
+
+fn window_index(window: FlowWindow) -> usize {
+    match window {
+        FlowWindow::FiveMinutes => 0,
+        FlowWindow::Hour => 1,
+        FlowWindow::Day => 2,
+    }
+}
+
+/// Click count decaying exponentially, with one value per window using the window
+/// duration as time constant.
+#[derive(Debug, Default, Clone, Copy)]
+struct DecayedCounter {
+    values: [f64; 3],
+    updated_at: f64,
+}
+
+impl DecayedCounter {
+    fn value_at(&self, window: FlowWindow, now_secs: f64) -> f64 {
+        let elapsed = (now_secs - self.updated_at).max(0.0);
+        self.values[window_index(window)] * (-elapsed / window.duration_secs() as f64).exp()
+    }
+
+    fn increment_at(&mut self, now_secs: f64) {
+        for window in WINDOWS {
+            let index = window_index(window);
+            self.values[index] = self.value_at(window, now_secs) + 1.0;
+        }
+        self.updated_at = self.updated_at.max(now_secs);
+    }
+}
+
+struct HeatmapState {
+    tiles: Vec<DecayedCounter>,
+    countries: HashMap<String, DecayedCounter>,
+}
+
+/// Decayed click counters per tile and per country, used to render battle hot-spots.
+///
+/// Counters are only touched when a click lands on them and decayed lazily when read,
+/// so recording a click is O(1) whatever the map size.
+pub struct ActivityHeatmap {
+    state: Mutex<HeatmapState>,
+}
+
+impl ActivityHeatmap {
+    /// Clicks on tile ids at or above `tile_count` are ignored.
+    pub fn new(tile_count: usize) -> Self {
+        Self {
+            state: Mutex::new(HeatmapState {
+                tiles: vec![DecayedCounter::default(); tile_count],
+                countries: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn record(&self, click: &Click) {
+        self.record_at(click, now_secs());
+    }
+
+    fn record_at(&self, click: &Click, now_secs: f64) {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(tile) = usize::try_from(click.tile_id)
+            .ok()
+            .and_then(|tile_id| state.tiles.get_mut(tile_id))
+        else {
+            return;
+        };
+        tile.increment_at(now_secs);
+
+        state.countries
+            .entry(click.country_id.clone())
+            .or_default()
+            .increment_at(now_secs);
+    }
+
+    pub fn snapshot(&self, window: FlowWindow) -> MapDensityResponse {
+        self.snapshot_at(window, now_secs())
+    }
+
+    fn snapshot_at(&self, window: FlowWindow, now_secs: f64) -> MapDensityResponse {
+        let state = self.state.lock().unwrap();
+
+        let tile_densities: Vec<f32> = state.tiles
+            .iter()
+            .map(|counter| counter.value_at(window, now_secs) as f32)
+            .collect();
+
+        let mut countries: Vec<CountryActivity> = state.countries
+            .iter()
+            .map(|(country_id, counter)| (country_id, counter.value_at(window, now_secs)))
+            .filter(|(_, clicks)| *clicks >= MIN_COUNTRY_ACTIVITY)
+            .map(|(country_id, clicks)| CountryActivity {
+                country_id: country_id.clone(),
+                clicks: clicks as f32,
+            })
+            .collect();
+        countries.sort_by(|a, b| {
+            b.clicks
+                .total_cmp(&a.clicks)
+                .then_with(|| a.country_id.cmp(&b.country_id))
+        });
+
+        let total: f64 = tile_densities.iter().map(|&density| density as f64).sum();
+
+        MapDensityResponse {
+            density: total.round() as i32,
+            window: window.as_str().to_string(),
+            max_density: tile_densities.iter().copied().fold(0.0, f32::max),
+            tile_densities,
+            countries,
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, mut clicks: broadcast::Receiver<Click>) {
+        info!("Starting activity heatmap");
+
+        loop {
+            match clicks.recv().await {
+                Ok(click) => self.record(&click),
+                Err(RecvError::Lagged(skipped)) => {
+                    METRICS.broadcast_lag_events.with("activity_heatmap").inc();
+                    warn!("Activity heatmap missed {} clicks", skipped);
+                }
+                Err(RecvError::Closed) => {
+                    info!("Click channel closed, stopping activity heatmap");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: f64 = 1_700_000_000.0;
+
+    fn click(tile_id: i32, country_id: &str) -> Click {
+        Click {
+            tile_id,
+            country_id: country_id.to_string(),
+            timestamp_ns: 0,
+            click_id: String::new(),
+            session_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_dense_snapshot() {
+        let heatmap = ActivityHeatmap::new(4);
+
+        heatmap.record_at(&click(1, "fr"), NOW);
+        heatmap.record_at(&click(1, "de"), NOW);
+        heatmap.record_at(&click(3, "fr"), NOW);
+        heatmap.record_at(&click(3, "fr"), NOW);
+        heatmap.record_at(&click(3, "fr"), NOW);
+        // Out of the map, ignored
+        heatmap.record_at(&click(4, "fr"), NOW);
+        heatmap.record_at(&click(-1, "fr"), NOW);
+
+        let snapshot = heatmap.snapshot_at(FlowWindow::Hour, NOW);
+        assert_eq!(snapshot.window, "1h");
+        assert_eq!(snapshot.tile_densities, vec![0.0, 2.0, 0.0, 3.0]);
+        assert_eq!(snapshot.max_density, 3.0);
+        assert_eq!(snapshot.density, 5);
+        assert_eq!(
+            snapshot.countries,
+            vec![
+                CountryActivity { country_id: "fr".to_string(), clicks: 4.0 },
+                CountryActivity { country_id: "de".to_string(), clicks: 1.0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decay_per_window() {
+        let heatmap = ActivityHeatmap::new(1);
+        heatmap.record_at(&click(0, "fr"), NOW);
+
+        let later = NOW + 5.0 * 60.0;
+        let short = heatmap.snapshot_at(FlowWindow::FiveMinutes, later).tile_densities[0];
+        let hour = heatmap.snapshot_at(FlowWindow::Hour, later).tile_densities[0];
+        let day = heatmap.snapshot_at(FlowWindow::Day, later).tile_densities[0];
+
+        assert!((short - (-1.0f32).exp()).abs() < 1e-6);
+        assert!(short < hour && hour < day && day < 1.0);
+
+        // A new click adds to the decayed value
+        heatmap.record_at(&click(0, "fr"), later);
+        let short_after = heatmap.snapshot_at(FlowWindow::FiveMinutes, later).tile_densities[0];
+        assert!((short_after - (1.0 + (-1.0f32).exp())).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_inactive_countries_dropped() {
+        let heatmap = ActivityHeatmap::new(1);
+        heatmap.record_at(&click(0, "fr"), NOW);
+
+        let snapshot = heatmap.snapshot_at(FlowWindow::FiveMinutes, NOW + 24.0 * 60.0 * 60.0);
+        assert!(snapshot.countries.is_empty());
+        assert_eq!(snapshot.density, 0);
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use clickplanet_proto::clicks::Click;
+use futures::stream::BoxStream;
+use thiserror::Error;
+
+use crate::nats_commons::ConsumerConfig;
+
+/// How long published clicks stay available to consumers by default, see `stream.retention_secs`.
+pub const CLICK_RETENTION: Duration = Duration::from_secs(8 * 60 * 60);
+
+#[derive(Error, Debug)]
+pub enum ClickBusError {
+    #[error("Failed to connect to the click bus: {0}")]
+    Connection(String),
+    #[error("Failed to publish click: {0}")]
+    Publish(String),
+    #[error("Failed to subscribe: {0}")]
+    Subscribe(String),
+    #[error("Failed to receive click: {0}")]
+    Receive(String),
+    #[error("Failed to acknowledge click: {0}")]
+    Ack(String),
+    #[error("Failed to dead-letter click: {0}")]
+    DeadLetter(String),
+    #[error("Failed to read consumer status: {0}")]
+    Status(String),
+    #[error("Click log error: {0}")]
+    Log(#[from] std::io::Error),
+}
+
+#[async_trait]
+pub trait Acker: Send {
+    async fn ack(self: Box<Self>) -> Result<(), ClickBusError>;
+
+    /// Sets the click aside with the reason it could not be processed, then acknowledges it.
+    async fn dead_letter(self: Box<Self>, reason: &str) -> Result<(), ClickBusError>;
+}
+
+/// A click handed to a consumer, to be acknowledged once processed.
+pub struct Delivery {
+    /// Position of the click in the bus, following the publication order
+    pub sequence: u64,
+    /// Number of times the click was delivered, this one included
+    pub delivery_count: u64,
+    pub click: Click,
+    acker: Box<dyn Acker>,
+}
+
+impl Delivery {
+    pub fn new(sequence: u64, delivery_count: u64, click: Click, acker: Box<dyn Acker>) -> Self {
+        Self { sequence, delivery_count, click, acker }
+    }
+
+    pub async fn ack(self) -> Result<(), ClickBusError> {
+        self.acker.ack().await
+    }
+
+    pub async fn dead_letter(self, reason: &str) -> Result<(), ClickBusError> {
+        self.acker.dead_letter(reason).await
+    }
+}
+
+pub type ClickSubscription = BoxStream<'static, Result<Delivery, ClickBusError>>;
+
+/// Progress of a consumer through the clicks of the bus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConsumerStatus {
+    /// Sequence of the last published click
+    pub last_sequence: u64,
+    /// Clicks published but not yet processed by the consumer
+    pub lag: u64,
+}
+
+/// Carries clicks from the servers receiving them to the services applying them, as seen by
+/// the consumers. Servers publish through a [`ClickPublisher`](crate::click_publisher::ClickPublisher).
+#[async_trait]
+pub trait ClickBus: Send + Sync {
+    /// Clicks not yet delivered to the durable consumer `consumer_name`, starting with the
+    /// oldest retained one for a new consumer. Subscriptions sharing a name share the clicks.
+    ///
+    /// With a `start_sequence` in the config, every click from that sequence is delivered
+    /// to this subscription alone instead.
+    async fn subscribe(&self, consumer_name: &str, config: &ConsumerConfig) -> Result<ClickSubscription, ClickBusError>;
+
+    /// Waits for the clicks and acknowledgments sent so far to leave the process.
+    async fn flush(&self) -> Result<(), ClickBusError>;
+
+    fn is_connected(&self) -> bool;
+
+    /// Status of the last subscription made by this process as `consumer_name`: the one given
+    /// a start sequence, rather than the durable consumer it does not read, after a replay.
+    async fn consumer_status(&self, consumer_name: &str) -> Result<ConsumerStatus, ClickBusError>;
+}
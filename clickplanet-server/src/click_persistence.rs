@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 use axum::async_trait;
 use thiserror::Error;
 use clickplanet_proto::clicks::{Click, Ownership, OwnershipState, UpdateNotification};
@@ -25,7 +24,27 @@ pub trait ClickRepository: Send + Sync {
         end_tile_id: u32,
     ) -> Result<OwnershipState, ClickRepositoryError>;
 
+    /// Applies the click if it is newer than the current ownership, returning the previous one.
+    ///
+    /// A click with an empty country id clears the tile. Its timestamp is kept so that older
+    /// clicks stay ignored, but the tile counts for no country.
     async fn save_click(&self, tile_id: u32, click: &Click) -> Result<Option<Ownership>, ClickRepositoryError>;
+
+    /// Saves clicks on distinct tiles, outdated ones being ignored as with `save_click`.
+    async fn save_clicks(&self, clicks: &[Click]) -> Result<(), ClickRepositoryError> {
+        for click in clicks {
+            self.save_click(click.tile_id as u32, click).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Last click bus sequence written to the storage by the persister.
+#[async_trait]
+pub trait PersistenceCheckpoint: Send + Sync {
+    async fn save_checkpoint(&self, sequence: u64) -> Result<(), ClickRepositoryError>;
+
+    async fn checkpoint(&self) -> Result<Option<u64>, ClickRepositoryError>;
 }
 
 #[derive(Error, Debug)]
@@ -44,35 +63,3 @@ pub trait LeaderboardRepository: Send + Sync {
     async fn get_score(&self, country_id: &str) -> Result<u32, LeaderboardError>;
     async fn leaderboard(&self) -> Result<std::collections::HashMap<String, u32>, LeaderboardError>;
 }
-
-pub struct LeaderboardOnClicks<T: ClickRepository>(pub T);
-
-#[async_trait]
-impl<T: ClickRepository + Send + Sync> LeaderboardRepository for LeaderboardOnClicks<T> {
-    async fn get_score(&self, country_id: &str) -> Result<u32, LeaderboardError> {
-        let ownership_state = self.0.get_ownerships()
-            .await
-            .map_err(|e| LeaderboardError::StorageError(e.to_string()))?;
-
-        let score = ownership_state.ownerships
-            .iter()
-            .filter(|ownership| ownership.country_id == country_id)
-            .count() as u32;
-
-        Ok(score)
-    }
-
-    async fn leaderboard(&self) -> Result<HashMap<String, u32>, LeaderboardError> {
-        let ownership_state = self.0.get_ownerships()
-            .await
-            .map_err(|e| LeaderboardError::StorageError(e.to_string()))?;
-
-        let mut scores: HashMap<String, u32> = HashMap::new();
-
-        for ownership in ownership_state.ownerships {
-            *scores.entry(ownership.country_id).or_insert(0) += 1;
-        }
-
-        Ok(scores)
-    }
-}
\ No newline at end of file
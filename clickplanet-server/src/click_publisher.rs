@@ -0,0 +1,224 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_nats::jetstream;
+use async_nats::jetstream::kv;
+use async_trait::async_trait;
+use clickplanet_proto::clicks::{Click, SystemMessage};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use prost::Message;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::error;
+
+use crate::admin::Restrictions;
+use crate::click_bus::{ClickBus, ClickBusError};
+use crate::jetstream_click_bus::JetStreamClickBus;
+use crate::nats_commons::{CLICK_STREAM_NAME, CLICK_SUBJECT_PREFIX};
+
+const HISTORY_FETCH_SIZE: usize = 1000;
+/// Key-value bucket of the state shared by the `/admin` API of every server
+const ADMIN_BUCKET: &str = "CLICKPLANET_ADMIN";
+const RESTRICTIONS_KEY: &str = "restrictions";
+/// Core NATS subject of the system messages, only sent to the servers connected at the time
+const SYSTEM_MESSAGE_SUBJECT: &str = "clickplanet.system_messages";
+
+/// The click bus as used by the servers taking clicks in, on top of consuming them. Kept out
+/// of [`ClickBus`] so that the persister, which only consumes, does not build it.
+#[async_trait]
+pub trait ClickPublisher: ClickBus {
+    async fn publish(&self, click: &Click) -> Result<(), ClickBusError>;
+
+    /// Every click retained when called, in publication order, read as the stream is polled.
+    /// Consumers are left untouched.
+    async fn history(&self) -> Result<BoxStream<'static, Result<Click, ClickBusError>>, ClickBusError>;
+
+    /// Shares the restrictions of the `/admin` API with the other servers of the bus.
+    async fn publish_restrictions(&self, restrictions: &Restrictions) -> Result<(), ClickBusError>;
+
+    /// The restrictions published by any server, starting with the latest one.
+    async fn restriction_updates(&self) -> Result<BoxStream<'static, Restrictions>, ClickBusError>;
+
+    /// Sends a message to the players connected to any server of the bus.
+    async fn publish_system_message(&self, message: &SystemMessage) -> Result<(), ClickBusError>;
+
+    /// The system messages published by any server from now on.
+    async fn system_messages(&self) -> Result<BoxStream<'static, SystemMessage>, ClickBusError>;
+
+    /// The same bus, for the services consuming it.
+    fn as_click_bus(self: Arc<Self>) -> Arc<dyn ClickBus>;
+}
+
+impl JetStreamClickBus {
+    async fn admin_store(&self) -> Result<kv::Store, ClickBusError> {
+        if let Ok(store) = self.jetstream.get_key_value(ADMIN_BUCKET).await {
+            return Ok(store);
+        }
+
+        self.jetstream
+            .create_key_value(kv::Config {
+                bucket: ADMIN_BUCKET.to_string(),
+                history: 1,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| ClickBusError::Connection(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl ClickPublisher for JetStreamClickBus {
+    async fn publish(&self, click: &Click) -> Result<(), ClickBusError> {
+        let subject = format!("{}{}", CLICK_SUBJECT_PREFIX, click.tile_id);
+
+        // The server acknowledgment is not awaited, clicks are answered as soon as they are sent
+        self.jetstream
+            .publish(subject, click.encode_to_vec().into())
+            .await
+            .map_err(|e| ClickBusError::Publish(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn history(&self) -> Result<BoxStream<'static, Result<Click, ClickBusError>>, ClickBusError> {
+        let mut stream = self.jetstream
+            .get_stream(CLICK_STREAM_NAME)
+            .await
+            .map_err(|e| ClickBusError::Receive(e.to_string()))?;
+        // Clicks published while reading are left out
+        let last_sequence = stream.info().await.map_err(|e| ClickBusError::Receive(e.to_string()))?.state.last_sequence;
+
+        // Ephemeral consumer reading the whole stream without acknowledging anything
+        let reader = stream
+            .create_consumer(jetstream::consumer::pull::Config {
+                deliver_policy: jetstream::consumer::DeliverPolicy::All,
+                ack_policy: jetstream::consumer::AckPolicy::None,
+                inactive_threshold: Duration::from_secs(60),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| ClickBusError::Subscribe(e.to_string()))?;
+
+        // One batch ahead of the reader, which stops the fetches by dropping the stream
+        let (sender, receiver) = tokio::sync::mpsc::channel(HISTORY_FETCH_SIZE);
+        tokio::spawn(async move {
+            let mut sequence = 0;
+            while sequence < last_sequence {
+                let mut messages = match reader.fetch().max_messages(HISTORY_FETCH_SIZE).messages().await {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        let _ = sender.send(Err(ClickBusError::Receive(e.to_string()))).await;
+                        return;
+                    }
+                };
+
+                let mut fetched = false;
+                while let Some(message) = messages.next().await {
+                    let sequenced = message
+                        .map_err(|e| ClickBusError::Receive(e.to_string()))
+                        .and_then(|message| match message.info() {
+                            Ok(info) => Ok((info.stream_sequence, message.payload.clone())),
+                            Err(e) => Err(ClickBusError::Receive(e.to_string())),
+                        });
+                    let payload = match sequenced {
+                        Ok((message_sequence, payload)) => {
+                            sequence = message_sequence;
+                            payload
+                        }
+                        Err(e) => {
+                            let _ = sender.send(Err(e)).await;
+                            return;
+                        }
+                    };
+                    fetched = true;
+
+                    if sequence > last_sequence {
+                        break;
+                    }
+                    match Click::decode(payload) {
+                        Ok(click) => {
+                            if sender.send(Ok(click)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => error!("Skipping undecodable click {} of the history: {}", sequence, e),
+                    }
+                }
+
+                if !fetched {
+                    break;
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(receiver).boxed())
+    }
+
+    async fn publish_restrictions(&self, restrictions: &Restrictions) -> Result<(), ClickBusError> {
+        let value = serde_json::to_vec(restrictions).expect("restrictions are serializable");
+        self.admin_store()
+            .await?
+            .put(RESTRICTIONS_KEY, value.into())
+            .await
+            .map_err(|e| ClickBusError::Publish(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn restriction_updates(&self) -> Result<BoxStream<'static, Restrictions>, ClickBusError> {
+        let store = self.admin_store().await?;
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+
+        // The watch borrows the store, both are kept by the task
+        tokio::spawn(async move {
+            let mut updates = match store.watch_with_history(RESTRICTIONS_KEY).await {
+                Ok(updates) => updates,
+                Err(e) => {
+                    error!("Failed to watch the admin restrictions: {}", e);
+                    return;
+                }
+            };
+
+            while let Some(entry) = updates.next().await {
+                let restrictions = entry
+                    .map_err(|e| e.to_string())
+                    .and_then(|entry| serde_json::from_slice(&entry.value).map_err(|e| e.to_string()));
+                match restrictions {
+                    Ok(restrictions) => {
+                        if sender.send(restrictions).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => error!("Skipping unreadable admin restrictions: {}", e),
+                }
+            }
+        });
+
+        Ok(ReceiverStream::new(receiver).boxed())
+    }
+
+    async fn publish_system_message(&self, message: &SystemMessage) -> Result<(), ClickBusError> {
+        self.client
+            .publish(SYSTEM_MESSAGE_SUBJECT.to_string(), message.encode_to_vec().into())
+            .await
+            .map_err(|e| ClickBusError::Publish(e.to_string()))
+    }
+
+    async fn system_messages(&self) -> Result<BoxStream<'static, SystemMessage>, ClickBusError> {
+        let messages = self.client
+            .subscribe(SYSTEM_MESSAGE_SUBJECT.to_string())
+            .await
+            .map_err(|e| ClickBusError::Subscribe(e.to_string()))?;
+
+        Ok(messages
+            .filter_map(|message| async move {
+                SystemMessage::decode(message.payload)
+                    .inspect_err(|e| error!("Skipping undecodable system message: {}", e))
+                    .ok()
+            })
+            .boxed())
+    }
+
+    fn as_click_bus(self: Arc<Self>) -> Arc<dyn ClickBus> {
+        self
+    }
+}
@@ -1,69 +1,30 @@
-use async_nats::{jetstream, ConnectError};
-use prost::Message;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use async_nats::jetstream::Context;
-use thiserror::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::broadcast::Sender;
 use tracing::{info, instrument, warn, Span};
 use uuid::Uuid;
 use clickplanet_proto::clicks::{Click};
-use crate::nats_commons::{CLICK_STREAM_NAME, CLICK_SUBJECT_PREFIX};
+use crate::click_publisher::ClickPublisher;
+use crate::metrics::METRICS;
 
-pub struct ClickService {
-    jetstream: Arc<jetstream::Context>,
-    sender: Arc<Sender<Click>>,
+/// Whether `tile_id` is one of the `tile_count` tiles of the globe, numbered from 0.
+pub fn is_tile(tile_id: i64, tile_count: usize) -> bool {
+    usize::try_from(tile_id).is_ok_and(|tile_id| tile_id < tile_count)
 }
 
-#[derive(Error, Debug)]
-pub enum ClickServiceError {
-    #[error("Failed to connect to NATS: {0}")]
-    ConnectionError(#[from] ConnectError),  // Changed to specific ConnectError
-    #[error("Failed to create stream: {0}")]
-    StreamCreationError(String),
-    #[error("Nats ack error: {0}")]
-    NatsError(String)
-}
-
-pub async fn get_or_create_jet_stream(nats_url: &str) -> Result<Context, ClickServiceError> {
-    let client = async_nats::connect(nats_url).await?;
-    let jetstream = async_nats::jetstream::new(client);
-
-    let stream_config = async_nats::jetstream::stream::Config {
-        name: CLICK_STREAM_NAME.to_string(),
-        subjects: vec![format!("{}*", CLICK_SUBJECT_PREFIX).to_string()],
-        max_age: Duration::from_secs(8 * 60 * 60),
-        discard: async_nats::jetstream::stream::DiscardPolicy::Old,
-        ..Default::default()
-    };
-
-    match jetstream.get_stream(CLICK_STREAM_NAME).await {
-        Ok(_) => {
-            jetstream
-                .update_stream(stream_config)
-                .await
-                .map_err(|e| ClickServiceError::StreamCreationError(e.to_string()))?;
-        }
-        Err(_) => {
-            jetstream
-                .create_stream(stream_config)
-                .await
-                .map_err(|e| ClickServiceError::StreamCreationError(e.to_string()))?;
-        }
-    }
-
-    Ok(jetstream)
+pub struct ClickService {
+    click_bus: Arc<dyn ClickPublisher>,
+    sender: Arc<Sender<Click>>,
 }
 
-
 impl ClickService {
-    pub async fn new(jetstream: Arc<Context>, sender: Arc<Sender<Click>>) -> Result<Self, ClickServiceError> {
-        Ok(Self { jetstream, sender })
+    pub fn new(click_bus: Arc<dyn ClickPublisher>, sender: Arc<Sender<Click>>) -> Self {
+        Self { click_bus, sender }
     }
 
     #[instrument(
         name = "process_click",
-        skip(self, request),
+        skip(self, request, session_id),
         fields(
         tile_id = tracing::field::Empty,
         country = tracing::field::Empty,
@@ -72,9 +33,11 @@ impl ClickService {
         publish_time = tracing::field::Empty,
         )
     )]
+    /// Publishes the click, made in `session_id` or anonymously when empty.
     pub async fn process_click(
         &self,
         request: clickplanet_proto::clicks::ClickRequest,
+        session_id: String,
     ) -> Result<clickplanet_proto::clicks::ClickResponse, Box<dyn std::error::Error + Send + Sync>> {
 
         let click_id = Uuid::new_v4();
@@ -83,8 +46,6 @@ impl ClickService {
             .unwrap()
             .as_nanos() as u64;
 
-        let subject = format!("{}{}", CLICK_SUBJECT_PREFIX, request.tile_id);
-
         let response = clickplanet_proto::clicks::ClickResponse {
             timestamp_ns: timestamp,
             click_id: click_id.to_string(),
@@ -95,20 +56,15 @@ impl ClickService {
             country_id: request.country_id.clone(),
             timestamp_ns: timestamp,
             click_id: click_id.to_string(),
+            session_id,
         };
 
-        let mut click_bytes = Vec::new();
-        click_data.encode(&mut click_bytes)?;
-
-        let jetstream = self.jetstream.clone();
-
-        let result = jetstream.publish(subject, click_bytes.into())
-            .await
-            .map_err(|e| ClickServiceError::NatsError(e.to_string()));
-
-        if let Err(e) = result {
-            warn!("Failed to send click to nats channel (service might be shutting down): {:?}", e);
+        let publish_timer = METRICS.bus_publish_seconds.start_timer();
+        if let Err(e) = self.click_bus.publish(&click_data).await {
+            METRICS.bus_publish_errors.inc();
+            warn!("Failed to send click to the click bus (service might be shutting down): {:?}", e);
         }
+        drop(publish_timer);
 
         let send_error= self.sender.send(click_data);
         if let Err(e) = send_error {
@@ -128,6 +84,7 @@ impl ClickService {
         span.record("click_id", &click_id.to_string());
         span.record("publish_time", publish_time);
 
+        METRICS.clicks.with("accepted").inc();
         info!(
         "Click processed successfully for tile {} (country: {})",
             request.tile_id, request.country_id
@@ -135,4 +92,25 @@ impl ClickService {
 
         Ok(response)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GameConfig;
+    use crate::home_territory::HomeTerritoryMap;
+
+    #[test]
+    fn test_highest_real_tile_is_clickable() {
+        let homes = HomeTerritoryMap::from_file("../tile_to_countries.json").unwrap();
+        let highest_tile_id = homes.tile_count() as i64 - 1;
+        assert_eq!(highest_tile_id, 257944);
+
+        for tile_count in [homes.tile_count(), GameConfig::default().tile_count] {
+            assert!(is_tile(0, tile_count));
+            assert!(is_tile(highest_tile_id, tile_count));
+            assert!(!is_tile(highest_tile_id + 1, tile_count));
+            assert!(!is_tile(-1, tile_count));
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use clickplanet_telemetry::{init_telemetry, TelemetryArgs};
+use tracing::{error, info};
+use tokio_util::sync::CancellationToken;
+
+use clickplanet_server::{persister, server, shutdown};
+use clickplanet_server::config::{Config, ConfigArgs, ConfigError, Storage};
+use clickplanet_server::dead_letter_queue::DeadLetterQueue;
+use clickplanet_server::jetstream_click_bus::JetStreamClickBus;
+use clickplanet_server::persister::PersisterArgs;
+use clickplanet_server::server::ServerArgs;
+use clickplanet_server::shared_args::SharedArgs;
+
+/// ClickPlanet API, persister, or both along with the webapp.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    #[command(flatten)]
+    shared: SharedArgs,
+
+    #[command(flatten)]
+    telemetry: TelemetryArgs,
+}
+
+impl Cli {
+    /// The configuration, with the flags of the command applied.
+    fn load_config(&self) -> Result<Config, ConfigError> {
+        self.config.load(|config| {
+            self.shared.apply(config);
+            match &self.command {
+                Command::Serve(args) => args.apply(config),
+                Command::Persist(args) => args.apply(config),
+                Command::AllInOne(args) => {
+                    args.server.apply(config);
+                    args.persister.apply(config);
+                }
+                Command::DeadLetters(_) => {}
+            }
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Runs the HTTP and WebSocket API
+    Serve(ServerArgs),
+    /// Writes the clicks published on NATS to the cold storage
+    Persist(PersisterArgs),
+    /// Runs the API, the persister and serves the webapp from a single process
+    AllInOne(AllInOneArgs),
+    /// Lists or replays the clicks the consumers failed to process
+    #[command(subcommand)]
+    DeadLetters(DeadLetterCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum DeadLetterCommand {
+    /// Prints the oldest dead letters, leaving them in the queue
+    Inspect {
+        #[arg(long, default_value = "20")]
+        limit: usize,
+    },
+    /// Publishes the oldest dead letters again on their original subject and removes them
+    Replay {
+        #[arg(long, default_value_t = usize::MAX, hide_default_value = true)]
+        limit: usize,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct AllInOneArgs {
+    #[command(flatten)]
+    server: ServerArgs,
+
+    #[command(flatten)]
+    persister: PersisterArgs,
+
+    /// Compiled webapp assets, as produced by `dx bundle`
+    #[arg(long, env = "WEBAPP_DIR", default_value = "webapp")]
+    webapp_dir: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let config = cli.load_config()?;
+    if cli.config.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+
+    let telemetry = init_telemetry(&cli.telemetry, "clickplanet")?;
+
+    let result = match &cli.command {
+        Command::Serve(_) => {
+            let click_bus = server::create_click_bus(&config).await?;
+            server::run(&config, click_bus, None, shutdown::on_signals()).await
+        }
+        Command::Persist(_) => {
+            let click_bus = Arc::new(JetStreamClickBus::connect(&config.bus.nats_url, config.stream.retention()).await?);
+            persister::run(&config, click_bus, shutdown::on_signals()).await
+        }
+        Command::AllInOne(args) => all_in_one(&config, &args.webapp_dir, shutdown::on_signals()).await,
+        Command::DeadLetters(command) => dead_letters(&config, command).await,
+    };
+
+    telemetry.shutdown().await;
+    result
+}
+
+async fn dead_letters(config: &Config, command: &DeadLetterCommand) -> Result<(), Box<dyn std::error::Error>> {
+    let queue = DeadLetterQueue::connect(&config.bus.nats_url).await?;
+
+    match command {
+        DeadLetterCommand::Inspect { limit } => {
+            for dead_letter in queue.inspect(*limit).await? {
+                let click = match &dead_letter.click {
+                    Some(click) => format!("tile {} by {} at {}", click.tile_id, click.country_id, click.timestamp_ns),
+                    None => "undecodable payload".to_string(),
+                };
+                println!(
+                    "#{} {} (stream sequence {}, {} deliveries to {}): {} - {}",
+                    dead_letter.sequence,
+                    dead_letter.subject,
+                    dead_letter.stream_sequence,
+                    dead_letter.delivery_count,
+                    dead_letter.consumer,
+                    click,
+                    dead_letter.reason,
+                );
+            }
+        }
+        DeadLetterCommand::Replay { limit } => {
+            let replayed = queue.replay(*limit).await?;
+            info!("Replayed {} dead letters", replayed);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the API and the persister until shutdown, or until one of them stops, which then
+/// stops the other.
+async fn all_in_one(
+    config: &Config,
+    webapp_dir: &str,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let click_bus = server::create_click_bus(config).await?;
+    let server = server::run(config, click_bus.clone(), Some(webapp_dir), shutdown.clone());
+
+    // The embedded storage is written by the server itself
+    if config.storage.kind == Storage::Embedded {
+        info!("Embedded storage, not starting the persister");
+        return server.await;
+    }
+
+    let persister = persister::run(config, click_bus.as_click_bus(), shutdown.clone());
+
+    let (server_result, persister_result) = tokio::join!(
+        async {
+            let result = server.await;
+            if !shutdown.is_cancelled() {
+                error!("API stopped");
+                shutdown.cancel();
+            }
+            result
+        },
+        async {
+            let result = persister.await;
+            if !shutdown.is_cancelled() {
+                error!("Persister stopped");
+                shutdown.cancel();
+            }
+            result
+        },
+    );
+
+    server_result.and(persister_result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_all_in_one_shares_connections() {
+        let cli = Cli::try_parse_from([
+            "clickplanet", "all-in-one",
+            "--redis-url", "redis://redis:6379",
+            "--storage", "embedded",
+            "--webapp-dir", "dist",
+        ]).unwrap();
+
+        let config = cli.load_config().unwrap();
+        assert_eq!(config.storage.redis_url, "redis://redis:6379");
+        assert_eq!(config.storage.kind, Storage::Embedded);
+        let Command::AllInOne(args) = cli.command else { panic!("expected all-in-one") };
+        assert_eq!(args.webapp_dir, "dist");
+    }
+
+    #[test]
+    fn test_dead_letter_commands() {
+        let cli = Cli::try_parse_from(["clickplanet", "dead-letters", "inspect", "--limit", "5"]).unwrap();
+        assert!(matches!(cli.command, Command::DeadLetters(DeadLetterCommand::Inspect { limit: 5 })));
+
+        let cli = Cli::try_parse_from(["clickplanet", "dead-letters", "replay"]).unwrap();
+        assert!(matches!(cli.command, Command::DeadLetters(DeadLetterCommand::Replay { limit: usize::MAX })));
+    }
+}
@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use async_nats::jetstream;
+use async_nats::jetstream::consumer::pull;
+use async_nats::jetstream::stream::Stream;
+use async_nats::HeaderMap;
+use clickplanet_proto::clicks::Click;
+use futures::StreamExt;
+use prost::Message;
+
+use crate::click_bus::ClickBusError;
+use crate::jetstream_click_bus::{
+    dead_letter_stream_config, CONSUMER_HEADER, DEAD_LETTER_SUBJECT_PREFIX, DELIVERY_COUNT_HEADER, ERROR_HEADER,
+    STREAM_SEQUENCE_HEADER,
+};
+
+const FETCH_SIZE: usize = 100;
+
+/// A click set aside by a consumer, as stored in the `CLICKS_DLQ` stream.
+#[derive(Debug)]
+pub struct DeadLetter {
+    /// Sequence in the dead letter stream
+    pub sequence: u64,
+    /// Subject the click was first published on
+    pub subject: String,
+    pub reason: String,
+    pub delivery_count: u64,
+    /// Sequence in the clicks stream
+    pub stream_sequence: u64,
+    pub consumer: String,
+    /// None if the payload is not a click
+    pub click: Option<Click>,
+    payload: bytes::Bytes,
+}
+
+impl DeadLetter {
+    fn from_message(message: &jetstream::Message) -> Result<Self, ClickBusError> {
+        let sequence = message.info().map_err(|e| ClickBusError::Receive(e.to_string()))?.stream_sequence;
+        let headers = message.headers.clone().unwrap_or_default();
+
+        Ok(Self {
+            sequence,
+            subject: message.subject.trim_start_matches(DEAD_LETTER_SUBJECT_PREFIX).to_string(),
+            reason: header(&headers, ERROR_HEADER),
+            delivery_count: header(&headers, DELIVERY_COUNT_HEADER).parse().unwrap_or(0),
+            stream_sequence: header(&headers, STREAM_SEQUENCE_HEADER).parse().unwrap_or(0),
+            consumer: header(&headers, CONSUMER_HEADER),
+            click: Click::decode(message.payload.clone()).ok(),
+            payload: message.payload.clone(),
+        })
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> String {
+    headers.get(name).map(|value| value.as_str().to_string()).unwrap_or_default()
+}
+
+/// Reads and replays the clicks the consumers failed to process.
+pub struct DeadLetterQueue {
+    jetstream: jetstream::Context,
+    stream: Stream,
+}
+
+impl DeadLetterQueue {
+    pub async fn connect(nats_url: &str) -> Result<Self, ClickBusError> {
+        let client = async_nats::connect(nats_url)
+            .await
+            .map_err(|e| ClickBusError::Connection(e.to_string()))?;
+        let jetstream = jetstream::new(client);
+
+        let stream = jetstream
+            .get_or_create_stream(dead_letter_stream_config())
+            .await
+            .map_err(|e| ClickBusError::Connection(e.to_string()))?;
+
+        Ok(Self { jetstream, stream })
+    }
+
+    /// Up to `limit` dead letters, oldest first. The queue is left untouched.
+    pub async fn inspect(&self, limit: usize) -> Result<Vec<DeadLetter>, ClickBusError> {
+        let consumer = self.reader().await?;
+        let mut dead_letters = Vec::new();
+
+        while dead_letters.len() < limit {
+            let batch = self.fetch(&consumer, (limit - dead_letters.len()).min(FETCH_SIZE)).await?;
+            if batch.is_empty() {
+                break;
+            }
+            dead_letters.extend(batch);
+        }
+
+        Ok(dead_letters)
+    }
+
+    /// Publishes up to `limit` dead letters again on their original subject, oldest first,
+    /// and removes them from the queue. Returns the number of replayed clicks.
+    pub async fn replay(&self, limit: usize) -> Result<usize, ClickBusError> {
+        let consumer = self.reader().await?;
+        let mut replayed = 0;
+
+        while replayed < limit {
+            let batch = self.fetch(&consumer, (limit - replayed).min(FETCH_SIZE)).await?;
+            if batch.is_empty() {
+                break;
+            }
+
+            for dead_letter in batch {
+                self.jetstream
+                    .publish(dead_letter.subject.clone(), dead_letter.payload.clone())
+                    .await
+                    .map_err(|e| ClickBusError::Publish(e.to_string()))?
+                    .await
+                    .map_err(|e| ClickBusError::Publish(e.to_string()))?;
+
+                self.stream
+                    .delete_message(dead_letter.sequence)
+                    .await
+                    .map_err(|e| ClickBusError::DeadLetter(e.to_string()))?;
+                replayed += 1;
+            }
+        }
+
+        Ok(replayed)
+    }
+
+    /// Ephemeral consumer reading the whole queue without acknowledging anything.
+    async fn reader(&self) -> Result<jetstream::consumer::Consumer<pull::Config>, ClickBusError> {
+        self.stream
+            .create_consumer(pull::Config {
+                deliver_policy: jetstream::consumer::DeliverPolicy::All,
+                ack_policy: jetstream::consumer::AckPolicy::None,
+                inactive_threshold: Duration::from_secs(60),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| ClickBusError::Subscribe(e.to_string()))
+    }
+
+    async fn fetch(
+        &self,
+        consumer: &jetstream::consumer::Consumer<pull::Config>,
+        max_messages: usize,
+    ) -> Result<Vec<DeadLetter>, ClickBusError> {
+        let mut messages = consumer
+            .fetch()
+            .max_messages(max_messages)
+            .messages()
+            .await
+            .map_err(|e| ClickBusError::Receive(e.to_string()))?;
+
+        let mut dead_letters = Vec::new();
+        while let Some(message) = messages.next().await {
+            let message = message.map_err(|e| ClickBusError::Receive(e.to_string()))?;
+            dead_letters.push(DeadLetter::from_message(&message)?);
+        }
+
+        Ok(dead_letters)
+    }
+}
@@ -0,0 +1,356 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use clickplanet_proto::clicks::{Click, Ownership, OwnershipState};
+use redb::{Database, ReadableTable, TableDefinition};
+use thiserror::Error;
+use tokio::task::JoinError;
+
+use crate::click_persistence::{ClickRepository, ClickRepositoryError};
+use crate::leaderboard_history::{LeaderboardHistoryError, LeaderboardHistoryStore, LeaderboardSnapshot};
+
+/// tile id -> (timestamp, country id)
+const TILES: TableDefinition<u32, (u64, &str)> = TableDefinition::new("tiles");
+/// timestamp -> JSON snapshot
+const LEADERBOARD_HISTORY: TableDefinition<u64, &str> = TableDefinition::new("leaderboard_history");
+
+#[derive(Error, Debug)]
+pub enum EmbeddedError {
+    #[error("Embedded storage error: {0}")]
+    Redb(Box<redb::Error>),
+    #[error("Embedded storage task failed: {0}")]
+    Task(#[from] JoinError),
+}
+
+macro_rules! from_redb_error {
+    ($($error:ty),*) => {
+        $(impl From<$error> for EmbeddedError {
+            fn from(err: $error) -> Self {
+                EmbeddedError::Redb(Box::new(err.into()))
+            }
+        })*
+    };
+}
+
+from_redb_error!(redb::Error, redb::DatabaseError, redb::TransactionError, redb::TableError, redb::StorageError, redb::CommitError);
+
+impl From<EmbeddedError> for ClickRepositoryError {
+    fn from(err: EmbeddedError) -> Self {
+        ClickRepositoryError::StorageError(err.to_string())
+    }
+}
+
+impl From<EmbeddedError> for LeaderboardHistoryError {
+    fn from(err: EmbeddedError) -> Self {
+        LeaderboardHistoryError::StorageError(err.to_string())
+    }
+}
+
+/// Tile ownerships and leaderboard snapshots in a single redb file, for deployments
+/// without Redis.
+pub struct EmbeddedClickRepository {
+    database: Arc<Database>,
+}
+
+impl EmbeddedClickRepository {
+    /// Opens the file, creating it if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, EmbeddedError> {
+        let database = Database::create(path)?;
+
+        // Read transactions fail on missing tables
+        let create_tables = || -> Result<(), EmbeddedError> {
+            let transaction = database.begin_write()?;
+            transaction.open_table(TILES)?;
+            transaction.open_table(LEADERBOARD_HISTORY)?;
+            transaction.commit()?;
+            Ok(())
+        };
+        create_tables()?;
+
+        Ok(Self {
+            database: Arc::new(database),
+        })
+    }
+
+    /// Runs blocking redb calls off the async runtime.
+    async fn with_database<T, F>(&self, f: F) -> Result<T, EmbeddedError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T, EmbeddedError> + Send + 'static,
+    {
+        let database = self.database.clone();
+        tokio::task::spawn_blocking(move || f(&database)).await?
+    }
+
+    async fn ownerships_in(&self, start_tile_id: u32, end_tile_id: u32) -> Result<OwnershipState, EmbeddedError> {
+        self.with_database(move |database| {
+            let transaction = database.begin_read()?;
+            let table = transaction.open_table(TILES)?;
+
+            let mut ownerships = Vec::new();
+            for entry in table.range(start_tile_id..=end_tile_id)? {
+                let (tile_id, value) = entry?;
+                let (timestamp_ns, country_id) = value.value();
+                ownerships.push(Ownership {
+                    tile_id: tile_id.value(),
+                    country_id: country_id.to_string(),
+                    timestamp_ns,
+                });
+            }
+
+            Ok(OwnershipState { ownerships })
+        }).await
+    }
+}
+
+#[async_trait]
+impl ClickRepository for EmbeddedClickRepository {
+    async fn get_tile(&self, tile_id: u32) -> Result<Option<Ownership>, ClickRepositoryError> {
+        Ok(self.ownerships_in(tile_id, tile_id).await?.ownerships.pop())
+    }
+
+    async fn get_ownerships(&self) -> Result<OwnershipState, ClickRepositoryError> {
+        Ok(self.ownerships_in(0, u32::MAX).await?)
+    }
+
+    async fn get_ownerships_by_batch(
+        &self,
+        start_tile_id: u32,
+        end_tile_id: u32,
+    ) -> Result<OwnershipState, ClickRepositoryError> {
+        Ok(self.ownerships_in(start_tile_id, end_tile_id).await?)
+    }
+
+    async fn save_click(&self, tile_id: u32, click: &Click) -> Result<Option<Ownership>, ClickRepositoryError> {
+        let click = click.clone();
+
+        Ok(self.with_database(move |database| {
+            let transaction = database.begin_write()?;
+
+            let previous_ownership = {
+                let mut table = transaction.open_table(TILES)?;
+
+                let previous_ownership = table.get(tile_id)?.map(|value| {
+                    let (timestamp_ns, country_id) = value.value();
+                    Ownership {
+                        tile_id,
+                        country_id: country_id.to_string(),
+                        timestamp_ns,
+                    }
+                });
+
+                if previous_ownership.as_ref().is_some_and(|previous| click.timestamp_ns <= previous.timestamp_ns) {
+                    return Ok(previous_ownership);
+                }
+
+                table.insert(tile_id, (click.timestamp_ns, click.country_id.as_str()))?;
+                previous_ownership
+            };
+
+            transaction.commit()?;
+            Ok(previous_ownership)
+        }).await?)
+    }
+}
+
+#[async_trait]
+impl LeaderboardHistoryStore for EmbeddedClickRepository {
+    async fn append(&self, snapshot: &LeaderboardSnapshot) -> Result<(), LeaderboardHistoryError> {
+        let timestamp_secs = snapshot.timestamp_secs;
+        let member = serde_json::to_string(snapshot)
+            .map_err(|e| LeaderboardHistoryError::InvalidDataError(e.to_string()))?;
+
+        Ok(self.with_database(move |database| {
+            let transaction = database.begin_write()?;
+            transaction.open_table(LEADERBOARD_HISTORY)?.insert(timestamp_secs, member.as_str())?;
+            transaction.commit()?;
+            Ok(())
+        }).await?)
+    }
+
+    async fn range(&self, from_secs: u64, to_secs: u64) -> Result<Vec<LeaderboardSnapshot>, LeaderboardHistoryError> {
+        let members = self.with_database(move |database| {
+            let transaction = database.begin_read()?;
+            let table = transaction.open_table(LEADERBOARD_HISTORY)?;
+
+            let mut members = Vec::new();
+            for entry in table.range(from_secs..=to_secs)? {
+                members.push(entry?.1.value().to_string());
+            }
+            Ok(members)
+        }).await?;
+
+        members
+            .iter()
+            .map(|member| {
+                serde_json::from_str(member)
+                    .map_err(|e| LeaderboardHistoryError::InvalidDataError(e.to_string()))
+            })
+            .collect()
+    }
+
+    async fn prune_before(&self, timestamp_secs: u64) -> Result<(), LeaderboardHistoryError> {
+        Ok(self.with_database(move |database| {
+            let transaction = database.begin_write()?;
+            transaction.open_table(LEADERBOARD_HISTORY)?.retain_in(..timestamp_secs, |_, _| false)?;
+            transaction.commit()?;
+            Ok(())
+        }).await?)
+    }
+}
+
+/// Serves reads and ownership changes from the in-memory `hot` repository, and copies
+/// every click to the `cold` one first so the state survives restarts. A click the `cold`
+/// repository failed to save is not applied, and its error is returned.
+pub struct WriteThroughClickRepository {
+    hot: Arc<dyn ClickRepository>,
+    cold: Arc<dyn ClickRepository>,
+}
+
+impl WriteThroughClickRepository {
+    pub fn new(hot: Arc<dyn ClickRepository>, cold: Arc<dyn ClickRepository>) -> Self {
+        Self { hot, cold }
+    }
+}
+
+#[async_trait]
+impl ClickRepository for WriteThroughClickRepository {
+    async fn get_tile(&self, tile_id: u32) -> Result<Option<Ownership>, ClickRepositoryError> {
+        self.hot.get_tile(tile_id).await
+    }
+
+    async fn get_ownerships(&self) -> Result<OwnershipState, ClickRepositoryError> {
+        self.hot.get_ownerships().await
+    }
+
+    async fn get_ownerships_by_batch(
+        &self,
+        start_tile_id: u32,
+        end_tile_id: u32,
+    ) -> Result<OwnershipState, ClickRepositoryError> {
+        self.hot.get_ownerships_by_batch(start_tile_id, end_tile_id).await
+    }
+
+    async fn save_click(&self, tile_id: u32, click: &Click) -> Result<Option<Ownership>, ClickRepositoryError> {
+        // Both repositories ignore clicks older than their own state, so it does not matter
+        // if concurrent writes reach them out of order
+        self.cold.save_click(tile_id, click).await?;
+        self.hot.save_click(tile_id, click).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::in_memory_click_persistence::PapayaClickRepository;
+
+    fn database_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("clickplanet-{}.redb", uuid::Uuid::new_v4()))
+    }
+
+    fn click(tile_id: u32, country_id: &str, timestamp_ns: u64) -> Click {
+        Click {
+            tile_id: tile_id as i32,
+            country_id: country_id.to_string(),
+            timestamp_ns,
+            click_id: String::new(),
+            session_id: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_is_restored_from_file() {
+        let path = database_path();
+
+        {
+            let repository = EmbeddedClickRepository::open(&path).unwrap();
+            assert_eq!(repository.save_click(3, &click(3, "fr", 10)).await.unwrap(), None);
+            repository.save_click(1, &click(1, "de", 10)).await.unwrap();
+            repository.save_click(3, &click(3, "es", 20)).await.unwrap();
+            // Outdated, ignored
+            let previous = repository.save_click(3, &click(3, "it", 15)).await.unwrap().unwrap();
+            assert_eq!(previous.country_id, "es");
+        }
+
+        let repository = EmbeddedClickRepository::open(&path).unwrap();
+        let ownerships = repository.get_ownerships().await.unwrap().ownerships;
+        let owners: Vec<(u32, &str, u64)> = ownerships
+            .iter()
+            .map(|o| (o.tile_id, o.country_id.as_str(), o.timestamp_ns))
+            .collect();
+        assert_eq!(owners, vec![(1, "de", 10), (3, "es", 20)]);
+
+        let papaya = PapayaClickRepository::populate_with(Arc::new(repository)).await.unwrap();
+        assert_eq!(papaya.get_tile(3).await.unwrap().unwrap().country_id, "es");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_through() {
+        let path = database_path();
+        let cold = Arc::new(EmbeddedClickRepository::open(&path).unwrap());
+        let repository = WriteThroughClickRepository::new(Arc::new(PapayaClickRepository::new()), cold.clone());
+
+        repository.save_click(1, &click(1, "fr", 10)).await.unwrap();
+        let previous = repository.save_click(1, &click(1, "de", 5)).await.unwrap();
+
+        assert_eq!(previous.unwrap().country_id, "fr");
+        assert_eq!(cold.get_tile(1).await.unwrap().unwrap().country_id, "fr");
+        assert_eq!(cold.get_tile(2).await.unwrap(), None);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_leaderboard_history() {
+        let path = database_path();
+        let repository = EmbeddedClickRepository::open(&path).unwrap();
+
+        for timestamp_secs in [100, 200, 300] {
+            repository.append(&LeaderboardSnapshot {
+                timestamp_secs,
+                scores: HashMap::from([("fr".to_string(), timestamp_secs as u32)]),
+            }).await.unwrap();
+        }
+
+        repository.prune_before(200).await.unwrap();
+        let snapshots = repository.range(0, 250).await.unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].timestamp_secs, 200);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    struct FailingRepository;
+
+    #[async_trait]
+    impl ClickRepository for FailingRepository {
+        async fn get_tile(&self, _tile_id: u32) -> Result<Option<Ownership>, ClickRepositoryError> {
+            Ok(None)
+        }
+
+        async fn get_ownerships(&self) -> Result<OwnershipState, ClickRepositoryError> {
+            Ok(OwnershipState::default())
+        }
+
+        async fn get_ownerships_by_batch(&self, _start_tile_id: u32, _end_tile_id: u32) -> Result<OwnershipState, ClickRepositoryError> {
+            Ok(OwnershipState::default())
+        }
+
+        async fn save_click(&self, _tile_id: u32, _click: &Click) -> Result<Option<Ownership>, ClickRepositoryError> {
+            Err(ClickRepositoryError::StorageError("disk full".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clicks_the_cold_repository_refuses_are_not_applied() {
+        let hot = Arc::new(PapayaClickRepository::new());
+        let repository = WriteThroughClickRepository::new(hot.clone(), Arc::new(FailingRepository));
+
+        assert!(repository.save_click(1, &click(1, "fr", 10)).await.is_err());
+        assert_eq!(hot.get_tile(1).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::click_bus::ClickBus;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Served as `/v2/status`, and by `/readyz` when not ready.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ServiceStatus {
+    pub version: &'static str,
+    pub uptime_secs: u64,
+    pub ready: bool,
+    pub bus_connected: bool,
+    /// Sequence of the last click published on the bus
+    pub stream_sequence: Option<u64>,
+    /// Clicks published but not yet processed by the consumer of this service
+    pub consumer_lag: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub websocket_clients: Option<usize>,
+}
+
+/// Readiness of a service consuming the click bus: connected, subscribed, caught up with the
+/// published clicks and not shutting down.
+pub struct Health {
+    started_at: Instant,
+    click_bus: Arc<dyn ClickBus>,
+    consumer_name: &'static str,
+    max_lag: u64,
+    shutdown: CancellationToken,
+}
+
+impl Health {
+    pub fn new(click_bus: Arc<dyn ClickBus>, consumer_name: &'static str, max_lag: u64, shutdown: CancellationToken) -> Self {
+        Self {
+            started_at: Instant::now(),
+            click_bus,
+            consumer_name,
+            max_lag,
+            shutdown,
+        }
+    }
+
+    pub async fn status(&self) -> ServiceStatus {
+        let bus_connected = self.click_bus.is_connected();
+        let consumer_status = match self.click_bus.consumer_status(self.consumer_name).await {
+            Ok(consumer_status) => Some(consumer_status),
+            Err(e) => {
+                warn!("Consumer {} not ready: {}", self.consumer_name, e);
+                None
+            }
+        };
+
+        let caught_up = consumer_status.is_some_and(|consumer_status| consumer_status.lag <= self.max_lag);
+        ServiceStatus {
+            version: VERSION,
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            ready: bus_connected && caught_up && !self.shutdown.is_cancelled(),
+            bus_connected,
+            stream_sequence: consumer_status.map(|consumer_status| consumer_status.last_sequence),
+            consumer_lag: consumer_status.map(|consumer_status| consumer_status.lag),
+            tile_count: None,
+            websocket_clients: None,
+        }
+    }
+}
+
+pub async fn handle_healthz() -> impl IntoResponse {
+    "ok"
+}
+
+/// 200 when ready, 503 with the status otherwise.
+pub fn readiness(status: ServiceStatus) -> impl IntoResponse {
+    if status.ready {
+        (StatusCode::OK, Json(status))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use futures::StreamExt;
+    use crate::click_bus::{ClickBusError, ClickSubscription, ConsumerStatus};
+    use crate::nats_commons::ConsumerConfig;
+
+    struct FakeBus {
+        connected: bool,
+        consumer_status: Option<ConsumerStatus>,
+    }
+
+    #[async_trait]
+    impl ClickBus for FakeBus {
+        async fn subscribe(&self, _consumer_name: &str, _config: &ConsumerConfig) -> Result<ClickSubscription, ClickBusError> {
+            // The health checks only read the consumer status
+            Ok(futures::stream::empty().boxed())
+        }
+
+        async fn flush(&self) -> Result<(), ClickBusError> {
+            Ok(())
+        }
+
+        fn is_connected(&self) -> bool {
+            self.connected
+        }
+
+        async fn consumer_status(&self, consumer_name: &str) -> Result<ConsumerStatus, ClickBusError> {
+            self.consumer_status.ok_or_else(|| ClickBusError::Status(consumer_name.to_string()))
+        }
+    }
+
+    fn health(connected: bool, consumer_status: Option<ConsumerStatus>, shutdown: CancellationToken) -> Health {
+        let click_bus = Arc::new(FakeBus { connected, consumer_status });
+        Health::new(click_bus, "consumer", 10, shutdown)
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let caught_up = ConsumerStatus { last_sequence: 100, lag: 10 };
+        let lagging = ConsumerStatus { last_sequence: 100, lag: 11 };
+
+        let status = health(true, Some(caught_up), CancellationToken::new()).status().await;
+        assert!(status.ready);
+        assert_eq!((status.stream_sequence, status.consumer_lag), (Some(100), Some(10)));
+
+        assert!(!health(true, Some(lagging), CancellationToken::new()).status().await.ready);
+        assert!(!health(false, Some(caught_up), CancellationToken::new()).status().await.ready);
+
+        // Not subscribed yet
+        let status = health(true, None, CancellationToken::new()).status().await;
+        assert_eq!((status.ready, status.consumer_lag), (false, None));
+
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        assert!(!health(true, Some(caught_up), shutdown).status().await.ready);
+    }
+}
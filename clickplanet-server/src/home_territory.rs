@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use clickplanet_proto::clicks::{CountryStandingResponse, Occupation, OwnershipState, UpdateNotification};
+
+use crate::click_persistence::{ClickRepository, ClickRepositoryError};
+use crate::ownership_listener::{resync, OwnershipListener};
+
+/// Real-world country of every tile, as generated in `tile_to_countries.json`.
+#[derive(Debug, Default)]
+pub struct HomeTerritoryMap {
+    tile_to_country: HashMap<u32, String>,
+    home_tile_counts: HashMap<String, u32>,
+}
+
+impl HomeTerritoryMap {
+    pub fn new(tile_to_country: HashMap<u32, String>) -> Self {
+        let mut home_tile_counts: HashMap<String, u32> = HashMap::new();
+        for country in tile_to_country.values() {
+            *home_tile_counts.entry(country.clone()).or_insert(0) += 1;
+        }
+
+        Self {
+            tile_to_country,
+            home_tile_counts,
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let tile_to_country: HashMap<u32, String> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(Self::new(tile_to_country))
+    }
+
+    pub fn home_country(&self, tile_id: u32) -> Option<&str> {
+        self.tile_to_country.get(&tile_id).map(|s| s.as_str())
+    }
+
+    /// Tiles up to the highest tile id with a country, ocean tiles included.
+    pub fn tile_count(&self) -> usize {
+        self.tile_to_country.keys().max().map_or(0, |tile_id| *tile_id as usize + 1)
+    }
+
+    pub fn home_tile_count(&self, country_id: &str) -> u32 {
+        self.home_tile_counts.get(country_id).copied().unwrap_or(0)
+    }
+
+    /// Tiles of the home territory of `country_id`, in no particular order.
+    pub fn home_tiles(&self, country_id: &str) -> Vec<u32> {
+        self.tile_to_country
+            .iter()
+            .filter(|(_, home)| home.as_str() == country_id)
+            .map(|(tile_id, _)| *tile_id)
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct CountryControl {
+    owned_tiles: u32,
+    home_tiles_controlled: u32,
+    // Foreign countries holding tiles of this country's home territory
+    occupiers: HashMap<String, u32>,
+}
+
+#[derive(Default)]
+struct HomeControlState {
+    owners: HashMap<u32, String>,
+    countries: HashMap<String, CountryControl>,
+}
+
+impl HomeControlState {
+    fn add(&mut self, homes: &HomeTerritoryMap, tile_id: u32, owner: &str) {
+        self.countries.entry(owner.to_string()).or_default().owned_tiles += 1;
+
+        match homes.home_country(tile_id) {
+            Some(home) if home == owner => {
+                self.countries.entry(owner.to_string()).or_default().home_tiles_controlled += 1;
+            }
+            Some(home) => {
+                *self.countries
+                    .entry(home.to_string())
+                    .or_default()
+                    .occupiers
+                    .entry(owner.to_string())
+                    .or_insert(0) += 1;
+            }
+            None => {}
+        }
+    }
+
+    fn remove(&mut self, homes: &HomeTerritoryMap, tile_id: u32, owner: &str) {
+        if let Some(control) = self.countries.get_mut(owner) {
+            control.owned_tiles = control.owned_tiles.saturating_sub(1);
+        }
+
+        match homes.home_country(tile_id) {
+            Some(home) if home == owner => {
+                if let Some(control) = self.countries.get_mut(owner) {
+                    control.home_tiles_controlled = control.home_tiles_controlled.saturating_sub(1);
+                }
+            }
+            Some(home) => {
+                if let Some(control) = self.countries.get_mut(home) {
+                    if let Some(count) = control.occupiers.get_mut(owner) {
+                        *count -= 1;
+                        if *count == 0 {
+                            control.occupiers.remove(owner);
+                        }
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// Second scoring dimension next to the raw tile count: how much of its own real-world
+/// territory each country holds, how much it holds abroad and who occupies its land.
+pub struct HomeControlScores {
+    homes: Arc<HomeTerritoryMap>,
+    state: Mutex<HomeControlState>,
+}
+
+impl HomeControlScores {
+    pub fn new(homes: Arc<HomeTerritoryMap>) -> Self {
+        Self {
+            homes,
+            state: Mutex::new(HomeControlState::default()),
+        }
+    }
+
+    pub async fn populate_with(
+        homes: Arc<HomeTerritoryMap>,
+        repository: Arc<dyn ClickRepository>,
+    ) -> Result<Self, ClickRepositoryError> {
+        let scores = Self::new(homes);
+        resync(&scores, repository.as_ref()).await?;
+        Ok(scores)
+    }
+
+    /// Fraction of the country's home tiles it controls, 0 for countries without home territory.
+    pub fn home_control_ratio(&self, country_id: &str) -> f64 {
+        let home_tiles_controlled = self.state
+            .lock()
+            .unwrap()
+            .countries
+            .get(country_id)
+            .map_or(0, |control| control.home_tiles_controlled);
+
+        ratio(home_tiles_controlled, self.homes.home_tile_count(country_id))
+    }
+
+    pub fn standing(&self, country_id: &str) -> CountryStandingResponse {
+        let state = self.state.lock().unwrap();
+        let control = state.countries.get(country_id).cloned().unwrap_or_default();
+        let home_tiles = self.homes.home_tile_count(country_id);
+
+        let mut occupiers: Vec<Occupation> = control.occupiers
+            .into_iter()
+            .map(|(country_id, tiles)| Occupation { country_id, tiles })
+            .collect();
+        occupiers.sort_by(|a, b| b.tiles.cmp(&a.tiles).then_with(|| a.country_id.cmp(&b.country_id)));
+
+        CountryStandingResponse {
+            country_id: country_id.to_string(),
+            home_tiles,
+            home_tiles_controlled: control.home_tiles_controlled,
+            home_control_ratio: ratio(control.home_tiles_controlled, home_tiles),
+            tiles_abroad: control.owned_tiles - control.home_tiles_controlled,
+            owned_tiles: control.owned_tiles,
+            occupiers,
+        }
+    }
+}
+
+fn ratio(home_tiles_controlled: u32, home_tiles: u32) -> f64 {
+    if home_tiles == 0 {
+        0.0
+    } else {
+        home_tiles_controlled as f64 / home_tiles as f64
+    }
+}
+
+impl OwnershipListener for HomeControlScores {
+    fn name(&self) -> &'static str {
+        "home control scores"
+    }
+
+    fn load(&self, ownership_state: OwnershipState) {
+        let mut state = HomeControlState::default();
+
+        for ownership in ownership_state.ownerships {
+            state.add(&self.homes, ownership.tile_id, &ownership.country_id);
+            state.owners.insert(ownership.tile_id, ownership.country_id);
+        }
+
+        *self.state.lock().unwrap() = state;
+    }
+
+    fn apply_update(&self, update: &UpdateNotification) {
+        let tile_id = update.tile_id as u32;
+        let mut state = self.state.lock().unwrap();
+
+        let previous = if update.country_id.is_empty() {
+            state.owners.remove(&tile_id)
+        } else {
+            state.owners.insert(tile_id, update.country_id.clone())
+        };
+        if previous.as_deref().unwrap_or_default() == update.country_id {
+            return;
+        }
+
+        if let Some(previous_country) = previous {
+            state.remove(&self.homes, tile_id, &previous_country);
+        }
+        if !update.country_id.is_empty() {
+            state.add(&self.homes, tile_id, &update.country_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickplanet_proto::clicks::Ownership;
+
+    // Tiles 0..4 are in France, 4..6 in Germany, 6 is in the ocean
+    fn homes() -> Arc<HomeTerritoryMap> {
+        let tile_to_country = (0..6)
+            .map(|tile_id| (tile_id, if tile_id < 4 { "fr" } else { "de" }.to_string()))
+            .collect();
+
+        Arc::new(HomeTerritoryMap::new(tile_to_country))
+    }
+
+    fn update(tile_id: i32, country_id: &str, previous_country_id: &str) -> UpdateNotification {
+        UpdateNotification {
+            tile_id,
+            country_id: country_id.to_string(),
+            previous_country_id: previous_country_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_home_control() {
+        let scores = HomeControlScores::new(homes());
+
+        for tile_id in [0, 1, 4, 6] {
+            scores.apply_update(&update(tile_id, "fr", ""));
+        }
+        scores.apply_update(&update(2, "de", ""));
+        scores.apply_update(&update(3, "es", ""));
+
+        let france = scores.standing("fr");
+        assert_eq!(france.home_tiles, 4);
+        assert_eq!(france.home_tiles_controlled, 2);
+        assert_eq!(france.home_control_ratio, 0.5);
+        assert_eq!(scores.home_control_ratio("fr"), 0.5);
+        assert_eq!(france.owned_tiles, 4);
+        assert_eq!(france.tiles_abroad, 2);
+        assert_eq!(
+            france.occupiers,
+            vec![
+                Occupation { country_id: "de".to_string(), tiles: 1 },
+                Occupation { country_id: "es".to_string(), tiles: 1 },
+            ]
+        );
+
+        let germany = scores.standing("de");
+        assert_eq!(germany.home_tiles, 2);
+        assert_eq!(germany.home_tiles_controlled, 0);
+        assert_eq!(germany.tiles_abroad, 1);
+        assert_eq!(germany.occupiers, vec![Occupation { country_id: "fr".to_string(), tiles: 1 }]);
+    }
+
+    #[test]
+    fn test_liberation() {
+        let scores = HomeControlScores::new(homes());
+
+        scores.load(OwnershipState {
+            ownerships: vec![Ownership { tile_id: 0, country_id: "de".to_string(), timestamp_ns: 1 }],
+        });
+        assert_eq!(scores.standing("fr").occupiers.len(), 1);
+
+        scores.apply_update(&update(0, "fr", "de"));
+
+        let france = scores.standing("fr");
+        assert_eq!(france.home_tiles_controlled, 1);
+        assert_eq!(france.home_control_ratio, 0.25);
+        assert!(france.occupiers.is_empty());
+        assert_eq!(scores.standing("de").owned_tiles, 0);
+    }
+
+    #[test]
+    fn test_unknown_country() {
+        let standing = HomeControlScores::new(homes()).standing("zz");
+
+        assert_eq!(standing.home_tiles, 0);
+        assert_eq!(standing.home_control_ratio, 0.0);
+        assert_eq!(standing.owned_tiles, 0);
+    }
+}
@@ -27,10 +27,14 @@ impl PapayaClickRepository {
     }
 
     pub async fn populate_with(repository: Arc<dyn ClickRepository>) -> Result<Self, ClickRepositoryError> {
-        let papaya= Self::new();
-
         let ownership_state: OwnershipState = repository.get_ownerships().await?;
 
+        Self::from_ownerships(ownership_state).await
+    }
+
+    pub async fn from_ownerships(ownership_state: OwnershipState) -> Result<Self, ClickRepositoryError> {
+        let papaya = Self::new();
+
         for ownership in ownership_state.ownerships {
             let tile_id = ownership.tile_id;
             papaya.save_click(tile_id, &Click{
@@ -38,6 +42,7 @@ impl PapayaClickRepository {
                 country_id: ownership.country_id.clone(),
                 timestamp_ns: ownership.timestamp_ns,
                 click_id: "".to_string(),
+                session_id: "".to_string(),
             }).await?;
 
             papaya.update_country_index(tile_id, &ownership.country_id, None).await;
@@ -78,8 +83,8 @@ impl ClickRepository for PapayaClickRepository {
     async fn get_ownerships(&self) -> Result<OwnershipState, ClickRepositoryError> {
         let mut ownerships = Vec::new();
 
-        // Use Papaya's iterator to get all tiles
-        self.tiles.pin().iter().for_each(|(tile_id, v)| {
+        // Use Papaya's iterator to get all tiles, cleared ones being unowned
+        self.tiles.pin().iter().filter(|(_, v)| !v.country_id.is_empty()).for_each(|(tile_id, v)| {
             ownerships.push(Ownership {
                 tile_id: *tile_id as u32,
                 country_id: v.country_id.clone(),
@@ -99,7 +104,7 @@ impl ClickRepository for PapayaClickRepository {
 
         // Use Papaya's scan feature which is more efficient than individual gets
         self.tiles.pin().iter().for_each(|(k, v)| {
-            if *k >= start_tile_id && *k <= end_tile_id {
+            if *k >= start_tile_id && *k <= end_tile_id && !v.country_id.is_empty() {
                 ownerships.push(Ownership {
                     tile_id: *k as u32,
                     country_id: v.country_id.clone(),
@@ -150,9 +155,9 @@ impl LeaderboardMaintainer for PapayaClickRepository {
                         pinned_set.remove(&tile_id);
 
                         if pinned_set.is_empty() {
-                            Operation::<Arc<papaya::HashSet<u32>>, ()>::Insert(existing.clone())
-                        } else {
                             Operation::Remove
+                        } else {
+                            Operation::<Arc<papaya::HashSet<u32>>, ()>::Insert(existing.clone())
                         }
                     }
                     None => {
@@ -162,6 +167,10 @@ impl LeaderboardMaintainer for PapayaClickRepository {
             });
         });
 
+        if new_country.is_empty() {
+            return;
+        }
+
         country_map.update_or_insert(new_country.to_string(), |existing| {
             let set = existing.pin();
             set.insert(tile_id);
@@ -201,10 +210,7 @@ impl LeaderboardRepository for PapayaClickRepository {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::click_persistence::LeaderboardOnClicks;
-    use std::collections::HashMap;
     use std::time::{SystemTime, UNIX_EPOCH};
-    use uuid::Uuid;
 
     #[tokio::test]
     async fn test_concurrent_updates() {
@@ -225,6 +231,7 @@ mod tests {
                     click_id: "".to_string(),
                     country_id: format!("COUNTRY{}", i % 5),
                     timestamp_ns: base_time + i as u64,
+                    session_id: String::new(),
                 };
                 repo.save_click(tile_id, &click).await
             });
@@ -242,53 +249,19 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_leaderboard_accuracy() {
-        let repository = PapayaClickRepository::new();
-        let click_repo = Arc::new(repository.clone());
-        let mut leaderboard_handles = Vec::new();
-
-        for i in 0..10 {
-            let repo = click_repo.clone();  // Clone the Arc before moving into spawn
-            let handle = tokio::spawn(async move {
-                let click = Click {
-                    tile_id: i,
-                    country_id: format!("COUNTRY{}", i % 2),
-                    timestamp_ns: (10 + i * 10) as u64,
-                    click_id: Uuid::new_v4().to_string(),
-                };
-
-                println!("Processing click: {:?}", click);
-                repo.save_click(click.tile_id as u32, &click).await
-            });
-
-            leaderboard_handles.push(handle);
+    async fn test_country_index_keeps_countries_with_tiles() {
+        let repo = PapayaClickRepository::new();
+        for tile_id in [1, 2] {
+            repo.update_country_index(tile_id, "fr", None).await;
         }
 
-        // Wait for all clicks to be processed
-        for handle in leaderboard_handles {
-            handle.await.unwrap().unwrap();
-        }
-
-        // Create leaderboard computation after all clicks are processed
-        let leader_board_computation = LeaderboardOnClicks(repository);
-
-        // Check scores
-        let score0 = leader_board_computation.get_score("COUNTRY0").await.unwrap();
-        let score1 = leader_board_computation.get_score("COUNTRY1").await.unwrap();
-        let score2 = leader_board_computation.get_score("COUNTRY2").await.unwrap();
-
-        // Get and verify leaderboard
-        let leaderboard = leader_board_computation.leaderboard().await.unwrap();
-
-        let expected_map = {
-            let mut map = HashMap::new();
-            map.insert("COUNTRY0".to_string(), 5);
-            map.insert("COUNTRY1".to_string(), 5);
-            map
-        };
+        repo.update_country_index(1, "de", Some("fr")).await;
+        let scores = repo.leaderboard().await.unwrap();
+        assert_eq!(scores, HashMap::from([("fr".to_string(), 1), ("de".to_string(), 1)]));
 
-        assert_eq!(leaderboard, expected_map);
-        assert_eq!(score0 + score1 + score2, 10);
+        repo.update_country_index(2, "de", Some("fr")).await;
+        let scores = repo.leaderboard().await.unwrap();
+        assert_eq!(scores, HashMap::from([("de".to_string(), 2)]));
     }
 }
 
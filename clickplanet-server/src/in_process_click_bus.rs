@@ -0,0 +1,471 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use clickplanet_proto::clicks::{Click, SystemMessage};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use prost::Message;
+use tokio::sync::{broadcast, watch};
+use tracing::{error, info, warn};
+
+use crate::admin::Restrictions;
+use crate::click_bus::{Acker, ClickBus, ClickBusError, ClickSubscription, ConsumerStatus, Delivery, CLICK_RETENTION};
+use crate::click_publisher::ClickPublisher;
+use crate::nats_commons::ConsumerConfig;
+
+/// System messages buffered for the servers' WebSocket fan-out
+const SYSTEM_MESSAGE_CAPACITY: usize = 16;
+
+struct NoAck(u64);
+
+#[async_trait]
+impl Acker for NoAck {
+    async fn ack(self: Box<Self>) -> Result<(), ClickBusError> {
+        Ok(())
+    }
+
+    // No dead letter queue without NATS
+    async fn dead_letter(self: Box<Self>, reason: &str) -> Result<(), ClickBusError> {
+        error!("Dropping click {}: {}", self.0, reason);
+        Ok(())
+    }
+}
+
+struct ClickLog {
+    clicks: VecDeque<Click>,
+    // Sequence number of the first retained click
+    first_seq: u64,
+    // Sequence number of the next click to deliver to each durable consumer
+    cursors: HashMap<String, u64>,
+    // Same for the last subscription given a start sequence under each name, which is not
+    // shared with other subscriptions
+    ephemeral_cursors: HashMap<String, Arc<AtomicU64>>,
+    file: Option<BufWriter<File>>,
+}
+
+impl ClickLog {
+    fn end_seq(&self) -> u64 {
+        self.first_seq + self.clicks.len() as u64
+    }
+
+    fn prune_older_than(&mut self, cutoff_ns: u64) {
+        while self.clicks.front().is_some_and(|click| click.timestamp_ns < cutoff_ns) {
+            self.clicks.pop_front();
+            self.first_seq += 1;
+        }
+    }
+
+    /// The click at `seq`, or the oldest retained one after it if it expired.
+    fn get_from(&self, seq: u64) -> Option<(u64, Click)> {
+        let seq = seq.max(self.first_seq);
+        let click = self.clicks.get((seq - self.first_seq) as usize)?.clone();
+        Some((seq, click))
+    }
+
+    fn next_for(&mut self, consumer_name: &str) -> Option<(u64, Click)> {
+        let cursor = self.cursors.get(consumer_name).copied().unwrap_or(self.first_seq);
+        let (seq, click) = self.get_from(cursor)?;
+        self.cursors.insert(consumer_name.to_string(), seq + 1);
+        Some((seq, click))
+    }
+
+    /// Clicks not yet delivered to the last subscription of `consumer_name`.
+    fn lag_of(&self, consumer_name: &str) -> Option<u64> {
+        let next_seq = match self.ephemeral_cursors.get(consumer_name) {
+            Some(seq) => seq.load(Ordering::Relaxed),
+            None => *self.cursors.get(consumer_name)?,
+        };
+        Some(self.end_seq().saturating_sub(next_seq.max(self.first_seq)))
+    }
+}
+
+/// Where a subscription reads the log from.
+enum Cursor {
+    Durable(String),
+    From(Arc<AtomicU64>),
+}
+
+/// Click bus living inside the server process, for single-node deployments and tests.
+///
+/// Acknowledgments are not tracked: a click is delivered once per durable consumer. When a
+/// log file is given, retained clicks are replayed to consumers after a restart, which the
+/// timestamp checks of the click repositories make harmless.
+pub struct InProcessClickBus {
+    log: Arc<Mutex<ClickLog>>,
+    published: watch::Sender<u64>,
+    retention: Duration,
+    system_messages: broadcast::Sender<SystemMessage>,
+}
+
+impl InProcessClickBus {
+    pub fn new() -> Self {
+        Self::with_retention(CLICK_RETENTION)
+    }
+
+    /// Keeps the clicks published during the last `retention`.
+    pub fn with_retention(retention: Duration) -> Self {
+        Self::with_clicks(1, VecDeque::new(), None, retention)
+    }
+
+    /// Restores the clicks retained in `path` and appends new ones to it.
+    pub fn with_log_file(path: impl AsRef<Path>, retention: Duration) -> Result<Self, ClickBusError> {
+        let path = path.as_ref();
+        let (mut first_seq, mut clicks) = read_log(path)?;
+
+        let cutoff_ns = now_ns().saturating_sub(retention.as_nanos() as u64);
+        while clicks.front().is_some_and(|click| click.timestamp_ns < cutoff_ns) {
+            clicks.pop_front();
+            first_seq += 1;
+        }
+        info!("Restored {} clicks from {}", clicks.len(), path.display());
+
+        // Rewrite the log without the expired clicks
+        let compacted_path = PathBuf::from(format!("{}.compacted", path.display()));
+        {
+            let mut writer = BufWriter::new(File::create(&compacted_path)?);
+            writer.write_all(&first_seq.to_le_bytes())?;
+            for click in &clicks {
+                writer.write_all(&click.encode_length_delimited_to_vec())?;
+            }
+            writer.flush()?;
+        }
+        std::fs::rename(&compacted_path, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self::with_clicks(first_seq, clicks, Some(BufWriter::new(file)), retention))
+    }
+
+    fn with_clicks(first_seq: u64, clicks: VecDeque<Click>, file: Option<BufWriter<File>>, retention: Duration) -> Self {
+        let end_seq = first_seq + clicks.len() as u64;
+        let (published, _) = watch::channel(end_seq);
+
+        Self {
+            log: Arc::new(Mutex::new(ClickLog {
+                clicks,
+                first_seq,
+                cursors: HashMap::new(),
+                ephemeral_cursors: HashMap::new(),
+                file,
+            })),
+            published,
+            retention,
+            system_messages: broadcast::channel(SYSTEM_MESSAGE_CAPACITY).0,
+        }
+    }
+}
+
+impl Default for InProcessClickBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads the sequence of the first click, stored as a little endian header, then the clicks.
+fn read_log(path: &Path) -> Result<(u64, VecDeque<Click>), ClickBusError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((1, VecDeque::new())),
+        Err(e) => return Err(e.into()),
+    };
+
+    let Some((header, mut remaining)) = bytes.split_first_chunk::<8>() else {
+        return Ok((1, VecDeque::new()));
+    };
+    let first_seq = u64::from_le_bytes(*header);
+
+    let mut clicks = VecDeque::new();
+    while !remaining.is_empty() {
+        match Click::decode_length_delimited(&mut remaining) {
+            Ok(click) => clicks.push_back(click),
+            Err(e) => {
+                // Most likely a write interrupted by a crash
+                warn!("Ignoring the end of click log {}: {}", path.display(), e);
+                break;
+            }
+        }
+    }
+
+    Ok((first_seq, clicks))
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+#[async_trait]
+impl ClickBus for InProcessClickBus {
+    async fn subscribe(&self, consumer_name: &str, config: &ConsumerConfig) -> Result<ClickSubscription, ClickBusError> {
+        let cursor = {
+            let mut log = self.log.lock().unwrap();
+            match config.start_sequence {
+                Some(start_sequence) => {
+                    let seq = Arc::new(AtomicU64::new(start_sequence));
+                    log.ephemeral_cursors.insert(consumer_name.to_string(), seq.clone());
+                    Cursor::From(seq)
+                }
+                None => {
+                    // A durable subscription replaces an ephemeral one in the status
+                    log.ephemeral_cursors.remove(consumer_name);
+                    let first_seq = log.first_seq;
+                    log.cursors.entry(consumer_name.to_string()).or_insert(first_seq);
+                    Cursor::Durable(consumer_name.to_string())
+                }
+            }
+        };
+        let state = (self.log.clone(), self.published.subscribe(), cursor);
+
+        Ok(futures::stream::unfold(state, |(log, mut published, cursor)| async move {
+            loop {
+                let next = {
+                    let mut log = log.lock().unwrap();
+                    match &cursor {
+                        Cursor::Durable(consumer_name) => log.next_for(consumer_name),
+                        Cursor::From(seq) => log
+                            .get_from(seq.load(Ordering::Relaxed))
+                            .inspect(|(delivered, _)| seq.store(delivered + 1, Ordering::Relaxed)),
+                    }
+                };
+                if let Some((sequence, click)) = next {
+                    let delivery = Delivery::new(sequence, 1, click, Box::new(NoAck(sequence)));
+                    return Some((Ok(delivery), (log, published, cursor)));
+                }
+
+                // The bus was dropped
+                published.changed().await.ok()?;
+            }
+        }).boxed())
+    }
+
+    // Clicks are written to the log file as they are published
+    async fn flush(&self) -> Result<(), ClickBusError> {
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    // Clicks are processed as soon as delivered, without acknowledgments
+    async fn consumer_status(&self, consumer_name: &str) -> Result<ConsumerStatus, ClickBusError> {
+        let log = self.log.lock().unwrap();
+        let lag = log.lag_of(consumer_name)
+            .ok_or_else(|| ClickBusError::Status(format!("No consumer {}", consumer_name)))?;
+
+        Ok(ConsumerStatus {
+            last_sequence: log.end_seq() - 1,
+            lag,
+        })
+    }
+}
+
+#[async_trait]
+impl ClickPublisher for InProcessClickBus {
+    async fn publish(&self, click: &Click) -> Result<(), ClickBusError> {
+        let end_seq = {
+            let mut log = self.log.lock().unwrap();
+
+            if let Some(file) = log.file.as_mut() {
+                file.write_all(&click.encode_length_delimited_to_vec())?;
+                file.flush()?;
+            }
+
+            log.clicks.push_back(click.clone());
+            log.prune_older_than(now_ns().saturating_sub(self.retention.as_nanos() as u64));
+            log.end_seq()
+        };
+
+        self.published.send_replace(end_seq);
+        Ok(())
+    }
+
+    async fn history(&self) -> Result<BoxStream<'static, Result<Click, ClickBusError>>, ClickBusError> {
+        let clicks: Vec<Click> = self.log.lock().unwrap().clicks.iter().cloned().collect();
+        Ok(futures::stream::iter(clicks.into_iter().map(Ok)).boxed())
+    }
+
+    // This server is the only one
+    async fn publish_restrictions(&self, _restrictions: &Restrictions) -> Result<(), ClickBusError> {
+        Ok(())
+    }
+
+    async fn restriction_updates(&self) -> Result<BoxStream<'static, Restrictions>, ClickBusError> {
+        Ok(futures::stream::empty().boxed())
+    }
+
+    async fn publish_system_message(&self, message: &SystemMessage) -> Result<(), ClickBusError> {
+        // No one to tell when no WebSocket client is connected
+        let _ = self.system_messages.send(message.clone());
+        Ok(())
+    }
+
+    async fn system_messages(&self) -> Result<BoxStream<'static, SystemMessage>, ClickBusError> {
+        let messages = futures::stream::unfold(self.system_messages.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => return Some((message, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        Ok(messages.boxed())
+    }
+
+    fn as_click_bus(self: Arc<Self>) -> Arc<dyn ClickBus> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn click(tile_id: i32, country_id: &str) -> Click {
+        Click {
+            tile_id,
+            country_id: country_id.to_string(),
+            timestamp_ns: now_ns(),
+            click_id: String::new(),
+            session_id: String::new(),
+        }
+    }
+
+    async fn next_delivery(subscription: &mut ClickSubscription) -> (u64, i32) {
+        let delivery = tokio::time::timeout(Duration::from_secs(1), subscription.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let delivered = (delivery.sequence, delivery.click.tile_id);
+        delivery.ack().await.unwrap();
+        delivered
+    }
+
+    async fn next_tile(subscription: &mut ClickSubscription) -> i32 {
+        next_delivery(subscription).await.1
+    }
+
+    #[tokio::test]
+    async fn test_durable_consumers() {
+        let bus = InProcessClickBus::new();
+        let config = ConsumerConfig::default();
+
+        bus.publish(&click(1, "fr")).await.unwrap();
+        bus.publish(&click(2, "de")).await.unwrap();
+
+        let mut persister = bus.subscribe("persister", &config).await.unwrap();
+        let mut updates = bus.subscribe("updates", &config).await.unwrap();
+
+        assert_eq!(next_tile(&mut persister).await, 1);
+        assert_eq!(next_tile(&mut updates).await, 1);
+        assert_eq!(next_tile(&mut updates).await, 2);
+
+        // Resubscribing resumes where the consumer stopped
+        drop(persister);
+        let mut persister = bus.subscribe("persister", &config).await.unwrap();
+        assert_eq!(next_tile(&mut persister).await, 2);
+
+        // Waits for clicks published later
+        let pending = tokio::spawn(async move { next_tile(&mut persister).await });
+        bus.publish(&click(3, "es")).await.unwrap();
+        assert_eq!(pending.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_expired_clicks_are_dropped() {
+        let bus = InProcessClickBus::new();
+
+        let mut expired = click(1, "fr");
+        expired.timestamp_ns -= CLICK_RETENTION.as_nanos() as u64 + 1;
+        bus.publish(&expired).await.unwrap();
+        bus.publish(&click(2, "de")).await.unwrap();
+
+        let mut subscription = bus.subscribe("persister", &ConsumerConfig::default()).await.unwrap();
+        assert_eq!(next_tile(&mut subscription).await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_clicks_are_restored_from_log_file() {
+        let path = std::env::temp_dir().join(format!("clicks-{}.log", uuid::Uuid::new_v4()));
+
+        {
+            let bus = InProcessClickBus::with_log_file(&path, CLICK_RETENTION).unwrap();
+            bus.publish(&click(1, "fr")).await.unwrap();
+            bus.publish(&click(2, "de")).await.unwrap();
+        }
+
+        // Half written click at the end of the file
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&click(3, "es").encode_length_delimited_to_vec()[..4]).unwrap();
+
+        let bus = InProcessClickBus::with_log_file(&path, CLICK_RETENTION).unwrap();
+        bus.publish(&click(4, "it")).await.unwrap();
+
+        let mut subscription = bus.subscribe("persister", &ConsumerConfig::default()).await.unwrap();
+        assert_eq!(next_delivery(&mut subscription).await, (1, 1));
+        assert_eq!(next_delivery(&mut subscription).await, (2, 2));
+        assert_eq!(next_delivery(&mut subscription).await, (3, 4));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sequences_survive_compaction() {
+        let path = std::env::temp_dir().join(format!("clicks-{}.log", uuid::Uuid::new_v4()));
+
+        {
+            let bus = InProcessClickBus::with_log_file(&path, CLICK_RETENTION).unwrap();
+            let mut expired = click(1, "fr");
+            expired.timestamp_ns -= CLICK_RETENTION.as_nanos() as u64 + 1;
+            // Stays in the file until the next restart
+            bus.publish(&expired).await.unwrap();
+            bus.publish(&click(2, "de")).await.unwrap();
+        }
+
+        let bus = InProcessClickBus::with_log_file(&path, CLICK_RETENTION).unwrap();
+        bus.publish(&click(3, "es")).await.unwrap();
+
+        let config = ConsumerConfig {
+            start_sequence: Some(3),
+            ..Default::default()
+        };
+        let mut subscription = bus.subscribe("updates", &config).await.unwrap();
+        assert_eq!(next_delivery(&mut subscription).await, (3, 3));
+
+        let mut subscription = bus.subscribe("updates", &ConsumerConfig::default()).await.unwrap();
+        assert_eq!(next_delivery(&mut subscription).await, (2, 2));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_consumer_status() {
+        let bus = InProcessClickBus::new();
+        for tile_id in 1..=3 {
+            bus.publish(&click(tile_id, "fr")).await.unwrap();
+        }
+        assert!(bus.consumer_status("updates").await.is_err());
+
+        let mut durable = bus.subscribe("updates", &ConsumerConfig::default()).await.unwrap();
+        assert_eq!(bus.consumer_status("updates").await.unwrap(), ConsumerStatus { last_sequence: 3, lag: 3 });
+        next_tile(&mut durable).await;
+        assert_eq!(bus.consumer_status("updates").await.unwrap().lag, 2);
+
+        // The subscription given a start sequence is reported instead
+        let config = ConsumerConfig { start_sequence: Some(3), ..Default::default() };
+        let mut replay = bus.subscribe("updates", &config).await.unwrap();
+        assert_eq!(bus.consumer_status("updates").await.unwrap().lag, 1);
+        next_tile(&mut replay).await;
+        assert_eq!(bus.consumer_status("updates").await.unwrap().lag, 0);
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clickplanet_proto::clicks::{InvasionFlow, InvasionFlowResponse, Rivalry, UpdateNotification};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+use crate::metrics::METRICS;
+
+const BUCKET_SECS: u64 = 60;
+const RETENTION_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowWindow {
+    FiveMinutes,
+    Hour,
+    Day,
+}
+
+impl FlowWindow {
+    pub fn duration_secs(&self) -> u64 {
+        match self {
+            FlowWindow::FiveMinutes => 5 * 60,
+            FlowWindow::Hour => 60 * 60,
+            FlowWindow::Day => 24 * 60 * 60,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlowWindow::FiveMinutes => "5m",
+            FlowWindow::Hour => "1h",
+            FlowWindow::Day => "1d",
+        }
+    }
+}
+
+impl FromStr for FlowWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "5m" => Ok(FlowWindow::FiveMinutes),
+            "1h" => Ok(FlowWindow::Hour),
+            "1d" => Ok(FlowWindow::Day),
+            other => Err(format!("Unknown window {}, expected one of 5m, 1h, 1d", other)),
+        }
+    }
+}
+
+/// Captures recorded during one minute, as attacker -> defender -> count.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct FlowBucket {
+    start_secs: u64,
+    captures: HashMap<String, HashMap<String, u32>>,
+}
+
+/// Counts tile captures between every pair of countries over rolling windows.
+///
+/// Captures are aggregated in one-minute buckets kept for a day, so a window is the sum
+/// of its most recent buckets.
+pub struct InvasionFlowTracker {
+    buckets: Mutex<VecDeque<FlowBucket>>,
+}
+
+impl Default for InvasionFlowTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InvasionFlowTracker {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let buckets: VecDeque<FlowBucket> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Ok(Self {
+            buckets: Mutex::new(buckets),
+        })
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = path.as_ref();
+        // Serialized once copied, not to hold up the captures being recorded
+        let buckets = {
+            let mut buckets = self.buckets.lock().unwrap();
+            Self::prune(&mut buckets, now_secs());
+            buckets.clone()
+        };
+        let json = serde_json::to_string(&buckets)?;
+
+        // Write then rename so a crash never leaves a truncated file behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, json)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn record(&self, update: &UpdateNotification) {
+        self.record_at(update, now_secs());
+    }
+
+    fn record_at(&self, update: &UpdateNotification, now_secs: u64) {
+        // First captures of unowned tiles are not invasions, nor are tiles being cleared
+        if update.previous_country_id.is_empty() || update.country_id.is_empty() || update.previous_country_id == update.country_id {
+            return;
+        }
+
+        let start_secs = now_secs - now_secs % BUCKET_SECS;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.back().is_none_or(|bucket| bucket.start_secs < start_secs) {
+            buckets.push_back(FlowBucket {
+                start_secs,
+                captures: HashMap::new(),
+            });
+            Self::prune(&mut buckets, now_secs);
+        }
+
+        let bucket = buckets.back_mut().unwrap();
+        *bucket.captures
+            .entry(update.country_id.clone())
+            .or_default()
+            .entry(update.previous_country_id.clone())
+            .or_insert(0) += 1;
+    }
+
+    fn prune(buckets: &mut VecDeque<FlowBucket>, now_secs: u64) {
+        while buckets
+            .front()
+            .is_some_and(|bucket| bucket.start_secs + RETENTION_SECS <= now_secs)
+        {
+            buckets.pop_front();
+        }
+    }
+
+    /// Captures per (attacker, defender) pair over the window.
+    pub fn matrix(&self, window: FlowWindow) -> HashMap<(String, String), u32> {
+        self.matrix_at(window, now_secs())
+    }
+
+    fn matrix_at(&self, window: FlowWindow, now_secs: u64) -> HashMap<(String, String), u32> {
+        let since = now_secs.saturating_sub(window.duration_secs());
+        let buckets = self.buckets.lock().unwrap();
+        let mut matrix = HashMap::new();
+
+        for bucket in buckets.iter().rev().take_while(|bucket| bucket.start_secs + BUCKET_SECS > since) {
+            for (attacker, defenders) in &bucket.captures {
+                for (defender, captures) in defenders {
+                    *matrix.entry((attacker.clone(), defender.clone())).or_insert(0) += captures;
+                }
+            }
+        }
+
+        matrix
+    }
+
+    pub fn flows(&self, window: FlowWindow, top_rivalries: usize) -> InvasionFlowResponse {
+        Self::to_response(window, self.matrix(window), top_rivalries)
+    }
+
+    fn to_response(window: FlowWindow, matrix: HashMap<(String, String), u32>, top_rivalries: usize) -> InvasionFlowResponse {
+        // A rivalry merges both directions of a country pair
+        let mut rivalries: HashMap<(String, String), Rivalry> = HashMap::new();
+        for ((attacker, defender), captures) in &matrix {
+            let (country_a, country_b) = if attacker < defender {
+                (attacker, defender)
+            } else {
+                (defender, attacker)
+            };
+
+            let rivalry = rivalries
+                .entry((country_a.clone(), country_b.clone()))
+                .or_insert_with(|| Rivalry {
+                    country_a: country_a.clone(),
+                    country_b: country_b.clone(),
+                    ..Default::default()
+                });
+
+            if attacker == country_a {
+                rivalry.captures_by_a += captures;
+            } else {
+                rivalry.captures_by_b += captures;
+            }
+            rivalry.total_captures += captures;
+        }
+
+        let mut rivalries: Vec<Rivalry> = rivalries.into_values().collect();
+        rivalries.sort_by(|a, b| {
+            b.total_captures
+                .cmp(&a.total_captures)
+                .then_with(|| (&a.country_a, &a.country_b).cmp(&(&b.country_a, &b.country_b)))
+        });
+        rivalries.truncate(top_rivalries);
+
+        let mut flows: Vec<InvasionFlow> = matrix
+            .into_iter()
+            .map(|((attacker_country_id, defender_country_id), captures)| InvasionFlow {
+                attacker_country_id,
+                defender_country_id,
+                captures,
+            })
+            .collect();
+        flows.sort_by(|a, b| {
+            b.captures
+                .cmp(&a.captures)
+                .then_with(|| a.attacker_country_id.cmp(&b.attacker_country_id))
+                .then_with(|| a.defender_country_id.cmp(&b.defender_country_id))
+        });
+
+        InvasionFlowResponse {
+            window: window.as_str().to_string(),
+            flows,
+            top_rivalries: rivalries,
+        }
+    }
+
+    pub async fn run(self: Arc<Self>, mut updates: broadcast::Receiver<UpdateNotification>) {
+        info!("Starting invasion flow tracker");
+
+        loop {
+            match updates.recv().await {
+                Ok(update) => self.record(&update),
+                Err(RecvError::Lagged(skipped)) => {
+                    METRICS.broadcast_lag_events.with("invasion_flows").inc();
+                    warn!("Invasion flow tracker missed {} updates", skipped);
+                }
+                Err(RecvError::Closed) => {
+                    info!("Update channel closed, stopping invasion flow tracker");
+                    break;
+                }
+            }
+        }
+    }
+
+    pub async fn persist_periodically(self: Arc<Self>, path: String, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            let (tracker, file) = (self.clone(), path.clone());
+            let saved = tokio::task::spawn_blocking(move || tracker.save_to_file(file)).await;
+            if let Err(e) = saved.map_err(Into::into).and_then(|saved| saved) {
+                error!("Failed to persist invasion flows to {}: {}", path, e);
+            }
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn capture(country_id: &str, previous_country_id: &str) -> UpdateNotification {
+        UpdateNotification {
+            tile_id: 1,
+            country_id: country_id.to_string(),
+            previous_country_id: previous_country_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_windows() {
+        let tracker = InvasionFlowTracker::new();
+
+        tracker.record_at(&capture("fr", "de"), NOW - 2 * 60 * 60);
+        tracker.record_at(&capture("fr", "de"), NOW - 30 * 60);
+        tracker.record_at(&capture("fr", "de"), NOW - 60);
+        tracker.record_at(&capture("de", "fr"), NOW);
+        tracker.record_at(&capture("fr", ""), NOW);
+
+        let pair = |attacker: &str, defender: &str| (attacker.to_string(), defender.to_string());
+
+        let five_minutes = tracker.matrix_at(FlowWindow::FiveMinutes, NOW);
+        assert_eq!(five_minutes.len(), 2);
+        assert_eq!(five_minutes[&pair("fr", "de")], 1);
+        assert_eq!(five_minutes[&pair("de", "fr")], 1);
+
+        assert_eq!(tracker.matrix_at(FlowWindow::Hour, NOW)[&pair("fr", "de")], 2);
+        assert_eq!(tracker.matrix_at(FlowWindow::Day, NOW)[&pair("fr", "de")], 3);
+
+        // The day window slides forward with time
+        tracker.record_at(&capture("es", "pt"), NOW + RETENTION_SECS - 60 * 60);
+        let day = tracker.matrix_at(FlowWindow::Day, NOW + RETENTION_SECS - 60 * 60);
+        assert_eq!(day[&pair("fr", "de")], 2);
+    }
+
+    #[test]
+    fn test_rivalries() {
+        let tracker = InvasionFlowTracker::new();
+
+        for _ in 0..3 {
+            tracker.record_at(&capture("fr", "de"), NOW);
+        }
+        tracker.record_at(&capture("de", "fr"), NOW);
+        tracker.record_at(&capture("es", "pt"), NOW);
+
+        let response = InvasionFlowTracker::to_response(
+            FlowWindow::Hour,
+            tracker.matrix_at(FlowWindow::Hour, NOW),
+            1,
+        );
+
+        assert_eq!(response.window, "1h");
+        assert_eq!(response.flows.len(), 3);
+        assert_eq!(response.flows[0].attacker_country_id, "fr");
+        assert_eq!(response.flows[0].captures, 3);
+        assert_eq!(
+            response.top_rivalries,
+            vec![Rivalry {
+                country_a: "de".to_string(),
+                country_b: "fr".to_string(),
+                captures_by_a: 1,
+                captures_by_b: 3,
+                total_captures: 4,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_window_parsing() {
+        assert_eq!("5m".parse::<FlowWindow>(), Ok(FlowWindow::FiveMinutes));
+        assert_eq!("1d".parse::<FlowWindow>(), Ok(FlowWindow::Day));
+        assert!("1w".parse::<FlowWindow>().is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_nats::jetstream;
+use async_nats::jetstream::Context;
+use async_nats::HeaderMap;
+use async_trait::async_trait;
+use clickplanet_proto::clicks::Click;
+use futures::StreamExt;
+use prost::Message;
+use tracing::error;
+
+use crate::click_bus::{Acker, ClickBus, ClickBusError, ClickSubscription, ConsumerStatus, Delivery};
+use crate::nats_commons::{ConsumerConfig, CLICK_STREAM_NAME, CLICK_SUBJECT_PREFIX};
+
+pub const DEAD_LETTER_STREAM_NAME: &str = "CLICKS_DLQ";
+/// Dead letters are published on the original subject behind this prefix
+pub const DEAD_LETTER_SUBJECT_PREFIX: &str = "clicks_dlq.";
+pub const ERROR_HEADER: &str = "Clickplanet-Error";
+pub const DELIVERY_COUNT_HEADER: &str = "Clickplanet-Delivery-Count";
+pub const STREAM_SEQUENCE_HEADER: &str = "Clickplanet-Stream-Sequence";
+pub const CONSUMER_HEADER: &str = "Clickplanet-Consumer";
+const DEAD_LETTER_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+pub fn dead_letter_stream_config() -> jetstream::stream::Config {
+    jetstream::stream::Config {
+        name: DEAD_LETTER_STREAM_NAME.to_string(),
+        subjects: vec![format!("{}>", DEAD_LETTER_SUBJECT_PREFIX)],
+        max_age: DEAD_LETTER_RETENTION,
+        ..Default::default()
+    }
+}
+
+struct JetStreamAcker {
+    message: jetstream::Message,
+    jetstream: Arc<Context>,
+    consumer_name: String,
+}
+
+impl JetStreamAcker {
+    /// Copies the message to the dead letter stream, waiting for it to be stored.
+    async fn publish_dead_letter(&self, reason: &str) -> Result<(), ClickBusError> {
+        let (stream_sequence, delivered) = self.message
+            .info()
+            .map(|info| (info.stream_sequence, info.delivered))
+            .map_err(|e| ClickBusError::DeadLetter(e.to_string()))?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ERROR_HEADER, reason);
+        headers.insert(DELIVERY_COUNT_HEADER, delivered.to_string().as_str());
+        headers.insert(STREAM_SEQUENCE_HEADER, stream_sequence.to_string().as_str());
+        headers.insert(CONSUMER_HEADER, self.consumer_name.as_str());
+
+        self.jetstream
+            .publish_with_headers(
+                format!("{}{}", DEAD_LETTER_SUBJECT_PREFIX, self.message.subject),
+                headers,
+                self.message.payload.clone(),
+            )
+            .await
+            .map_err(|e| ClickBusError::DeadLetter(e.to_string()))?
+            .await
+            .map_err(|e| ClickBusError::DeadLetter(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Acker for JetStreamAcker {
+    async fn ack(self: Box<Self>) -> Result<(), ClickBusError> {
+        self.message.ack().await.map_err(|e| ClickBusError::Ack(e.to_string()))
+    }
+
+    async fn dead_letter(self: Box<Self>, reason: &str) -> Result<(), ClickBusError> {
+        // Left for redelivery if it could not be set aside
+        self.publish_dead_letter(reason).await?;
+        self.ack().await
+    }
+}
+
+/// Clicks published on `clicks.tile.<tile_id>` subjects of the `CLICKS` stream, consumed
+/// by durable pull consumers.
+pub struct JetStreamClickBus {
+    pub(crate) client: async_nats::Client,
+    pub(crate) jetstream: Arc<Context>,
+    retention: Duration,
+    // Consumers of the subscriptions, by the name they were subscribed with
+    consumers: Mutex<HashMap<String, jetstream::consumer::Consumer<jetstream::consumer::pull::Config>>>,
+}
+
+impl JetStreamClickBus {
+    /// Connects and creates or updates the clicks stream, keeping clicks for `retention`.
+    pub async fn connect(nats_url: &str, retention: Duration) -> Result<Self, ClickBusError> {
+        let client = async_nats::connect(nats_url)
+            .await
+            .map_err(|e| ClickBusError::Connection(e.to_string()))?;
+        let jetstream = async_nats::jetstream::new(client.clone());
+
+        let stream_config = async_nats::jetstream::stream::Config {
+            name: CLICK_STREAM_NAME.to_string(),
+            subjects: vec![format!("{}*", CLICK_SUBJECT_PREFIX).to_string()],
+            max_age: retention,
+            discard: async_nats::jetstream::stream::DiscardPolicy::Old,
+            ..Default::default()
+        };
+
+        match jetstream.get_stream(CLICK_STREAM_NAME).await {
+            Ok(_) => {
+                jetstream
+                    .update_stream(stream_config)
+                    .await
+                    .map_err(|e| ClickBusError::Connection(e.to_string()))?;
+            }
+            Err(_) => {
+                jetstream
+                    .create_stream(stream_config)
+                    .await
+                    .map_err(|e| ClickBusError::Connection(e.to_string()))?;
+            }
+        }
+
+        jetstream
+            .get_or_create_stream(dead_letter_stream_config())
+            .await
+            .map_err(|e| ClickBusError::Connection(e.to_string()))?;
+
+        Ok(Self {
+            client,
+            jetstream: Arc::new(jetstream),
+            retention,
+            consumers: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl ClickBus for JetStreamClickBus {
+    async fn subscribe(&self, consumer_name: &str, config: &ConsumerConfig) -> Result<ClickSubscription, ClickBusError> {
+        let stream = self.jetstream
+            .get_stream(CLICK_STREAM_NAME)
+            .await
+            .map_err(|e| ClickBusError::Subscribe(e.to_string()))?;
+
+        let consumer_config = match config.start_sequence {
+            // Ephemeral consumer, removed by the server once unused
+            Some(start_sequence) => jetstream::consumer::pull::Config {
+                deliver_policy: jetstream::consumer::DeliverPolicy::ByStartSequence { start_sequence },
+                ack_policy: jetstream::consumer::AckPolicy::Explicit,
+                ack_wait: config.ack_wait,
+                max_deliver: config.max_deliver,
+                ..Default::default()
+            },
+            None => jetstream::consumer::pull::Config {
+                durable_name: Some(consumer_name.to_string()),
+                deliver_policy: jetstream::consumer::DeliverPolicy::All,
+                ack_policy: jetstream::consumer::AckPolicy::Explicit,
+                ack_wait: config.ack_wait,
+                max_deliver: config.max_deliver,
+                name: Some(consumer_name.to_string()),
+                // A durable consumer nobody reads, as the one left by a server restored from
+                // a snapshot, is removed rather than piling up pending clicks. Its clicks are
+                // gone by then anyway
+                inactive_threshold: self.retention,
+                ..Default::default()
+            },
+        };
+
+        let consumer = stream
+            .create_consumer(consumer_config)
+            .await
+            .map_err(|e| ClickBusError::Subscribe(e.to_string()))?;
+        self.consumers.lock().unwrap().insert(consumer_name.to_string(), consumer.clone());
+
+        let messages = consumer
+            .stream()
+            .max_messages_per_batch(config.batch_size)
+            .messages()
+            .await
+            .map_err(|e| ClickBusError::Subscribe(e.to_string()))?;
+
+        let jetstream = self.jetstream.clone();
+        let consumer_name = consumer_name.to_string();
+
+        Ok(messages
+            .filter_map(move |message_result| {
+                let jetstream = jetstream.clone();
+                let consumer_name = consumer_name.clone();
+
+                async move {
+                    let message = match message_result {
+                        Ok(message) => message,
+                        Err(e) => return Some(Err(ClickBusError::Receive(e.to_string()))),
+                    };
+
+                    let (sequence, delivered) = match message.info() {
+                        Ok(info) => (info.stream_sequence, info.delivered as u64),
+                        Err(e) => return Some(Err(ClickBusError::Receive(e.to_string()))),
+                    };
+
+                    let decoded = Click::decode(message.payload.clone());
+                    let acker = Box::new(JetStreamAcker { message, jetstream, consumer_name });
+
+                    match decoded {
+                        Ok(click) => Some(Ok(Delivery::new(sequence, delivered, click, acker))),
+                        Err(e) => {
+                            error!("Failed to decode click {}: {}", sequence, e);
+                            // Malformed messages would be redelivered forever
+                            if let Err(dead_letter_err) = acker.dead_letter(&format!("Undecodable click: {}", e)).await {
+                                error!("Failed to dead-letter malformed message: {}", dead_letter_err);
+                            }
+                            None
+                        }
+                    }
+                }
+            })
+            .boxed())
+    }
+
+    async fn flush(&self) -> Result<(), ClickBusError> {
+        self.client.flush().await.map_err(|e| ClickBusError::Publish(e.to_string()))
+    }
+
+    fn is_connected(&self) -> bool {
+        self.client.connection_state() == async_nats::connection::State::Connected
+    }
+
+    async fn consumer_status(&self, consumer_name: &str) -> Result<ConsumerStatus, ClickBusError> {
+        let consumer = self.consumers.lock().unwrap().get(consumer_name).cloned();
+        let mut consumer = consumer.ok_or_else(|| ClickBusError::Status(format!("No consumer {}", consumer_name)))?;
+
+        let mut stream = self.jetstream
+            .get_stream(CLICK_STREAM_NAME)
+            .await
+            .map_err(|e| ClickBusError::Status(e.to_string()))?;
+        let last_sequence = stream.info().await.map_err(|e| ClickBusError::Status(e.to_string()))?.state.last_sequence;
+        let info = consumer.info().await.map_err(|e| ClickBusError::Status(e.to_string()))?;
+
+        Ok(ConsumerStatus {
+            last_sequence,
+            lag: info.num_pending + info.num_ack_pending as u64,
+        })
+    }
+}
@@ -1,106 +1,245 @@
-use async_nats::{jetstream};
-use clickplanet_proto::clicks::{Click, UpdateNotification};
-use futures::{future, StreamExt};
-use prost::Message;
-use std::sync::Arc;
-use tracing::{error, info};
-use crate::click_persistence::{ClickRepository, LeaderboardRepository};
-use crate::redis_click_persistence::{RedisClickRepository};
-use crate::nats_commons;
-use crate::nats_commons::{get_stream, ConsumerConfig, PollingConsumerError};
+use futures::StreamExt;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use clickplanet_proto::clicks::Click;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+use crate::click_bus::{ClickBus, ClickBusError, ClickSubscription, Delivery};
+use crate::click_persistence::{ClickRepository, PersistenceCheckpoint};
+use crate::nats_commons::{ConsumerConfig, PollingConsumerError};
 
 
-const CONSUMER_NAME: &'static str = "tile-state-processor";
+pub const CONSUMER_NAME: &'static str = "tile-state-processor";
 
 pub struct ClickConsumer {
-    jetstream: Arc<jetstream::Context>,
+    click_bus: Arc<dyn ClickBus>,
     consumer_config: ConsumerConfig,
     click_repository: Arc<dyn ClickRepository>,
+    checkpoint: Option<Arc<dyn PersistenceCheckpoint>>,
 }
 
 impl ClickConsumer {
-    pub async fn new(nats_url: &str, consumer_config: Option<ConsumerConfig>,
-                     redis_click_repository: RedisClickRepository) -> Result<Self, PollingConsumerError> {
-        let client = async_nats::connect(nats_url).await?;
-        let jetstream = async_nats::jetstream::new(client);
-
-        Ok(Self {
-            jetstream: Arc::new(jetstream),
+    pub fn new(click_bus: Arc<dyn ClickBus>, consumer_config: Option<ConsumerConfig>,
+               click_repository: Arc<dyn ClickRepository>,
+               checkpoint: Option<Arc<dyn PersistenceCheckpoint>>) -> Self {
+        Self {
+            click_bus,
             consumer_config: consumer_config.unwrap_or_default(),
-            click_repository: Arc::new(redis_click_repository)
-        })
+            click_repository,
+            checkpoint,
+        }
     }
 
-    pub async fn create_consumer(
-        &self,
-    ) -> Result<jetstream::consumer::pull::Stream, PollingConsumerError> {
-        let stream = get_stream(self.jetstream.clone()).await?;
-
-        let config = jetstream::consumer::pull::Config {
-            durable_name: Some(CONSUMER_NAME.to_string()),
-            deliver_policy: jetstream::consumer::DeliverPolicy::All,
-            ack_policy: jetstream::consumer::AckPolicy::Explicit,
-            ack_wait: self.consumer_config.ack_wait,
-            max_deliver: self.consumer_config.max_deliver,
-            name: Some(CONSUMER_NAME.to_string()),
-            ..Default::default()
-        };
-
-        let consumer = stream
-            .create_consumer(config)
-            .await
-            .map_err(|e| PollingConsumerError::Processing(e.to_string()))?;
-
-        let messages = consumer
-            .messages()
-            .await
-            .map_err(|e| PollingConsumerError::Processing(e.to_string()))?;
-
-        Ok(messages)
+    pub async fn create_consumer(&self) -> Result<ClickSubscription, PollingConsumerError> {
+        Ok(self.click_bus.subscribe(CONSUMER_NAME, &self.consumer_config).await?)
     }
 
-    pub async fn run(&self) -> Result<(), PollingConsumerError> {
+    /// Writes the clicks by batches of the deliveries already received. Batches are written
+    /// concurrently but completed in order, and the checkpoint stops short of the clicks of
+    /// failed batches until they are written.
+    ///
+    /// Once `shutdown` is cancelled, no more clicks are pulled and the batches in progress
+    /// are finished.
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<(), PollingConsumerError> {
+        let saved = match &self.checkpoint {
+            Some(checkpoint) => checkpoint.checkpoint().await?,
+            None => None,
+        };
+        let tracker = Mutex::new(CheckpointTracker::new(saved));
+
         let consumer = self.create_consumer().await?;
         info!("Starting stream processor");
 
         consumer
-            .map(|message_result| {
+            .take_until(shutdown.cancelled_owned())
+            .ready_chunks(self.consumer_config.batch_size.max(1))
+            .map(|batch| self.handle_batch(batch))
+            .buffered(self.consumer_config.concurrent_processors.max(1))
+            .for_each(|outcome| {
+                let tracker = &tracker;
                 async move {
-                    match message_result {
-                        Ok(msg) => {
-                            info!("Processing message on subject: {}", msg.subject);
-                            if let Err(e) = self.handle_message(msg).await {
-                                error!("Error processing message: {}", e);
-                            }
+                    let advanced = match outcome {
+                        BatchOutcome::Persisted(sequences) => tracker.lock().unwrap().persisted(&sequences),
+                        BatchOutcome::Failed(sequences) => {
+                            tracker.lock().unwrap().failed(&sequences);
+                            None
+                        }
+                    };
+                    if let (Some(sequence), Some(checkpoint)) = (advanced, &self.checkpoint) {
+                        if let Err(e) = checkpoint.save_checkpoint(sequence).await {
+                            error!("Failed to save checkpoint {}: {}", sequence, e);
                         }
-                        Err(e) => error!("Error receiving message: {}", e),
                     }
                 }
             })
-            .buffer_unordered(self.consumer_config.concurrent_processors)
-            .for_each(|_| future::ready(()))
             .await;
 
+        info!("Stream processor stopped");
         Ok(())
     }
 
-    async fn handle_message(&self, message: jetstream::Message) -> Result<(), PollingConsumerError> {
-        let subject = message.subject.as_str();
-
-        let tile_id: u32 = subject
-            .strip_prefix(nats_commons::CLICK_SUBJECT_PREFIX)
-            .and_then(|id| id.parse().ok())
-            .ok_or_else(|| PollingConsumerError::Processing("Invalid subject format".to_string()))?;
-        let click: Click = clickplanet_proto::clicks::Click::decode(message.payload.clone())?;
+    /// Persists and acknowledges a batch.
+    async fn handle_batch(&self, batch: Vec<Result<Delivery, ClickBusError>>) -> BatchOutcome {
+        let deliveries: Vec<Delivery> = batch
+            .into_iter()
+            .filter_map(|delivery| delivery.inspect_err(|e| error!("Error receiving message: {}", e)).ok())
+            .collect();
+        let sequences: Vec<u64> = deliveries.iter().map(|delivery| delivery.sequence).collect();
+        let Some(last_sequence) = sequences.iter().max().copied() else {
+            return BatchOutcome::Persisted(sequences);
+        };
 
-        self.click_repository.save_click(tile_id, &click).await?;
+        if let Err(e) = self.persist(&deliveries).await {
+            error!("Error persisting {} clicks: {}", deliveries.len(), e);
+            return BatchOutcome::Failed(self.dead_letter_exhausted(deliveries, &e.to_string()).await);
+        }
+
+        // Acknowledged one by one: acknowledging the last click with `AckPolicy::All` would
+        // also acknowledge the clicks of earlier batches that failed and wait for redelivery,
+        // and the ack policy of the existing durable consumers cannot be changed
+        let acks = futures::future::join_all(deliveries.into_iter().map(Delivery::ack)).await;
+        for ack in acks {
+            if let Err(e) = ack {
+                error!("Failed to acknowledge persisted click: {}", e);
+            }
+        }
+
+        debug!("Persisted clicks up to {}", last_sequence);
+        BatchOutcome::Persisted(sequences)
+    }
 
-        message
-            .ack()
-            .await
-            .map_err(|e| PollingConsumerError::Processing(e.to_string()))?;
+    /// Dead-letters the clicks delivered for the last time. The others are not acknowledged
+    /// and delivered again after the ack wait, their sequences are returned.
+    async fn dead_letter_exhausted(&self, deliveries: Vec<Delivery>, reason: &str) -> Vec<u64> {
+        let max_deliver = self.consumer_config.max_deliver;
+
+        let mut redelivered = Vec::new();
+        for delivery in deliveries {
+            let sequence = delivery.sequence;
+            if max_deliver > 0 && delivery.delivery_count >= max_deliver as u64 {
+                if let Err(e) = delivery.dead_letter(reason).await {
+                    error!("Failed to dead-letter click {}: {}", sequence, e);
+                    redelivered.push(sequence);
+                }
+            } else {
+                redelivered.push(sequence);
+            }
+        }
+        redelivered
+    }
 
+    async fn persist(&self, deliveries: &[Delivery]) -> Result<(), PollingConsumerError> {
+        let clicks = newest_per_tile(deliveries.iter().map(|delivery| &delivery.click));
+        self.click_repository.save_clicks(&clicks).await?;
         Ok(())
     }
 }
 
+enum BatchOutcome {
+    /// Sequences of the clicks written
+    Persisted(Vec<u64>),
+    /// Sequences of the clicks left for redelivery
+    Failed(Vec<u64>),
+}
+
+/// Last sequence below which every click was written, as far as this consumer knows.
+struct CheckpointTracker {
+    saved: Option<u64>,
+    highest_persisted: u64,
+    // Clicks of failed batches, not written yet
+    pending: BTreeSet<u64>,
+}
+
+impl CheckpointTracker {
+    fn new(saved: Option<u64>) -> Self {
+        Self { saved, highest_persisted: 0, pending: BTreeSet::new() }
+    }
+
+    fn failed(&mut self, sequences: &[u64]) {
+        self.pending.extend(sequences);
+    }
+
+    /// Records written clicks, returning the new checkpoint when it moves forward. It stays
+    /// below the clicks waiting for redelivery, and redelivered older clicks never move it back.
+    fn persisted(&mut self, sequences: &[u64]) -> Option<u64> {
+        for sequence in sequences {
+            self.pending.remove(sequence);
+            self.highest_persisted = self.highest_persisted.max(*sequence);
+        }
+
+        let mut checkpoint = self.highest_persisted;
+        if let Some(first_pending) = self.pending.first() {
+            checkpoint = checkpoint.min(first_pending.checked_sub(1)?);
+        }
+        if checkpoint == 0 || self.saved.is_some_and(|saved| checkpoint <= saved) {
+            return None;
+        }
+
+        self.saved = Some(checkpoint);
+        Some(checkpoint)
+    }
+}
+
+/// Only the newest click of a tile matters, older ones would be discarded by the storage.
+fn newest_per_tile<'a>(clicks: impl Iterator<Item = &'a Click>) -> Vec<Click> {
+    let mut newest: HashMap<i32, &Click> = HashMap::new();
+    for click in clicks {
+        newest
+            .entry(click.tile_id)
+            .and_modify(|current| {
+                if click.timestamp_ns > current.timestamp_ns {
+                    *current = click;
+                }
+            })
+            .or_insert(click);
+    }
+
+    newest.into_values().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn click(tile_id: i32, country_id: &str, timestamp_ns: u64) -> Click {
+        Click {
+            tile_id,
+            country_id: country_id.to_string(),
+            timestamp_ns,
+            click_id: String::new(),
+            session_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_newest_per_tile() {
+        let clicks = [click(1, "fr", 20), click(2, "de", 10), click(1, "es", 30), click(1, "it", 25)];
+
+        let mut newest: Vec<(i32, String)> = newest_per_tile(clicks.iter())
+            .into_iter()
+            .map(|click| (click.tile_id, click.country_id))
+            .collect();
+        newest.sort();
+
+        assert_eq!(newest, vec![(1, "es".to_string()), (2, "de".to_string())]);
+    }
+
+    #[test]
+    fn test_checkpoint_waits_for_failed_clicks() {
+        let mut tracker = CheckpointTracker::new(Some(10));
+
+        assert_eq!(tracker.persisted(&[11, 12, 13]), Some(13));
+        tracker.failed(&[14, 15]);
+        assert_eq!(tracker.persisted(&[16, 17]), None);
+        assert_eq!(tracker.persisted(&[18, 19]), None);
+
+        // Redelivered one at a time
+        assert_eq!(tracker.persisted(&[14]), Some(14));
+        assert_eq!(tracker.persisted(&[15]), Some(19));
+        assert_eq!(tracker.persisted(&[20]), Some(20));
+
+        // Older clicks delivered again do not move it back
+        assert_eq!(tracker.persisted(&[12]), None);
+        assert_eq!(tracker.saved, Some(20));
+    }
+}
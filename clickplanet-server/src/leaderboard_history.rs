@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use clickplanet_proto::clicks::{LeaderboardMover, LeaderboardMoversResponse, LeaderboardPoint, LeaderboardSeriesResponse};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::click_persistence::{LeaderboardError, LeaderboardRepository};
+
+#[derive(Error, Debug)]
+pub enum LeaderboardHistoryError {
+    #[error("Storage error: {0}")]
+    StorageError(String),
+    #[error("Invalid data format: {0}")]
+    InvalidDataError(String),
+    #[error(transparent)]
+    Leaderboard(#[from] LeaderboardError),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaderboardSnapshot {
+    pub timestamp_secs: u64,
+    pub scores: HashMap<String, u32>,
+}
+
+impl LeaderboardSnapshot {
+    pub fn ranks(&self) -> HashMap<String, u32> {
+        rank_scores(&self.scores)
+    }
+}
+
+/// 1-based rank of every country owning at least one tile, ties broken by country id.
+pub fn rank_scores(scores: &HashMap<String, u32>) -> HashMap<String, u32> {
+    let mut entries: Vec<(&String, &u32)> = scores.iter().filter(|(_, &score)| score > 0).collect();
+    entries.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, (country_id, _))| (country_id.clone(), index as u32 + 1))
+        .collect()
+}
+
+#[async_trait]
+pub trait LeaderboardHistoryStore: Send + Sync {
+    async fn append(&self, snapshot: &LeaderboardSnapshot) -> Result<(), LeaderboardHistoryError>;
+
+    /// Snapshots taken between `from_secs` and `to_secs` (inclusive), oldest first.
+    async fn range(&self, from_secs: u64, to_secs: u64) -> Result<Vec<LeaderboardSnapshot>, LeaderboardHistoryError>;
+
+    async fn prune_before(&self, timestamp_secs: u64) -> Result<(), LeaderboardHistoryError>;
+}
+
+/// Periodic leaderboard snapshots, used to chart score and rank over time and to find
+/// the countries moving the most.
+pub struct LeaderboardHistory {
+    store: Arc<dyn LeaderboardHistoryStore>,
+    leaderboard: Arc<dyn LeaderboardRepository>,
+    retention: Duration,
+}
+
+impl LeaderboardHistory {
+    pub fn new(
+        store: Arc<dyn LeaderboardHistoryStore>,
+        leaderboard: Arc<dyn LeaderboardRepository>,
+        retention: Duration,
+    ) -> Self {
+        Self {
+            store,
+            leaderboard,
+            retention,
+        }
+    }
+
+    pub async fn take_snapshot(&self) -> Result<(), LeaderboardHistoryError> {
+        self.take_snapshot_at(now_secs()).await
+    }
+
+    async fn take_snapshot_at(&self, now_secs: u64) -> Result<(), LeaderboardHistoryError> {
+        let snapshot = LeaderboardSnapshot {
+            timestamp_secs: now_secs,
+            scores: self.leaderboard.leaderboard().await?,
+        };
+
+        self.store.append(&snapshot).await?;
+        self.store.prune_before(now_secs.saturating_sub(self.retention.as_secs())).await
+    }
+
+    pub async fn snapshot_periodically(self: Arc<Self>, interval: Duration) {
+        info!("Taking leaderboard snapshots every {:?}", interval);
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.take_snapshot().await {
+                error!("Failed to take leaderboard snapshot: {}", e);
+            }
+        }
+    }
+
+    pub async fn country_series(
+        &self,
+        country_id: &str,
+        from_secs: u64,
+        to_secs: u64,
+    ) -> Result<LeaderboardSeriesResponse, LeaderboardHistoryError> {
+        let snapshots = self.store.range(from_secs, to_secs).await?;
+
+        let points = snapshots
+            .iter()
+            .map(|snapshot| LeaderboardPoint {
+                timestamp_secs: snapshot.timestamp_secs,
+                score: snapshot.scores.get(country_id).copied().unwrap_or(0),
+                rank: snapshot.ranks().get(country_id).copied().unwrap_or(0),
+            })
+            .collect();
+
+        Ok(LeaderboardSeriesResponse {
+            country_id: country_id.to_string(),
+            points,
+        })
+    }
+
+    /// Biggest gainers and losers between the oldest snapshot of the period and the live leaderboard.
+    pub async fn movers(&self, period: Duration, top: usize) -> Result<LeaderboardMoversResponse, LeaderboardHistoryError> {
+        self.movers_at(period, top, now_secs()).await
+    }
+
+    async fn movers_at(&self, period: Duration, top: usize, now_secs: u64) -> Result<LeaderboardMoversResponse, LeaderboardHistoryError> {
+        let since_secs = now_secs.saturating_sub(period.as_secs());
+
+        let current = LeaderboardSnapshot {
+            timestamp_secs: now_secs,
+            scores: self.leaderboard.leaderboard().await?,
+        };
+
+        let Some(baseline) = self.store.range(since_secs, now_secs).await?.into_iter().next() else {
+            return Ok(LeaderboardMoversResponse {
+                since_secs: now_secs,
+                ..Default::default()
+            });
+        };
+
+        Ok(compare_snapshots(&baseline, &current, top))
+    }
+}
+
+fn compare_snapshots(baseline: &LeaderboardSnapshot, current: &LeaderboardSnapshot, top: usize) -> LeaderboardMoversResponse {
+    let baseline_ranks = baseline.ranks();
+    let current_ranks = current.ranks();
+    // Countries that were not ranked count as just below the last one
+    let unranked = baseline_ranks.len() as u32 + 1;
+
+    let mut movers: Vec<LeaderboardMover> = current.scores
+        .keys()
+        .chain(baseline.scores.keys())
+        .collect::<std::collections::HashSet<_>>()
+        .into_iter()
+        .filter_map(|country_id| {
+            let score = current.scores.get(country_id).copied().unwrap_or(0);
+            let previous_score = baseline.scores.get(country_id).copied().unwrap_or(0);
+            if score == previous_score {
+                return None;
+            }
+
+            let rank = current_ranks.get(country_id).copied().unwrap_or(0);
+            let previous_rank = baseline_ranks.get(country_id).copied().unwrap_or(unranked);
+
+            Some(LeaderboardMover {
+                country_id: country_id.clone(),
+                score,
+                score_change: score as i32 - previous_score as i32,
+                rank,
+                rank_change: if rank == 0 { 0 } else { previous_rank as i32 - rank as i32 },
+            })
+        })
+        .collect();
+
+    movers.sort_by(|a, b| {
+        b.score_change
+            .cmp(&a.score_change)
+            .then_with(|| a.country_id.cmp(&b.country_id))
+    });
+
+    let gainers = movers.iter().filter(|m| m.score_change > 0).take(top).cloned().collect();
+    let losers = movers.iter().rev().filter(|m| m.score_change < 0).take(top).cloned().collect();
+
+    LeaderboardMoversResponse {
+        since_secs: baseline.timestamp_secs,
+        gainers,
+        losers,
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[derive(Default)]
+    struct InMemoryHistory(Mutex<BTreeMap<u64, LeaderboardSnapshot>>);
+
+    #[async_trait]
+    impl LeaderboardHistoryStore for InMemoryHistory {
+        async fn append(&self, snapshot: &LeaderboardSnapshot) -> Result<(), LeaderboardHistoryError> {
+            self.0.lock().unwrap().insert(snapshot.timestamp_secs, snapshot.clone());
+            Ok(())
+        }
+
+        async fn range(&self, from_secs: u64, to_secs: u64) -> Result<Vec<LeaderboardSnapshot>, LeaderboardHistoryError> {
+            Ok(self.0.lock().unwrap().range(from_secs..=to_secs).map(|(_, s)| s.clone()).collect())
+        }
+
+        async fn prune_before(&self, timestamp_secs: u64) -> Result<(), LeaderboardHistoryError> {
+            self.0.lock().unwrap().retain(|&t, _| t >= timestamp_secs);
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FixedLeaderboard(Mutex<HashMap<String, u32>>);
+
+    impl FixedLeaderboard {
+        fn set(&self, scores: &[(&str, u32)]) {
+            *self.0.lock().unwrap() = scores.iter().map(|(c, s)| (c.to_string(), *s)).collect();
+        }
+    }
+
+    #[async_trait]
+    impl LeaderboardRepository for FixedLeaderboard {
+        async fn get_score(&self, country_id: &str) -> Result<u32, LeaderboardError> {
+            Ok(self.0.lock().unwrap().get(country_id).copied().unwrap_or(0))
+        }
+
+        async fn leaderboard(&self) -> Result<HashMap<String, u32>, LeaderboardError> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+    }
+
+    fn history() -> (LeaderboardHistory, Arc<FixedLeaderboard>, Arc<InMemoryHistory>) {
+        let leaderboard = Arc::new(FixedLeaderboard::default());
+        let store = Arc::new(InMemoryHistory::default());
+        let history = LeaderboardHistory::new(store.clone(), leaderboard.clone(), Duration::from_secs(24 * 60 * 60));
+        (history, leaderboard, store)
+    }
+
+    #[tokio::test]
+    async fn test_country_series() {
+        let (history, leaderboard, _) = history();
+
+        leaderboard.set(&[("fr", 10), ("de", 5)]);
+        history.take_snapshot_at(NOW).await.unwrap();
+        leaderboard.set(&[("fr", 4), ("de", 5)]);
+        history.take_snapshot_at(NOW + 60).await.unwrap();
+        leaderboard.set(&[("de", 9)]);
+        history.take_snapshot_at(NOW + 120).await.unwrap();
+
+        let series = history.country_series("fr", NOW, NOW + 120).await.unwrap();
+        assert_eq!(
+            series.points,
+            vec![
+                LeaderboardPoint { timestamp_secs: NOW, score: 10, rank: 1 },
+                LeaderboardPoint { timestamp_secs: NOW + 60, score: 4, rank: 2 },
+                LeaderboardPoint { timestamp_secs: NOW + 120, score: 0, rank: 0 },
+            ]
+        );
+
+        assert_eq!(history.country_series("fr", NOW + 1, NOW + 60).await.unwrap().points.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let (history, leaderboard, store) = history();
+        leaderboard.set(&[("fr", 1)]);
+
+        history.take_snapshot_at(NOW).await.unwrap();
+        history.take_snapshot_at(NOW + 60 * 60).await.unwrap();
+        history.take_snapshot_at(NOW + 25 * 60 * 60).await.unwrap();
+
+        let remaining: Vec<u64> = store.range(0, u64::MAX).await.unwrap().iter().map(|s| s.timestamp_secs).collect();
+        assert_eq!(remaining, vec![NOW + 60 * 60, NOW + 25 * 60 * 60]);
+    }
+
+    #[tokio::test]
+    async fn test_movers() {
+        let (history, leaderboard, _) = history();
+
+        leaderboard.set(&[("fr", 10), ("de", 8), ("es", 3), ("it", 2)]);
+        history.take_snapshot_at(NOW - 2 * 60 * 60).await.unwrap();
+        leaderboard.set(&[("fr", 10), ("de", 8), ("es", 3), ("it", 2)]);
+        history.take_snapshot_at(NOW - 50 * 60).await.unwrap();
+
+        leaderboard.set(&[("fr", 6), ("de", 8), ("es", 9), ("pt", 1)]);
+        let movers = history.movers_at(Duration::from_secs(60 * 60), 10, NOW).await.unwrap();
+
+        assert_eq!(movers.since_secs, NOW - 50 * 60);
+        assert_eq!(
+            movers.gainers,
+            vec![
+                LeaderboardMover { country_id: "es".to_string(), score: 9, score_change: 6, rank: 1, rank_change: 2 },
+                LeaderboardMover { country_id: "pt".to_string(), score: 1, score_change: 1, rank: 4, rank_change: 1 },
+            ]
+        );
+        assert_eq!(
+            movers.losers,
+            vec![
+                LeaderboardMover { country_id: "fr".to_string(), score: 6, score_change: -4, rank: 3, rank_change: -2 },
+                LeaderboardMover { country_id: "it".to_string(), score: 0, score_change: -2, rank: 0, rank_change: 0 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use clickplanet_proto::clicks::{LeaderboardEntry, LeaderboardResponse, OwnershipState, UpdateNotification};
+
+use crate::click_persistence::{ClickRepository, ClickRepositoryError, LeaderboardError, LeaderboardRepository};
+use crate::ownership_listener::{resync, OwnershipListener};
+
+const BUCKET_SECS: u64 = 60;
+const CHANGES_WINDOW_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LeaderboardQuery {
+    /// `limit` entries from the `offset`-th one, every remaining entry without limit
+    Page { offset: usize, limit: Option<usize> },
+    /// The country and up to `radius` countries ranked right above and below it
+    Around { country_id: String, radius: usize },
+}
+
+/// Tiles gained and lost per country during one minute.
+#[derive(Debug, Default)]
+struct ChangeBucket {
+    start_secs: u64,
+    gained: HashMap<String, u32>,
+    lost: HashMap<String, u32>,
+}
+
+#[derive(Default)]
+struct IndexState {
+    owners: HashMap<u32, String>,
+    scores: HashMap<String, u32>,
+    // Highest score first, ties broken by country id
+    order: BTreeSet<(Reverse<u32>, String)>,
+    changes: VecDeque<ChangeBucket>,
+}
+
+impl IndexState {
+    fn set_score(&mut self, country_id: &str, score: u32) {
+        if let Some(previous) = self.scores.get(country_id).copied() {
+            self.order.remove(&(Reverse(previous), country_id.to_string()));
+        }
+
+        if score == 0 {
+            self.scores.remove(country_id);
+        } else {
+            self.scores.insert(country_id.to_string(), score);
+            self.order.insert((Reverse(score), country_id.to_string()));
+        }
+    }
+
+    fn add_tile(&mut self, country_id: &str) {
+        let score = self.scores.get(country_id).copied().unwrap_or(0);
+        self.set_score(country_id, score + 1);
+    }
+
+    fn remove_tile(&mut self, country_id: &str) {
+        let score = self.scores.get(country_id).copied().unwrap_or(0);
+        self.set_score(country_id, score.saturating_sub(1));
+    }
+
+    fn current_bucket(&mut self, now_secs: u64) -> &mut ChangeBucket {
+        let start_secs = now_secs - now_secs % BUCKET_SECS;
+
+        if self.changes.back().is_none_or(|bucket| bucket.start_secs < start_secs) {
+            self.changes.push_back(ChangeBucket {
+                start_secs,
+                ..Default::default()
+            });
+
+            while self.changes
+                .front()
+                .is_some_and(|bucket| bucket.start_secs + CHANGES_WINDOW_SECS <= now_secs)
+            {
+                self.changes.pop_front();
+            }
+        }
+
+        self.changes.back_mut().unwrap()
+    }
+
+    fn changes_since(&self, country_id: &str, since_secs: u64) -> (u32, u32) {
+        self.changes
+            .iter()
+            .filter(|bucket| bucket.start_secs + BUCKET_SECS > since_secs)
+            .fold((0, 0), |(gained, lost), bucket| {
+                (
+                    gained + bucket.gained.get(country_id).copied().unwrap_or(0),
+                    lost + bucket.lost.get(country_id).copied().unwrap_or(0),
+                )
+            })
+    }
+}
+
+/// Country scores kept sorted as ownership updates come in, so leaderboard pages are
+/// served without sorting every country on each request.
+pub struct LeaderboardIndex {
+    total_tiles: u32,
+    state: Mutex<IndexState>,
+}
+
+impl LeaderboardIndex {
+    pub fn new(total_tiles: u32) -> Self {
+        Self {
+            total_tiles,
+            state: Mutex::new(IndexState::default()),
+        }
+    }
+
+    pub async fn populate_with(
+        total_tiles: u32,
+        repository: Arc<dyn ClickRepository>,
+    ) -> Result<Self, ClickRepositoryError> {
+        let index = Self::new(total_tiles);
+        resync(&index, repository.as_ref()).await?;
+        Ok(index)
+    }
+
+    /// Entries matching the query, `None` when the country of an `Around` query is not ranked.
+    pub fn query(&self, query: &LeaderboardQuery) -> Option<LeaderboardResponse> {
+        self.query_at(query, now_secs())
+    }
+
+    fn query_at(&self, query: &LeaderboardQuery, now_secs: u64) -> Option<LeaderboardResponse> {
+        let state = self.state.lock().unwrap();
+
+        let (offset, limit) = match query {
+            LeaderboardQuery::Page { offset, limit } => (*offset, limit.unwrap_or(usize::MAX)),
+            LeaderboardQuery::Around { country_id, radius } => {
+                let score = *state.scores.get(country_id)?;
+                let position = state.order
+                    .range(..(Reverse(score), country_id.clone()))
+                    .count();
+                let offset = position.saturating_sub(*radius);
+                (offset, position - offset + radius + 1)
+            }
+        };
+
+        let since_secs = now_secs.saturating_sub(CHANGES_WINDOW_SECS);
+        let entries = state.order
+            .iter()
+            .enumerate()
+            .skip(offset)
+            .take(limit)
+            .map(|(position, (Reverse(score), country_id))| {
+                let (tiles_gained_last_hour, tiles_lost_last_hour) = state.changes_since(country_id, since_secs);
+
+                LeaderboardEntry {
+                    country_id: country_id.clone(),
+                    score: *score,
+                    rank: position as u32 + 1,
+                    share: if self.total_tiles == 0 {
+                        0.0
+                    } else {
+                        *score as f64 / self.total_tiles as f64
+                    },
+                    tiles_gained_last_hour,
+                    tiles_lost_last_hour,
+                    home_control_ratio: 0.0,
+                }
+            })
+            .collect();
+
+        Some(LeaderboardResponse {
+            entries,
+            total_countries: state.scores.len() as u32,
+            total_tiles: self.total_tiles,
+            owned_tiles: state.owners.len() as u32,
+        })
+    }
+
+    fn apply_update_at(&self, update: &UpdateNotification, now_secs: u64) {
+        let tile_id = update.tile_id as u32;
+        let mut state = self.state.lock().unwrap();
+
+        // Cleared tiles have an empty country and no owner
+        let previous = if update.country_id.is_empty() {
+            state.owners.remove(&tile_id)
+        } else {
+            state.owners.insert(tile_id, update.country_id.clone())
+        };
+        if previous.as_deref().unwrap_or_default() == update.country_id {
+            return;
+        }
+
+        if !update.country_id.is_empty() {
+            state.add_tile(&update.country_id);
+            *state.current_bucket(now_secs).gained.entry(update.country_id.clone()).or_insert(0) += 1;
+        }
+        if let Some(previous_country) = previous {
+            state.remove_tile(&previous_country);
+            *state.current_bucket(now_secs).lost.entry(previous_country).or_insert(0) += 1;
+        }
+    }
+}
+
+impl OwnershipListener for LeaderboardIndex {
+    fn name(&self) -> &'static str {
+        "leaderboard index"
+    }
+
+    fn load(&self, ownership_state: OwnershipState) {
+        let mut state = self.state.lock().unwrap();
+        let changes = std::mem::take(&mut state.changes);
+
+        let mut scores: HashMap<String, u32> = HashMap::new();
+        let mut owners = HashMap::new();
+        for ownership in ownership_state.ownerships {
+            *scores.entry(ownership.country_id.clone()).or_insert(0) += 1;
+            owners.insert(ownership.tile_id, ownership.country_id);
+        }
+
+        *state = IndexState {
+            owners,
+            order: scores.iter().map(|(country_id, &score)| (Reverse(score), country_id.clone())).collect(),
+            scores,
+            // Gains and losses are not part of the ownership state, keep the ones already counted
+            changes,
+        };
+    }
+
+    fn apply_update(&self, update: &UpdateNotification) {
+        self.apply_update_at(update, now_secs());
+    }
+}
+
+#[async_trait]
+impl LeaderboardRepository for LeaderboardIndex {
+    async fn get_score(&self, country_id: &str) -> Result<u32, LeaderboardError> {
+        Ok(self.state.lock().unwrap().scores.get(country_id).copied().unwrap_or(0))
+    }
+
+    async fn leaderboard(&self) -> Result<HashMap<String, u32>, LeaderboardError> {
+        Ok(self.state.lock().unwrap().scores.clone())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickplanet_proto::clicks::{Click, Ownership};
+    use uuid::Uuid;
+    use crate::in_memory_click_persistence::PapayaClickRepository;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn update(tile_id: i32, country_id: &str, previous_country_id: &str) -> UpdateNotification {
+        UpdateNotification {
+            tile_id,
+            country_id: country_id.to_string(),
+            previous_country_id: previous_country_id.to_string(),
+        }
+    }
+
+    // fr: 4 tiles, de: 3, es: 2, it: 1
+    fn index() -> LeaderboardIndex {
+        let index = LeaderboardIndex::new(20);
+        let owners = [("fr", 4), ("de", 3), ("es", 2), ("it", 1)];
+
+        index.load(OwnershipState {
+            ownerships: owners
+                .iter()
+                .flat_map(|(country_id, tiles)| std::iter::repeat_n(country_id.to_string(), *tiles))
+                .enumerate()
+                .map(|(tile_id, country_id)| Ownership { tile_id: tile_id as u32, country_id, timestamp_ns: 0 })
+                .collect(),
+        });
+
+        index
+    }
+
+    fn countries(response: &LeaderboardResponse) -> Vec<(&str, u32)> {
+        response.entries.iter().map(|e| (e.country_id.as_str(), e.rank)).collect()
+    }
+
+    #[test]
+    fn test_pages() {
+        let index = index();
+
+        let all = index.query_at(&LeaderboardQuery::Page { offset: 0, limit: None }, NOW).unwrap();
+        assert_eq!(countries(&all), vec![("fr", 1), ("de", 2), ("es", 3), ("it", 4)]);
+        assert_eq!(all.total_countries, 4);
+        assert_eq!(all.owned_tiles, 10);
+        assert_eq!(all.entries[0].share, 0.2);
+
+        let page = index.query_at(&LeaderboardQuery::Page { offset: 1, limit: Some(2) }, NOW).unwrap();
+        assert_eq!(countries(&page), vec![("de", 2), ("es", 3)]);
+
+        let past_the_end = index.query_at(&LeaderboardQuery::Page { offset: 10, limit: Some(2) }, NOW).unwrap();
+        assert!(past_the_end.entries.is_empty());
+    }
+
+    #[test]
+    fn test_around() {
+        let index = index();
+        let around = |country_id: &str, radius| {
+            index.query_at(&LeaderboardQuery::Around { country_id: country_id.to_string(), radius }, NOW)
+        };
+
+        assert_eq!(countries(&around("es", 1).unwrap()), vec![("de", 2), ("es", 3), ("it", 4)]);
+        assert_eq!(countries(&around("fr", 1).unwrap()), vec![("fr", 1), ("de", 2)]);
+        assert_eq!(countries(&around("it", 0).unwrap()), vec![("it", 4)]);
+        assert!(around("pt", 1).is_none());
+    }
+
+    #[test]
+    fn test_updates_reorder_and_count_changes() {
+        let index = index();
+
+        // it takes two tiles from fr and a new one, before and during the last hour
+        index.apply_update_at(&update(0, "it", "fr"), NOW - 2 * 60 * 60);
+        index.apply_update_at(&update(1, "it", "fr"), NOW - 10 * 60);
+        index.apply_update_at(&update(15, "it", ""), NOW);
+        // Duplicate notification, ignored
+        index.apply_update_at(&update(15, "it", ""), NOW);
+
+        let all = index.query_at(&LeaderboardQuery::Page { offset: 0, limit: None }, NOW).unwrap();
+        assert_eq!(countries(&all), vec![("it", 1), ("de", 2), ("es", 3), ("fr", 4)]);
+        assert_eq!(all.entries[0].score, 4);
+        assert_eq!(all.entries[0].tiles_gained_last_hour, 2);
+        assert_eq!(all.entries[3].tiles_lost_last_hour, 1);
+        assert_eq!(all.owned_tiles, 11);
+    }
+
+    #[tokio::test]
+    async fn test_leaderboard_accuracy() {
+        let click_repo = Arc::new(PapayaClickRepository::new());
+        let mut leaderboard_handles = Vec::new();
+
+        for i in 0..10 {
+            let repo = click_repo.clone();  // Clone the Arc before moving into spawn
+            let handle = tokio::spawn(async move {
+                let click = Click {
+                    tile_id: i,
+                    country_id: format!("COUNTRY{}", i % 2),
+                    timestamp_ns: (10 + i * 10) as u64,
+                    click_id: Uuid::new_v4().to_string(),
+                    session_id: String::new(),
+                };
+
+                println!("Processing click: {:?}", click);
+                repo.save_click(click.tile_id as u32, &click).await
+            });
+
+            leaderboard_handles.push(handle);
+        }
+
+        // Wait for all clicks to be processed
+        for handle in leaderboard_handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        // Create leaderboard computation after all clicks are processed
+        let leader_board_computation = LeaderboardIndex::populate_with(10, click_repo).await.unwrap();
+
+        // Check scores
+        let score0 = leader_board_computation.get_score("COUNTRY0").await.unwrap();
+        let score1 = leader_board_computation.get_score("COUNTRY1").await.unwrap();
+        let score2 = leader_board_computation.get_score("COUNTRY2").await.unwrap();
+
+        // Get and verify leaderboard
+        let leaderboard = leader_board_computation.leaderboard().await.unwrap();
+
+        let expected_map = {
+            let mut map = HashMap::new();
+            map.insert("COUNTRY0".to_string(), 5);
+            map.insert("COUNTRY1".to_string(), 5);
+            map
+        };
+
+        assert_eq!(leaderboard, expected_map);
+        assert_eq!(score0 + score1 + score2, 10);
+    }
+}
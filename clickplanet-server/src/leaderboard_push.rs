@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clickplanet_proto::clicks::server_message::Payload;
+use clickplanet_proto::clicks::{LeaderboardDelta, LeaderboardDeltaEntry, LeaderboardEntry, LeaderboardResponse, ServerMessage};
+use tokio::sync::broadcast;
+use tracing::{error, info};
+
+use crate::click_persistence::{LeaderboardError, LeaderboardRepository};
+use crate::leaderboard_history::rank_scores;
+
+pub const ENVELOPE_VERSION: u32 = 1;
+
+pub fn envelope(payload: Payload) -> ServerMessage {
+    ServerMessage {
+        version: ENVELOPE_VERSION,
+        payload: Some(payload),
+    }
+}
+
+/// Full leaderboard sorted by score, highest first, with ranks.
+pub fn leaderboard_response(scores: HashMap<String, u32>) -> LeaderboardResponse {
+    let mut entries: Vec<LeaderboardEntry> = scores
+        .into_iter()
+        .map(|(country_id, score)| LeaderboardEntry {
+            country_id,
+            score,
+            ..Default::default()
+        })
+        .collect();
+
+    entries.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.country_id.cmp(&b.country_id)));
+    for (position, entry) in entries.iter_mut().enumerate() {
+        entry.rank = position as u32 + 1;
+    }
+
+    LeaderboardResponse {
+        total_countries: entries.len() as u32,
+        owned_tiles: entries.iter().map(|entry| entry.score).sum(),
+        entries,
+        ..Default::default()
+    }
+}
+
+/// Periodically diffs the leaderboard against the last published one and broadcasts
+/// the changes, so WebSocket clients do not have to poll the leaderboard endpoint.
+pub struct LeaderboardPublisher {
+    leaderboard: Arc<dyn LeaderboardRepository>,
+    sender: broadcast::Sender<LeaderboardDelta>,
+    // Score and rank of every country as of the last delta
+    published: Mutex<HashMap<String, (u32, u32)>>,
+}
+
+impl LeaderboardPublisher {
+    pub fn new(leaderboard: Arc<dyn LeaderboardRepository>) -> Self {
+        let (sender, _) = broadcast::channel(64);
+
+        Self {
+            leaderboard,
+            sender,
+            published: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LeaderboardDelta> {
+        self.sender.subscribe()
+    }
+
+    pub async fn leaderboard(&self) -> Result<LeaderboardResponse, LeaderboardError> {
+        Ok(leaderboard_response(self.leaderboard.leaderboard().await?))
+    }
+
+    /// Publishes the changes since the last delta, if any and if someone is listening.
+    pub async fn publish_changes(&self) -> Result<(), LeaderboardError> {
+        // Clients get the full leaderboard when they connect, deltas only need to be
+        // relative to some earlier state
+        if self.sender.receiver_count() == 0 {
+            return Ok(());
+        }
+
+        let scores = self.leaderboard.leaderboard().await?;
+
+        let entries = {
+            let mut published = self.published.lock().unwrap();
+            diff_leaderboard(&mut published, &scores)
+        };
+
+        if !entries.is_empty() {
+            let _ = self.sender.send(LeaderboardDelta {
+                timestamp_ns: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64,
+                entries,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub async fn run(self: Arc<Self>, interval: Duration) {
+        info!("Pushing leaderboard deltas every {:?}", interval);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.publish_changes().await {
+                error!("Failed to publish leaderboard delta: {}", e);
+            }
+        }
+    }
+}
+
+/// Entries whose score or rank differ from `published`, which is updated to `scores`.
+fn diff_leaderboard(
+    published: &mut HashMap<String, (u32, u32)>,
+    scores: &HashMap<String, u32>,
+) -> Vec<LeaderboardDeltaEntry> {
+    let ranks = rank_scores(scores);
+
+    let current: HashMap<String, (u32, u32)> = ranks
+        .into_iter()
+        .map(|(country_id, rank)| {
+            let score = scores[&country_id];
+            (country_id, (score, rank))
+        })
+        .collect();
+
+    let mut entries: Vec<LeaderboardDeltaEntry> = current
+        .iter()
+        .filter(|(country_id, standing)| published.get(*country_id) != Some(standing))
+        .map(|(country_id, &(score, rank))| LeaderboardDeltaEntry {
+            country_id: country_id.clone(),
+            score,
+            rank,
+        })
+        .chain(
+            published
+                .keys()
+                .filter(|country_id| !current.contains_key(*country_id))
+                .map(|country_id| LeaderboardDeltaEntry {
+                    country_id: country_id.clone(),
+                    score: 0,
+                    rank: 0,
+                }),
+        )
+        .collect();
+
+    entries.sort_by(|a, b| {
+        (a.rank == 0)
+            .cmp(&(b.rank == 0))
+            .then_with(|| a.rank.cmp(&b.rank))
+            .then_with(|| a.country_id.cmp(&b.country_id))
+    });
+
+    *published = current;
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scores(entries: &[(&str, u32)]) -> HashMap<String, u32> {
+        entries.iter().map(|(c, s)| (c.to_string(), *s)).collect()
+    }
+
+    fn entry(country_id: &str, score: u32, rank: u32) -> LeaderboardDeltaEntry {
+        LeaderboardDeltaEntry {
+            country_id: country_id.to_string(),
+            score,
+            rank,
+        }
+    }
+
+    #[test]
+    fn test_diff_leaderboard() {
+        let mut published = HashMap::new();
+
+        let first = diff_leaderboard(&mut published, &scores(&[("fr", 10), ("de", 5), ("es", 1)]));
+        assert_eq!(first, vec![entry("fr", 10, 1), entry("de", 5, 2), entry("es", 1, 3)]);
+
+        assert!(diff_leaderboard(&mut published, &scores(&[("fr", 10), ("de", 5), ("es", 1)])).is_empty());
+
+        // de overtakes fr, es is wiped out: fr only changed rank
+        let second = diff_leaderboard(&mut published, &scores(&[("fr", 10), ("de", 12), ("es", 0)]));
+        assert_eq!(second, vec![entry("de", 12, 1), entry("fr", 10, 2), entry("es", 0, 0)]);
+
+        // es is not reported twice
+        assert!(diff_leaderboard(&mut published, &scores(&[("fr", 10), ("de", 12)])).is_empty());
+    }
+
+    #[test]
+    fn test_leaderboard_response_sorted() {
+        let response = leaderboard_response(scores(&[("de", 5), ("fr", 10), ("es", 5)]));
+        let order: Vec<&str> = response.entries.iter().map(|e| e.country_id.as_str()).collect();
+
+        assert_eq!(order, vec!["fr", "de", "es"]);
+        assert_eq!(response.entries[2].rank, 3);
+        assert_eq!(response.owned_tiles, 20);
+    }
+}
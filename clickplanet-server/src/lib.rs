@@ -0,0 +1,37 @@
+//! Modules shared by the server, persister and migrator binaries.
+
+pub mod activity_heatmap;
+pub mod admin;
+pub mod click_bus;
+pub mod click_persistence;
+pub mod click_publisher;
+pub mod click_service;
+pub mod config;
+pub mod dead_letter_queue;
+pub mod embedded_click_persistence;
+pub mod health;
+pub mod home_territory;
+pub mod in_memory_click_persistence;
+pub mod in_process_click_bus;
+pub mod invasion_flows;
+pub mod jetstream_click_bus;
+pub mod jetstream_click_streamer;
+pub mod leaderboard_history;
+pub mod leaderboard_index;
+pub mod leaderboard_push;
+pub mod metrics;
+pub mod nats_commons;
+pub mod ownership_listener;
+pub mod ownership_service;
+pub mod persister;
+pub mod player_stats;
+pub mod postgres_click_persistence;
+pub mod rate_limit;
+pub mod redis_click_persistence;
+pub mod redis_leaderboard_history;
+pub mod server;
+pub mod session;
+pub mod shared_args;
+pub mod shutdown;
+pub mod state_snapshot;
+pub mod territory_analyzer;
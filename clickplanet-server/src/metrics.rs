@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Instant;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+
+/// Latencies in seconds, from half a millisecond to ten seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+/// Sizes in bytes, from 64 KiB to 256 MiB.
+const SIZE_BUCKETS: &[f64] = &[65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0, 268435456.0];
+
+/// Label values beyond this number share the `other` series. Country ids come from clients.
+const MAX_SERIES: usize = 512;
+const OVERFLOW_LABEL: &str = "other";
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    /// By `result`: accepted, invalid, limited, unauthenticated, forbidden, unavailable or failed
+    pub clicks: Labelled<Counter>,
+    pub bus_publish_seconds: Arc<Histogram>,
+    pub bus_publish_errors: Counter,
+    /// Tiles taken, by the `country` taking them
+    pub ownership_changes: Labelled<Counter>,
+    /// Broadcast receivers that fell behind and missed messages, by `receiver`
+    pub broadcast_lag_events: Labelled<Counter>,
+    pub websocket_connections: Gauge,
+    pub snapshot_bytes: Arc<Histogram>,
+    /// By `operation`, the repository method
+    pub redis_operation_seconds: Labelled<Histogram>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            clicks: Labelled::new("result", Counter::default),
+            bus_publish_seconds: Arc::new(Histogram::new(LATENCY_BUCKETS)),
+            bus_publish_errors: Counter::default(),
+            ownership_changes: Labelled::new("country", Counter::default),
+            broadcast_lag_events: Labelled::new("receiver", Counter::default),
+            websocket_connections: Gauge::default(),
+            snapshot_bytes: Arc::new(Histogram::new(SIZE_BUCKETS)),
+            redis_operation_seconds: Labelled::new("operation", || Histogram::new(LATENCY_BUCKETS)),
+        }
+    }
+
+    /// Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        self.clicks.encode(&mut out, "clickplanet_clicks_total", "Click requests by result");
+        self.bus_publish_seconds.encode_family(&mut out, "clickplanet_bus_publish_seconds", "Time to publish a click on the click bus");
+        self.bus_publish_errors.encode_family(&mut out, "clickplanet_bus_publish_errors_total", "Clicks the click bus failed to publish");
+        self.ownership_changes.encode(&mut out, "clickplanet_ownership_changes_total", "Tiles taken by each country");
+        self.broadcast_lag_events.encode(&mut out, "clickplanet_broadcast_lag_events_total", "Times a broadcast receiver missed messages");
+        self.websocket_connections.encode_family(&mut out, "clickplanet_websocket_connections", "Connected WebSocket clients");
+        self.snapshot_bytes.encode_family(&mut out, "clickplanet_snapshot_bytes", "Size of the state snapshots written");
+        self.redis_operation_seconds.encode(&mut out, "clickplanet_redis_operation_seconds", "Time spent in Redis by repository operation");
+        out
+    }
+}
+
+pub async fn handle_metrics() -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], METRICS.encode())
+}
+
+pub trait Metric {
+    const KIND: &'static str;
+
+    /// Writes the samples of the metric, `labels` being empty or `key="value"` pairs.
+    fn write_samples(&self, out: &mut String, name: &str, labels: &str);
+
+    fn encode_family(&self, out: &mut String, name: &str, help: &str) {
+        write_header(out, name, help, Self::KIND);
+        self.write_samples(out, name, "");
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn with_braces(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, amount: u64) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Counter {
+    const KIND: &'static str = "counter";
+
+    fn write_samples(&self, out: &mut String, name: &str, labels: &str) {
+        let _ = writeln!(out, "{}{} {}", name, with_braces(labels), self.get());
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge {
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Gauge {
+    const KIND: &'static str = "gauge";
+
+    fn write_samples(&self, out: &mut String, name: &str, labels: &str) {
+        let _ = writeln!(out, "{}{} {}", name, with_braces(labels), self.get());
+    }
+}
+
+pub struct Histogram {
+    bounds: &'static [f64],
+    // Observations per bucket, not cumulative, the last one above every bound
+    buckets: Vec<AtomicU64>,
+    sum: Mutex<f64>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: Mutex::new(0.0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        *self.sum.lock().unwrap() += value;
+    }
+
+    /// Observes the seconds elapsed until the returned timer is dropped.
+    pub fn start_timer(self: &Arc<Self>) -> HistogramTimer {
+        HistogramTimer { histogram: self.clone(), started_at: Instant::now() }
+    }
+}
+
+impl Metric for Histogram {
+    const KIND: &'static str = "histogram";
+
+    fn write_samples(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut count = 0;
+        for (bucket, observations) in self.buckets.iter().enumerate() {
+            count += observations.load(Ordering::Relaxed);
+            let bound = self.bounds.get(bucket).map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, count);
+        }
+        let _ = writeln!(out, "{}_sum{} {}", name, with_braces(labels), *self.sum.lock().unwrap());
+        let _ = writeln!(out, "{}_count{} {}", name, with_braces(labels), count);
+    }
+}
+
+pub struct HistogramTimer {
+    histogram: Arc<Histogram>,
+    started_at: Instant,
+}
+
+impl Drop for HistogramTimer {
+    fn drop(&mut self) {
+        self.histogram.observe(self.started_at.elapsed().as_secs_f64());
+    }
+}
+
+/// One series of a metric per value of a label.
+pub struct Labelled<M> {
+    label: &'static str,
+    series: Mutex<BTreeMap<String, Arc<M>>>,
+    new_series: fn() -> M,
+}
+
+impl<M: Metric> Labelled<M> {
+    fn new(label: &'static str, new_series: fn() -> M) -> Self {
+        Self {
+            label,
+            series: Mutex::new(BTreeMap::new()),
+            new_series,
+        }
+    }
+
+    pub fn with(&self, value: &str) -> Arc<M> {
+        let mut series = self.series.lock().unwrap();
+        if let Some(metric) = series.get(value) {
+            return metric.clone();
+        }
+
+        let value = if series.len() < MAX_SERIES { value } else { OVERFLOW_LABEL };
+        series.entry(value.to_string()).or_insert_with(|| Arc::new((self.new_series)())).clone()
+    }
+
+    fn encode(&self, out: &mut String, name: &str, help: &str) {
+        write_header(out, name, help, M::KIND);
+        for (value, metric) in self.series.lock().unwrap().iter() {
+            metric.write_samples(out, name, &format!("{}=\"{}\"", self.label, escape(value)));
+        }
+    }
+}
+
+fn escape(label_value: &str) -> String {
+    label_value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new();
+        metrics.clicks.with("accepted").inc_by(2);
+        metrics.ownership_changes.with("f\"r").inc();
+        metrics.websocket_connections.inc();
+        metrics.snapshot_bytes.observe(100000.0);
+        metrics.snapshot_bytes.observe(1e9);
+
+        let encoded = metrics.encode();
+
+        assert!(encoded.contains("# TYPE clickplanet_clicks_total counter\nclickplanet_clicks_total{result=\"accepted\"} 2\n"));
+        assert!(encoded.contains("clickplanet_ownership_changes_total{country=\"f\\\"r\"} 1\n"));
+        assert!(encoded.contains("clickplanet_websocket_connections 1\n"));
+        assert!(encoded.contains("clickplanet_snapshot_bytes_bucket{le=\"65536\"} 0\n"));
+        assert!(encoded.contains("clickplanet_snapshot_bytes_bucket{le=\"262144\"} 1\n"));
+        assert!(encoded.contains("clickplanet_snapshot_bytes_bucket{le=\"+Inf\"} 2\n"));
+        assert!(encoded.contains("clickplanet_snapshot_bytes_count 2\n"));
+    }
+
+    #[test]
+    fn test_label_values_are_capped() {
+        let counters = Labelled::new("country", Counter::default);
+        for country in 0..MAX_SERIES + 10 {
+            counters.with(&country.to_string()).inc();
+        }
+
+        assert_eq!(counters.series.lock().unwrap().len(), MAX_SERIES + 1);
+        assert_eq!(counters.with(OVERFLOW_LABEL).get(), 10);
+        assert_eq!(counters.with("0").get(), 1);
+    }
+}
@@ -1,8 +1,7 @@
-use std::sync::Arc;
 use std::time::Duration;
-use async_nats::{jetstream, ConnectError};
-use async_nats::jetstream::Context;
+use async_nats::ConnectError;
 use thiserror::Error;
+use crate::click_bus::ClickBusError;
 use crate::click_persistence::{ClickRepositoryError, LeaderboardError};
 
 pub const CLICK_SUBJECT_PREFIX: &'static str = "clicks.tile.";
@@ -14,6 +13,10 @@ pub struct ConsumerConfig {
     pub ack_wait: Duration,
     pub max_deliver: i64,
     pub concurrent_processors: usize,
+    /// Replays the stream from this sequence instead of resuming the durable consumer
+    pub start_sequence: Option<u64>,
+    /// Maximum number of clicks pulled from the server at once
+    pub batch_size: usize,
 }
 
 impl Default for ConsumerConfig {
@@ -23,6 +26,8 @@ impl Default for ConsumerConfig {
             ack_wait: Duration::from_secs(30),
             max_deliver: 3,
             concurrent_processors: 4,
+            start_sequence: None,
+            batch_size: 200,
         }
     }
 }
@@ -31,18 +36,12 @@ impl Default for ConsumerConfig {
 pub enum PollingConsumerError {
     #[error("Failed to connect to NATS: {0}")]
     NatsConnection(#[from] ConnectError),
-    #[error("Failed to process message: {0}")]
-    Processing(String),
     #[error("Failed to decode protobuf: {0}")]
     ProtobufDecode(#[from] prost::DecodeError),
     #[error("Failed to store data: {0}")]
     ClickPersistence(#[from] ClickRepositoryError),
     #[error("Failed to update leaderboard: {0}")]
     LeaderboardPersistence(#[from] LeaderboardError),
-}
-
-pub async fn get_stream(jetstream: Arc<Context>) -> Result<jetstream::stream::Stream, PollingConsumerError> {
-    jetstream.get_stream(CLICK_STREAM_NAME.to_string())
-        .await
-        .map_err(|e| PollingConsumerError::Processing(e.to_string()))
+    #[error("Click bus error: {0}")]
+    ClickBus(#[from] ClickBusError),
 }
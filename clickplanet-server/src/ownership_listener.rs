@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use clickplanet_proto::clicks::{OwnershipState, UpdateNotification};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+use crate::click_persistence::{ClickRepository, ClickRepositoryError};
+use crate::metrics::METRICS;
+
+/// In-memory view derived from tile ownerships, kept up to date from ownership updates.
+pub trait OwnershipListener: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Replaces the whole view with a full ownership state.
+    fn load(&self, ownership_state: OwnershipState);
+
+    fn apply_update(&self, update: &UpdateNotification);
+}
+
+pub async fn resync(listener: &dyn OwnershipListener, repository: &dyn ClickRepository) -> Result<(), ClickRepositoryError> {
+    let ownership_state = repository.get_ownerships().await?;
+    listener.load(ownership_state);
+    Ok(())
+}
+
+/// Applies ownership updates to the listener, reloading from the repository if it lags behind.
+pub async fn follow_updates(
+    listener: Arc<dyn OwnershipListener>,
+    mut updates: broadcast::Receiver<UpdateNotification>,
+    repository: Arc<dyn ClickRepository>,
+) {
+    info!("Starting {}", listener.name());
+
+    loop {
+        match updates.recv().await {
+            Ok(update) => listener.apply_update(&update),
+            Err(RecvError::Lagged(skipped)) => {
+                METRICS.broadcast_lag_events.with(listener.name()).inc();
+                warn!("{} lagged behind by {} updates, resyncing", listener.name(), skipped);
+                if let Err(e) = resync(listener.as_ref(), repository.as_ref()).await {
+                    error!("Failed to resync {}: {:?}", listener.name(), e);
+                }
+            }
+            Err(RecvError::Closed) => {
+                info!("Update channel closed, stopping {}", listener.name());
+                break;
+            }
+        }
+    }
+}
@@ -1,23 +1,24 @@
-use async_nats::jetstream;
-use async_nats::jetstream::consumer::pull::Stream;
 use clickplanet_proto::clicks::{Click, Ownership, UpdateNotification};
 use futures_util::stream::Map;
 use futures_util::{future, StreamExt, TryStreamExt};
-use prost::Message;
 use std::error::Error;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::Receiver;
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
 use crate::click_persistence::{ClickRepository, LeaderboardMaintainer, LeaderboardRepository};
 use crate::nats_commons;
-use crate::nats_commons::{get_stream, ConsumerConfig, PollingConsumerError};
+use crate::click_bus::{ClickBus, ClickSubscription, Delivery};
+use crate::nats_commons::{ConsumerConfig, PollingConsumerError};
 use crate::redis_click_persistence::{RedisClickRepository, RedisPersistenceError};
+use crate::state_snapshot::SequenceTracker;
+use crate::metrics::METRICS;
 
-const CONSUMER_NAME: &'static str = "tile-ownership-update";
+pub const CONSUMER_NAME: &'static str = "tile-ownership-update";
 
 #[derive(Error, Debug)]
 pub enum ConsumerError {
@@ -35,14 +36,24 @@ pub enum ConsumerError {
     PollingConsumerError(#[from] PollingConsumerError),
 }
 
+/// A click delivered by the bus, once applied. Unlike the clicks this server broadcasts, also
+/// applied when the bus delivers them, each click of the bus is delivered once.
+#[derive(Clone, Debug)]
+pub struct DeliveredClick {
+    pub sequence: u64,
+    pub click: Click,
+}
+
 #[derive(Clone)]
 pub struct OwnershipUpdateService {
     click_repository: Arc<dyn ClickRepository>,
     leaderboard_maintainer: Arc<dyn LeaderboardMaintainer>,
     click_sender: Arc<broadcast::Sender<Click>>,
     update_tx: Arc<broadcast::Sender<UpdateNotification>>,
-    jetstream: Arc<jetstream::Context>,
+    click_bus: Arc<dyn ClickBus>,
     consumer_config: ConsumerConfig,
+    sequence_tracker: Arc<SequenceTracker>,
+    delivered_clicks: Option<Arc<broadcast::Sender<DeliveredClick>>>,
 }
 
 impl OwnershipUpdateService {
@@ -51,38 +62,54 @@ impl OwnershipUpdateService {
         leaderboard_maintainer: Arc<dyn LeaderboardMaintainer>,
         click_sender: Arc<broadcast::Sender<Click>>,
         update_sender: Arc<broadcast::Sender<UpdateNotification>>,
-        jetstream: Arc<jetstream::Context>,
+        click_bus: Arc<dyn ClickBus>,
         consumer_config: Option<ConsumerConfig>,
+        sequence_tracker: Arc<SequenceTracker>,
     ) -> Self {
         Self {
             click_repository,
             leaderboard_maintainer,
             click_sender,
             update_tx: update_sender,
-            jetstream,
+            click_bus,
             consumer_config: consumer_config.unwrap_or_default(),
+            sequence_tracker,
+            delivered_clicks: None,
         }
     }
 
-    pub async fn run(&self) -> Result<(), ConsumerError> {
+    pub fn with_delivered_clicks(mut self, delivered_clicks: Arc<broadcast::Sender<DeliveredClick>>) -> Self {
+        self.delivered_clicks = Some(delivered_clicks);
+        self
+    }
+
+    /// Applies clicks until `shutdown` is cancelled, then returns once the clicks being
+    /// applied are done.
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<(), ConsumerError> {
         let click_rx = self.click_sender.subscribe();
-        let nats_consumer: Stream = self.create_consumer().await?;
+        let bus_consumer: ClickSubscription = self.create_consumer().await?
+            .take_until(shutdown.clone().cancelled_owned())
+            .boxed();
         let self_arc = Arc::new(self.clone());
 
-        let nats_handle: JoinHandle<()> = self.clone().launch_nats_consumer(nats_consumer).await;
-        let click_handle: JoinHandle<()> = self_arc.launch_direct_consumer(click_rx);
+        let mut bus_handle: JoinHandle<()> = self.clone().launch_bus_consumer(bus_consumer).await;
+        let mut click_handle: JoinHandle<()> = self_arc.launch_direct_consumer(click_rx, shutdown.clone());
 
         tokio::select! {
-            result = nats_handle => {
+            result = &mut bus_handle => {
                 if let Err(e) = result {
-                    error!("NATS processing task failed: {:?}", e);
+                    error!("Click bus processing task failed: {:?}", e);
+                } else if shutdown.is_cancelled() {
+                    let _ = click_handle.await;
                 } else {
-                    error!("Unexpected NATS exit");
+                    error!("Unexpected click bus exit");
                 }
             }
-            result = click_handle => {
+            result = &mut click_handle => {
                 if let Err(e) = result {
                     error!("Click processing task failed: {:?}", e);
+                } else if shutdown.is_cancelled() {
+                    let _ = bus_handle.await;
                 } else {
                     error!("Unexpected click handle exit");
                 }
@@ -92,7 +119,7 @@ impl OwnershipUpdateService {
         Ok(())
     }
 
-    fn launch_direct_consumer(self: Arc<Self>, click_rx: broadcast::Receiver<Click>) -> JoinHandle<()> {
+    fn launch_direct_consumer(self: Arc<Self>, click_rx: broadcast::Receiver<Click>, shutdown: CancellationToken) -> JoinHandle<()> {
         tokio::spawn({
             let this = self;
             let config = this.consumer_config.concurrent_processors;  // Get the config value before the move
@@ -103,6 +130,7 @@ impl OwnershipUpdateService {
                         Err(_) => None,
                     }
                 })
+                    .take_until(shutdown.cancelled_owned())
                     .map(move |click| {
                         let this = this.clone();
                         async move {
@@ -118,7 +146,7 @@ impl OwnershipUpdateService {
         })
     }
 
-    async fn launch_nats_consumer(self, stream: Stream) -> JoinHandle<()> {
+    async fn launch_bus_consumer(self, stream: ClickSubscription) -> JoinHandle<()> {
         let self_arc = Arc::new(self);
 
         tokio::spawn({
@@ -127,115 +155,106 @@ impl OwnershipUpdateService {
             let stream = stream;
 
             async move {
-                if let Err(e) = process_nats_messages(
+                if let Err(e) = process_deliveries(
                     stream,
                     self_arc,
                     config
                 ).await {
-                    error!("NATS message processing failed: {:?}", e);
+                    error!("Click bus processing failed: {:?}", e);
                 }
             }
         })
     }
 
-    async fn handle_nats_message(&self, message: jetstream::Message) -> Result<(), ConsumerError> {
-        let click: Click = match clickplanet_proto::clicks::Click::decode(message.payload.clone()) {
-            Ok(click) => click,
-            Err(e) => {
-                error!("Failed to decode message payload: {}", e);
-                // Try to ack malformed messages to avoid redelivery
-                if let Err(ack_err) = message.ack().await {
-                    error!("Failed to ack malformed message: {}", ack_err);
-                }
-                return Ok(());
-            }
-        };
+    async fn handle_delivery(&self, delivery: Delivery) -> Result<(), ConsumerError> {
+        let click = delivery.click.clone();
+        let sequence = delivery.sequence;
+        let delivery_count = delivery.delivery_count;
+
+        let result = self.process_click(click).await;
+        // Failed clicks are not retried either, the state is as complete as it will get
+        self.sequence_tracker.complete(sequence);
 
-        match self.process_click(click).await {
+        match result {
             Ok(_) => {
-                if let Err(e) = message.ack().await {
+                if let Some(delivered_clicks) = &self.delivered_clicks {
+                    // Nobody listening is fine
+                    let _ = delivered_clicks.send(DeliveredClick { sequence, click: delivery.click.clone() });
+                }
+                if let Err(e) = delivery.ack().await {
                     error!("Failed to acknowledge message after successful processing: {}", e);
                 }
                 Ok(())
             }
             Err(e) => {
-                error!("Failed to process click: {}", e);
-                if let Err(ack_err) = message.ack().await {
-                    error!("Also failed to acknowledge failed message: {}", ack_err);
+                error!("Failed to process click {} (delivery {}): {}", sequence, delivery_count, e);
+                if let Err(dead_letter_err) = delivery.dead_letter(&e.to_string()).await {
+                    error!("Also failed to dead-letter failed message: {}", dead_letter_err);
                 }
                 Ok(())
             }
         }
     }
 
-    async fn create_consumer(&self) -> Result<jetstream::consumer::pull::Stream, PollingConsumerError> {
-        let stream = get_stream(self.jetstream.clone()).await?;
-
-        let config = jetstream::consumer::pull::Config {
-            durable_name: Some(CONSUMER_NAME.to_string()),
-            deliver_policy: jetstream::consumer::DeliverPolicy::All,
-            ack_policy: jetstream::consumer::AckPolicy::Explicit,
-            ack_wait: self.consumer_config.ack_wait,
-            max_deliver: self.consumer_config.max_deliver,
-            ..Default::default()
-        };
-
-        let consumer = stream
-            .create_consumer(config)
-            .await
-            .map_err(|e| PollingConsumerError::Processing(e.to_string()))?;
-
-        consumer
-            .messages()
-            .await
-            .map_err(|e| PollingConsumerError::Processing(e.to_string()))
+    async fn create_consumer(&self) -> Result<ClickSubscription, PollingConsumerError> {
+        Ok(self.click_bus.subscribe(CONSUMER_NAME, &self.consumer_config).await?)
     }
 
     async fn process_click(&self, click: Click) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let previous_ownership: Option<Ownership> = self.click_repository.save_click(click.tile_id as u32, &click).await?;
 
         // Only process ownership change if:
-        // 1. There was a previous owner (Some) AND
-        // 2. The previous country_id is different from the current one
-        if let Some(last_ownership) = previous_ownership {
-            if last_ownership.country_id != click.country_id {
-                let notification = UpdateNotification {
-                    tile_id: click.tile_id.try_into().unwrap(),
-                    previous_country_id: last_ownership.country_id,
-                    country_id: click.country_id,
-                };
-
-                self.leaderboard_maintainer.update_country_index(click.tile_id as u32,
-                                                                 notification.country_id.as_str(),
-                                                                 Some(notification.previous_country_id.as_str())
-                                                                     .filter(|string| !string.is_empty())).await;
-
-                let result = self.update_tx.send(notification);
-                if let Err(e) = result {
-                    tracing::debug!("No listener for ownership update: {:?}", e);
-                }
-            }
+        // 1. The click was actually applied (newer than the previous ownership) AND
+        // 2. The tile had no owner, or the previous country_id is different from the current one
+        // Clearing a tile that was never owned changes nothing either
+        let previous_country = match previous_ownership {
+            Some(last_ownership) if last_ownership.timestamp_ns >= click.timestamp_ns => return Ok(()),
+            Some(last_ownership) if last_ownership.country_id == click.country_id => return Ok(()),
+            Some(last_ownership) => last_ownership.country_id,
+            None if click.country_id.is_empty() => return Ok(()),
+            None => String::new(),
+        };
+
+        let notification = UpdateNotification {
+            tile_id: click.tile_id,
+            previous_country_id: previous_country,
+            country_id: click.country_id,
+        };
+
+        if !notification.country_id.is_empty() {
+            METRICS.ownership_changes.with(&notification.country_id).inc();
+        }
+        self.leaderboard_maintainer.update_country_index(click.tile_id as u32,
+                                                         notification.country_id.as_str(),
+                                                         Some(notification.previous_country_id.as_str())
+                                                             .filter(|string| !string.is_empty())).await;
+
+        let result = self.update_tx.send(notification);
+        if let Err(e) = result {
+            tracing::debug!("No listener for ownership update: {:?}", e);
         }
 
         Ok(())
     }
 }
 
-async fn process_nats_messages(
-    nats_stream: Stream,
+async fn process_deliveries(
+    deliveries: ClickSubscription,
     owner: Arc<OwnershipUpdateService>,
     config: usize,
 ) -> Result<(), ConsumerError> {
-    nats_stream
+    deliveries
         .map(|message_result| {
             message_result.map_err(|e| ConsumerError::ProcessingError(e.to_string()))
         })
         .try_for_each_concurrent(config, |message| {
             let owner = owner.clone();
+            // Marked before any later delivery is pulled from the stream
+            owner.sequence_tracker.start(message.sequence);
 
             async move {
-                if let Err(e) = owner.handle_nats_message(message).await {
-                    error!("Error processing NATS message: {}", e);
+                if let Err(e) = owner.handle_delivery(message).await {
+                    error!("Error processing click bus delivery: {}", e);
                 }
                 Ok(())
             }
@@ -243,4 +262,98 @@ async fn process_nats_messages(
         .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use crate::in_memory_click_persistence::PapayaClickRepository;
+    use crate::in_process_click_bus::InProcessClickBus;
+    use crate::click_publisher::ClickPublisher;
+
+    #[tokio::test]
+    async fn test_clicks_from_bus_update_ownership() {
+        let repository = Arc::new(PapayaClickRepository::new());
+        let click_bus = Arc::new(InProcessClickBus::new());
+        let (click_sender, _) = broadcast::channel(16);
+        let (update_sender, mut updates) = broadcast::channel(16);
+        let sequence_tracker = Arc::new(SequenceTracker::default());
+
+        let service = OwnershipUpdateService::new(
+            repository.clone(),
+            repository.clone(),
+            Arc::new(click_sender),
+            Arc::new(update_sender),
+            click_bus.clone(),
+            None,
+            sequence_tracker.clone(),
+        );
+        tokio::spawn(async move { service.run(CancellationToken::new()).await });
+
+        click_bus.publish(&Click {
+            tile_id: 7,
+            country_id: "fr".to_string(),
+            // Recent enough not to expire right away
+            timestamp_ns: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64,
+            click_id: String::new(),
+            session_id: String::new(),
+        }).await.unwrap();
+
+        let update = tokio::time::timeout(Duration::from_secs(1), updates.recv()).await.unwrap().unwrap();
+        assert_eq!(update.tile_id, 7);
+        assert_eq!(update.country_id, "fr");
+        assert_eq!(repository.get_tile(7).await.unwrap().unwrap().country_id, "fr");
+
+        // Completed right after the update is sent
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(sequence_tracker.applied(), 1);
+    }
+
+    fn click(tile_id: i32, country_id: &str, timestamp_ns: u64) -> Click {
+        Click {
+            tile_id,
+            country_id: country_id.to_string(),
+            timestamp_ns,
+            click_id: String::new(),
+            session_id: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_ownership_changes_are_notified() {
+        let repository = Arc::new(PapayaClickRepository::new());
+        let (click_sender, _) = broadcast::channel(16);
+        let (update_sender, mut updates) = broadcast::channel(16);
+        let service = OwnershipUpdateService::new(
+            repository.clone(),
+            repository.clone(),
+            Arc::new(click_sender),
+            Arc::new(update_sender),
+            Arc::new(InProcessClickBus::new()),
+            None,
+            Arc::new(SequenceTracker::default()),
+        );
+        let mut notified = || updates.try_recv().ok().map(|update| (update.tile_id, update.previous_country_id, update.country_id));
+
+        // Clearing a tile nobody owns
+        service.process_click(click(1, "", 10)).await.unwrap();
+        assert_eq!(notified(), None);
+
+        // First capture, without a previous owner
+        service.process_click(click(2, "fr", 10)).await.unwrap();
+        assert_eq!(notified(), Some((2, String::new(), "fr".to_string())));
+
+        // Same country again
+        service.process_click(click(2, "fr", 20)).await.unwrap();
+        assert_eq!(notified(), None);
+
+        // Stale clicks, older than or as old as the current ownership
+        service.process_click(click(2, "de", 15)).await.unwrap();
+        service.process_click(click(2, "de", 20)).await.unwrap();
+        assert_eq!(notified(), None);
+        assert_eq!(repository.get_tile(2).await.unwrap().unwrap().country_id, "fr");
+
+        service.process_click(click(2, "de", 30)).await.unwrap();
+        assert_eq!(notified(), Some((2, "fr".to_string(), "de".to_string())));
+    }
+}
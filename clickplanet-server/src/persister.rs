@@ -0,0 +1,161 @@
+use std::sync::Arc;
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use crate::click_bus::ClickBus;
+use crate::click_persistence::{ClickRepository, PersistenceCheckpoint};
+use crate::health::{handle_healthz, readiness, Health};
+use crate::jetstream_click_streamer::{ClickConsumer, CONSUMER_NAME};
+use crate::postgres_click_persistence::PostgresClickRepository;
+use crate::redis_click_persistence::RedisClickRepository;
+use crate::config::{override_with, Backend, Config};
+use crate::metrics::handle_metrics;
+
+/// Options of the consumer writing clicks to the cold storage, each overriding the setting
+/// of the same name.
+#[derive(clap::Args, Debug, Clone)]
+pub struct PersisterArgs {
+    /// Overrides `persister.backend`
+    #[arg(long, env = "PERSISTER_BACKEND", value_enum)]
+    pub backend: Option<Backend>,
+
+    #[arg(long, env = "POSTGRES_URL")]
+    pub postgres_url: Option<String>,
+
+    /// Overrides `persister.consumer.concurrent_processors`
+    #[arg(long, env = "CONCURRENT_PROCESSORS")]
+    pub concurrent_processors: Option<usize>,
+
+    /// Overrides `persister.consumer.ack_wait_secs`
+    #[arg(long, env = "ACK_WAIT_SECS")]
+    pub ack_wait_secs: Option<u64>,
+
+    /// Overrides `persister.consumer.batch_size`
+    #[arg(long, env = "BATCH_SIZE")]
+    pub batch_size: Option<usize>,
+
+    /// Replays the clicks following the checkpoint stored in Redis instead of resuming the
+    /// durable consumer, e.g. after the consumer was deleted
+    #[arg(long, env = "RESUME_FROM_CHECKPOINT")]
+    pub resume_from_checkpoint: bool,
+
+    #[arg(long, env = "STATUS_PORT")]
+    pub status_port: Option<u16>,
+
+    /// Overrides `persister.ready_max_lag`
+    #[arg(long, env = "PERSISTER_READY_MAX_LAG")]
+    pub persister_ready_max_lag: Option<u64>,
+}
+
+impl PersisterArgs {
+    pub fn apply(&self, config: &mut Config) {
+        let persister = &mut config.persister;
+        override_with(&mut persister.backend, &self.backend);
+        override_with(&mut persister.postgres_url, &self.postgres_url);
+        override_with(&mut persister.consumer.concurrent_processors, &self.concurrent_processors);
+        override_with(&mut persister.consumer.ack_wait_secs, &self.ack_wait_secs);
+        override_with(&mut persister.consumer.batch_size, &self.batch_size);
+        persister.resume_from_checkpoint |= self.resume_from_checkpoint;
+        override_with(&mut persister.status_port, &self.status_port);
+        override_with(&mut persister.ready_max_lag, &self.persister_ready_max_lag);
+    }
+}
+
+/// Consumes the clicks of `click_bus` until the subscription ends or `shutdown` is cancelled.
+pub async fn run(
+    config: &Config,
+    click_bus: Arc<dyn ClickBus>,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let args = &config.persister;
+    let (click_persister, checkpoint): (Arc<dyn ClickRepository>, Option<Arc<dyn PersistenceCheckpoint>>) = match args.backend {
+        Backend::Redis => {
+            let repository = Arc::new(RedisClickRepository::new(&config.storage.redis_url).await?);
+            repository.ensure_country_index().await?;
+            (repository.clone(), Some(repository))
+        }
+        Backend::Postgres => (Arc::new(PostgresClickRepository::new(&args.postgres_url).await?), None),
+    };
+    info!("Persisting clicks to {:?}", args.backend);
+
+    let last_sequence = match &checkpoint {
+        Some(checkpoint) => checkpoint.checkpoint().await?,
+        None => None,
+    };
+    if let Some(last_sequence) = last_sequence {
+        info!("Clicks persisted up to sequence {}", last_sequence);
+    }
+
+    let start_sequence = if args.resume_from_checkpoint {
+        Some(last_sequence.map_or(1, |sequence| sequence + 1))
+    } else {
+        None
+    };
+
+    let consumer = ClickConsumer::new(
+        click_bus.clone(),
+        Some(args.consumer.consumer_config(start_sequence)),
+        click_persister,
+        checkpoint,
+    );
+
+    let health = Arc::new(Health::new(click_bus.clone(), CONSUMER_NAME, args.ready_max_lag, shutdown.clone()));
+    tokio::spawn({
+        let (port, shutdown) = (args.status_port, shutdown.clone());
+        async move {
+            if let Err(e) = serve_status(health, port, shutdown).await {
+                error!("Status server error: {}", e);
+            }
+        }
+    });
+
+    info!("Starting click consumer...");
+    let consumer_run = consumer.run(shutdown.clone());
+    tokio::pin!(consumer_run);
+    tokio::select! {
+        result = &mut consumer_run => result?,
+        _ = shutdown.cancelled() => {
+            match tokio::time::timeout(config.timeouts.drain(), &mut consumer_run).await {
+                Ok(result) => result?,
+                Err(_) => warn!("Clicks still being persisted after {:?}", config.timeouts.drain()),
+            }
+        }
+    }
+
+    // Acknowledgments of the last batches
+    if let Err(e) = click_bus.flush().await {
+        error!("Failed to flush the click bus: {}", e);
+    }
+
+    Ok(())
+}
+
+async fn handle_readyz(State(health): State<Arc<Health>>) -> impl IntoResponse {
+    readiness(health.status().await)
+}
+
+async fn handle_status(State(health): State<Arc<Health>>) -> impl IntoResponse {
+    Json(health.status().await)
+}
+
+/// Serves `/healthz`, `/readyz`, `/v2/status` and `/metrics` on `port` until `shutdown` is
+/// cancelled. The server has these routes on its API port.
+async fn serve_status(health: Arc<Health>, port: u16, shutdown: CancellationToken) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/healthz", get(handle_healthz))
+        .route("/readyz", get(handle_readyz))
+        .route("/v2/status", get(handle_status))
+        .route("/metrics", get(handle_metrics))
+        .with_state(health);
+
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
+    info!("Status listening on 0.0.0.0:{}", port);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
@@ -0,0 +1,366 @@
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clickplanet_proto::clicks::PlayerStatsResponse;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+use crate::click_persistence::{ClickRepository, ClickRepositoryError};
+use crate::home_territory::HomeTerritoryMap;
+use crate::metrics::METRICS;
+use crate::ownership_service::DeliveredClick;
+use crate::state_snapshot::now_ns;
+
+/// Who holds a tile, and since when.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TileHold {
+    country_id: String,
+    /// Session of the click that took the tile for its country, empty when anonymous or unknown
+    session_id: String,
+    since_ns: u64,
+    /// Timestamp of the newest click applied to the tile
+    timestamp_ns: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct SessionStats {
+    clicks: u64,
+    captures: u64,
+    held_tiles: BTreeSet<u32>,
+    /// Longest hold that is over, as tile id and nanoseconds
+    longest_hold: Option<(u32, u64)>,
+    /// Clicks by real-world country of the tile clicked
+    battlegrounds: HashMap<String, u64>,
+    /// Timestamp of the newest click of the session, 0 in files saved before it was kept
+    #[serde(default)]
+    last_click_ns: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct StatsState {
+    /// Highest click bus sequence recorded
+    sequence: u64,
+    tiles: HashMap<u32, TileHold>,
+    sessions: HashMap<String, SessionStats>,
+}
+
+/// Statistics of each player session, aggregated from the clicks delivered by the bus.
+///
+/// A tile is held by the session whose click took it for its country, until another
+/// country takes it or it is cleared. Anonymous clicks count for no session. Sessions that
+/// did not click for as long as a session token is valid are forgotten.
+pub struct PlayerStats {
+    homes: Option<Arc<HomeTerritoryMap>>,
+    /// Clicks up to this sequence were recorded before the statistics were saved, and are
+    /// not counted again when the bus replays them
+    recorded_up_to: u64,
+    state: Mutex<StatsState>,
+}
+
+impl PlayerStats {
+    pub fn new(homes: Option<Arc<HomeTerritoryMap>>) -> Self {
+        Self {
+            homes,
+            recorded_up_to: 0,
+            state: Mutex::new(StatsState::default()),
+        }
+    }
+
+    /// Starts from the current ownerships, held by no session.
+    pub async fn populate_with(
+        homes: Option<Arc<HomeTerritoryMap>>,
+        repository: Arc<dyn ClickRepository>,
+    ) -> Result<Self, ClickRepositoryError> {
+        let stats = Self::new(homes);
+        let ownerships = repository.get_ownerships().await?;
+
+        stats.state.lock().unwrap().tiles.extend(ownerships.ownerships.into_iter().map(|ownership| {
+            (ownership.tile_id, TileHold {
+                country_id: ownership.country_id,
+                session_id: String::new(),
+                since_ns: ownership.timestamp_ns,
+                timestamp_ns: ownership.timestamp_ns,
+            })
+        }));
+        Ok(stats)
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(path: P, homes: Option<Arc<HomeTerritoryMap>>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut state: StatsState = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        // Given a full lifetime from now
+        let now_ns = now_ns();
+        for stats in state.sessions.values_mut().filter(|stats| stats.last_click_ns == 0) {
+            stats.last_click_ns = now_ns;
+        }
+        Ok(Self {
+            homes,
+            recorded_up_to: state.sequence,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = path.as_ref();
+        // Serialized once copied, not to hold up the clicks being recorded
+        let state = self.state.lock().unwrap().clone();
+        let json = serde_json::to_string(&state)?;
+
+        // Write then rename so a crash never leaves a truncated file behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, json)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// For buses numbering their clicks from 1 again after a restart, as the in-process bus
+    /// without a log file.
+    pub fn forget_sequence(&mut self) {
+        self.recorded_up_to = 0;
+        self.state.get_mut().unwrap().sequence = 0;
+    }
+
+    pub fn record(&self, delivered: &DeliveredClick) {
+        if delivered.sequence <= self.recorded_up_to {
+            return;
+        }
+
+        let click = &delivered.click;
+        let tile_id = click.tile_id as u32;
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.sequence = state.sequence.max(delivered.sequence);
+
+        if !click.session_id.is_empty() {
+            let stats = state.sessions.entry(click.session_id.clone()).or_default();
+            stats.clicks += 1;
+            stats.last_click_ns = stats.last_click_ns.max(click.timestamp_ns);
+            if let Some(home) = self.homes.as_ref().and_then(|homes| homes.home_country(tile_id)) {
+                *stats.battlegrounds.entry(home.to_string()).or_default() += 1;
+            }
+        }
+
+        let previous = state.tiles.get_mut(&tile_id);
+        match previous {
+            // Taken by a newer click already
+            Some(hold) if hold.timestamp_ns >= click.timestamp_ns => return,
+            Some(hold) if hold.country_id == click.country_id => {
+                hold.timestamp_ns = click.timestamp_ns;
+                return;
+            }
+            None if click.country_id.is_empty() => return,
+            _ => {}
+        }
+
+        if let Some(hold) = state.tiles.remove(&tile_id) {
+            if let Some(stats) = state.sessions.get_mut(&hold.session_id) {
+                stats.held_tiles.remove(&tile_id);
+                let held_ns = click.timestamp_ns.saturating_sub(hold.since_ns);
+                if stats.longest_hold.is_none_or(|(_, longest_ns)| held_ns > longest_ns) {
+                    stats.longest_hold = Some((tile_id, held_ns));
+                }
+            }
+        }
+
+        // Cleared tiles are kept, so that older clicks are still ignored
+        let captured = !click.country_id.is_empty() && !click.session_id.is_empty();
+        state.tiles.insert(tile_id, TileHold {
+            country_id: click.country_id.clone(),
+            session_id: if captured { click.session_id.clone() } else { String::new() },
+            since_ns: click.timestamp_ns,
+            timestamp_ns: click.timestamp_ns,
+        });
+
+        if captured {
+            let stats = state.sessions.entry(click.session_id.clone()).or_default();
+            stats.captures += 1;
+            stats.held_tiles.insert(tile_id);
+        }
+    }
+
+    /// Forgets the sessions without any click since `idle_ns` before `now_ns`, whose tokens
+    /// expired. The tiles they hold stay held by no known session.
+    fn forget_idle_sessions(&self, now_ns: u64, idle_ns: u64) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let before = state.sessions.len();
+        state.sessions.retain(|_, stats| now_ns.saturating_sub(stats.last_click_ns) < idle_ns);
+        before - state.sessions.len()
+    }
+
+    /// Statistics of the session, all zero for sessions that never clicked.
+    pub fn stats(&self, session_id: &str) -> PlayerStatsResponse {
+        self.stats_at(session_id, now_ns())
+    }
+
+    fn stats_at(&self, session_id: &str, now_ns: u64) -> PlayerStatsResponse {
+        let state = self.state.lock().unwrap();
+        let mut response = PlayerStatsResponse {
+            session_id: session_id.to_string(),
+            ..Default::default()
+        };
+        let Some(stats) = state.sessions.get(session_id) else {
+            return response;
+        };
+
+        let ongoing_hold = stats.held_tiles
+            .iter()
+            .filter_map(|tile_id| state.tiles.get(tile_id).map(|hold| (*tile_id, now_ns.saturating_sub(hold.since_ns))))
+            .max_by_key(|(_, held_ns)| *held_ns);
+        let (longest_hold, ongoing) = match (ongoing_hold, stats.longest_hold) {
+            (Some(ongoing_hold), Some(longest_hold)) if longest_hold.1 > ongoing_hold.1 => (Some(longest_hold), false),
+            (Some(ongoing_hold), _) => (Some(ongoing_hold), true),
+            (None, longest_hold) => (longest_hold, false),
+        };
+
+        // Ties go to the first country in alphabetical order
+        let favourite = stats.battlegrounds
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)));
+
+        response.clicks = stats.clicks;
+        response.captures = stats.captures;
+        response.tiles_held = stats.held_tiles.len() as u32;
+        if let Some((tile_id, held_ns)) = longest_hold {
+            response.longest_held_tile_id = tile_id;
+            response.longest_held_secs = held_ns / 1_000_000_000;
+            response.longest_hold_ongoing = ongoing;
+        }
+        if let Some((country_id, clicks)) = favourite {
+            response.favourite_battleground = country_id.clone();
+            response.favourite_battleground_clicks = *clicks;
+        }
+        response
+    }
+
+    pub async fn run(self: Arc<Self>, mut delivered_clicks: broadcast::Receiver<DeliveredClick>) {
+        info!("Starting player statistics");
+
+        loop {
+            match delivered_clicks.recv().await {
+                Ok(delivered) => self.record(&delivered),
+                Err(RecvError::Lagged(skipped)) => {
+                    METRICS.broadcast_lag_events.with("player_stats").inc();
+                    warn!("Player statistics missed {} clicks", skipped);
+                }
+                Err(RecvError::Closed) => {
+                    info!("Click channel closed, stopping player statistics");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Saves the statistics every `interval`, first forgetting the sessions idle for longer
+    /// than `session_ttl`.
+    pub async fn persist_periodically(self: Arc<Self>, path: String, interval: Duration, session_ttl: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            let forgotten = self.forget_idle_sessions(now_ns(), session_ttl.as_nanos() as u64);
+            if forgotten > 0 {
+                info!("Forgot the statistics of {} idle sessions", forgotten);
+            }
+
+            let (stats, file) = (self.clone(), path.clone());
+            let saved = tokio::task::spawn_blocking(move || stats.save_to_file(file)).await;
+            if let Err(e) = saved.map_err(Into::into).and_then(|saved| saved) {
+                error!("Failed to persist player statistics to {}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickplanet_proto::clicks::Click;
+    use uuid::Uuid;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn delivered(sequence: u64, tile_id: i32, country_id: &str, session_id: &str, timestamp_secs: u64) -> DeliveredClick {
+        DeliveredClick {
+            sequence,
+            click: Click {
+                tile_id,
+                country_id: country_id.to_string(),
+                timestamp_ns: timestamp_secs * SECOND,
+                click_id: String::new(),
+                session_id: session_id.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_captures_and_holds() {
+        let homes = HomeTerritoryMap::new(HashMap::from([(1, "fr".to_string()), (2, "fr".to_string()), (3, "de".to_string())]));
+        let stats = PlayerStats::new(Some(Arc::new(homes)));
+
+        stats.record(&delivered(1, 1, "fr", "alice", 10));
+        stats.record(&delivered(2, 2, "fr", "alice", 20));
+        stats.record(&delivered(3, 3, "fr", "alice", 30));
+        // Already held by France, and an older click taken over since
+        stats.record(&delivered(4, 1, "fr", "bob", 40));
+        stats.record(&delivered(5, 2, "de", "bob", 15));
+        // Bob takes tile 2 after alice held it for 40 seconds, then an admin clears tile 3
+        stats.record(&delivered(6, 2, "de", "bob", 60));
+        stats.record(&delivered(7, 3, "", "", 70));
+
+        let alice = stats.stats_at("alice", 100 * SECOND);
+        assert_eq!((alice.clicks, alice.captures, alice.tiles_held), (3, 3, 1));
+        // Tile 1, still held since 10s
+        assert_eq!((alice.longest_held_tile_id, alice.longest_held_secs, alice.longest_hold_ongoing), (1, 90, true));
+        assert_eq!((alice.favourite_battleground.as_str(), alice.favourite_battleground_clicks), ("fr", 2));
+
+        let bob = stats.stats_at("bob", 100 * SECOND);
+        assert_eq!((bob.clicks, bob.captures, bob.tiles_held), (3, 1, 1));
+        assert_eq!((bob.longest_held_tile_id, bob.longest_held_secs), (2, 40));
+
+        // Holds that are over count too
+        let alice = stats.stats_at("alice", 45 * SECOND);
+        assert_eq!((alice.longest_held_tile_id, alice.longest_held_secs, alice.longest_hold_ongoing), (2, 40, false));
+
+        assert_eq!(stats.stats_at("carol", 100 * SECOND), PlayerStatsResponse { session_id: "carol".to_string(), ..Default::default() });
+    }
+
+    #[test]
+    fn test_replayed_clicks_are_not_counted_again() {
+        let path = std::env::temp_dir().join(format!("player_stats_{}.json", Uuid::new_v4()));
+        let stats = PlayerStats::new(None);
+        stats.record(&delivered(1, 1, "fr", "alice", 10));
+        stats.record(&delivered(2, 2, "fr", "alice", 20));
+        stats.save_to_file(&path).unwrap();
+
+        let stats = PlayerStats::load_from_file(&path, None).unwrap();
+        stats.record(&delivered(2, 2, "fr", "alice", 20));
+        stats.record(&delivered(3, 3, "fr", "alice", 30));
+        assert_eq!(stats.stats_at("alice", 40 * SECOND).clicks, 3);
+
+        let mut stats = PlayerStats::load_from_file(&path, None).unwrap();
+        stats.forget_sequence();
+        stats.record(&delivered(1, 4, "fr", "alice", 50));
+        assert_eq!(stats.stats_at("alice", 60 * SECOND).captures, 3);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_idle_sessions_are_forgotten() {
+        let stats = PlayerStats::new(None);
+        stats.record(&delivered(1, 1, "fr", "alice", 10));
+        stats.record(&delivered(2, 2, "de", "bob", 50));
+
+        assert_eq!(stats.forget_idle_sessions(60 * SECOND, 30 * SECOND), 1);
+        assert_eq!(stats.stats_at("alice", 60 * SECOND).clicks, 0);
+        assert_eq!(stats.stats_at("bob", 60 * SECOND).clicks, 1);
+
+        // The tile alice held is still taken by its newest click only
+        stats.record(&delivered(3, 1, "de", "bob", 70));
+        assert_eq!(stats.stats_at("bob", 80 * SECOND).captures, 2);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderMap;
+
+use crate::config::RateLimitConfig;
+
+/// Clients past this number are refused until the next sweep forgets the idle ones.
+const MAX_TRACKED_CLIENTS: usize = 100000;
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const FORWARDED_FOR_HEADER: &str = "x-forwarded-for";
+
+/// The address of the client behind `peer`, the last `X-Forwarded-For` address when the
+/// proxy setting it is trusted: the proxy appends the address it received the request from
+/// to whatever the client sent.
+pub fn client_of(peer: SocketAddr, headers: &HeaderMap, trust_forwarded_for: bool) -> IpAddr {
+    let forwarded_for = headers
+        .get_all(FORWARDED_FOR_HEADER)
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|client| client.trim().parse().ok());
+
+    match forwarded_for {
+        Some(client) if trust_forwarded_for => client,
+        _ => peer.ip(),
+    }
+}
+
+/// IPv6 clients are limited by /64, the smallest network usually handed to a subscriber.
+fn bucket_of(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(address) => IpAddr::V6(Ipv6Addr::from(u128::from(address) & !(u64::MAX as u128))),
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Token bucket per client address: up to `burst` clicks at once, refilled at
+/// `clicks_per_second`.
+pub struct RateLimiter {
+    clicks_per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// None when clicks are not limited.
+    pub fn new(config: &RateLimitConfig) -> Option<Self> {
+        if config.clicks_per_second <= 0.0 {
+            return None;
+        }
+
+        Some(Self {
+            clicks_per_second: config.clicks_per_second,
+            burst: config.burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes a token from the bucket of `client`, or of its /64 network, false when it is empty.
+    pub fn try_acquire(&self, client: IpAddr, now: Instant) -> bool {
+        let client = bucket_of(client);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+            return false;
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: self.burst, refilled_at: now });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets the clients whose bucket is back to a full burst.
+    pub fn sweep(&self, now: Instant) {
+        self.buckets.lock().unwrap().retain(|_, bucket| self.refill(bucket, now) < self.burst);
+    }
+
+    /// Sweeps every time an empty bucket takes to refill, so requests never pay for it.
+    pub async fn sweep_periodically(self: Arc<Self>) {
+        let refill_time = Duration::from_secs_f64(self.burst / self.clicks_per_second);
+        let mut ticker = tokio::time::interval(refill_time.max(MIN_SWEEP_INTERVAL));
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            self.sweep(Instant::now());
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.clicks_per_second).min(self.burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(clicks_per_second: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig { clicks_per_second, burst, trust_forwarded_for: false }).unwrap()
+    }
+
+    #[test]
+    fn test_bursts_then_refills() {
+        let limiter = limiter(2.0, 3);
+        let (client, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+
+        assert!((0..3).all(|_| limiter.try_acquire(client, start)));
+        assert!(!limiter.try_acquire(client, start));
+        assert!(limiter.try_acquire(other, start));
+
+        assert!(limiter.try_acquire(client, start + Duration::from_millis(500)));
+        assert!(!limiter.try_acquire(client, start + Duration::from_millis(500)));
+
+        // Never more than the burst
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| limiter.try_acquire(client, later)));
+        assert!(!limiter.try_acquire(client, later));
+    }
+
+    #[test]
+    fn test_full_map_refuses_new_clients_until_swept() {
+        let limiter = limiter(1.0, 1);
+        let start = Instant::now();
+        let client = |i: usize| IpAddr::from((i as u32 + 1).to_be_bytes());
+
+        assert!((0..MAX_TRACKED_CLIENTS).all(|i| limiter.try_acquire(client(i), start)));
+        let newcomer = client(MAX_TRACKED_CLIENTS);
+        assert!(!limiter.try_acquire(newcomer, start));
+
+        // Tracked clients are still served once refilled
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.try_acquire(client(0), later));
+        assert!(!limiter.try_acquire(newcomer, later));
+
+        limiter.sweep(later);
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+        assert!(limiter.try_acquire(newcomer, later));
+    }
+
+    #[test]
+    fn test_forwarded_for_only_when_trusted() {
+        let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(FORWARDED_FOR_HEADER, "203.0.113.7, 10.0.0.1".parse().unwrap());
+
+        assert_eq!(client_of(peer, &headers, false), peer.ip());
+        assert_eq!(client_of(peer, &headers, true), "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(client_of(peer, &HeaderMap::new(), true), peer.ip());
+
+        // The client may send any header, the proxy adds the address it sees last
+        headers.append(FORWARDED_FOR_HEADER, "198.51.100.4".parse().unwrap());
+        assert_eq!(client_of(peer, &headers, true), "198.51.100.4".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_ipv6_networks_share_a_bucket() {
+        let limiter = limiter(1.0, 1);
+        let start = Instant::now();
+
+        assert!(limiter.try_acquire("2001:db8:0:1::1".parse().unwrap(), start));
+        assert!(!limiter.try_acquire("2001:db8:0:1:ffff::2".parse().unwrap(), start));
+        assert!(limiter.try_acquire("2001:db8:0:2::1".parse().unwrap(), start));
+    }
+
+    #[test]
+    fn test_disabled_without_rate() {
+        assert!(RateLimiter::new(&RateLimitConfig::default()).is_none());
+    }
+}
@@ -1,22 +1,67 @@
-use crate::click_persistence::{ClickRepository, ClickRepositoryError};
+use crate::click_persistence::{ClickRepository, ClickRepositoryError, LeaderboardError, LeaderboardRepository, PersistenceCheckpoint};
 use async_trait::async_trait;
 use clickplanet_proto::clicks::UpdateNotification;
 use clickplanet_proto::clicks::{Click, Ownership, OwnershipState};
 use deadpool_redis::redis::AsyncCommands;
 use deadpool_redis::{redis, Config as RedisConfig, CreatePoolError, PoolError, Runtime};
-use log::error;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::in_memory_click_persistence::PapayaClickRepository;
+use crate::metrics::METRICS;
 use thiserror::Error;
 use tracing::{debug, info, instrument, Span};
 
-const TILES_KEY: &str = "tiles";
+/// Tiles are spread over hashes of `TILE_SHARD_SIZE` tiles, keyed by tile id
+const TILE_SHARD_KEY_PREFIX: &str = "tile_shard:";
+const TILE_SHARD_SIZE: u32 = 4096;
+/// Sorted set of countries scored by their tile count
+const LEADERBOARD_KEY: &str = "leaderboard";
+/// Prefix of the per-country sets of owned tile ids
+const COUNTRY_TILES_KEY_PREFIX: &str = "country_tiles:";
+const TIMESTAMP_WIDTH: usize = 20;
+/// Last click bus sequence written by the persister
+const CHECKPOINT_KEY: &str = "persister:last_sequence";
+const SAVE_CLICK_SCRIPT: &str = include_str!("redis_save_click.lua");
+
+fn tile_shard_key(tile_id: u32) -> String {
+    format!("{}{}", TILE_SHARD_KEY_PREFIX, tile_id / TILE_SHARD_SIZE)
+}
+
+fn country_tiles_key(country_id: &str) -> String {
+    format!("{}{}", COUNTRY_TILES_KEY_PREFIX, country_id)
+}
+
+/// Ownership as stored in a tile shard: the zero-padded timestamp then the country id, so the
+/// save script can compare timestamps without parsing them.
+fn pack_ownership(country_id: &str, timestamp_ns: u64) -> String {
+    format!("{:0width$}{}", timestamp_ns, country_id, width = TIMESTAMP_WIDTH)
+}
+
+fn unpack_ownership(tile_id: u32, packed: &str) -> Result<Ownership, ClickRepositoryError> {
+    let invalid = || ClickRepositoryError::InvalidDataError(format!("Tile {}: {:?}", tile_id, packed));
+
+    if packed.len() < TIMESTAMP_WIDTH || !packed.is_char_boundary(TIMESTAMP_WIDTH) {
+        return Err(invalid());
+    }
+
+    let (timestamp, country_id) = packed.split_at(TIMESTAMP_WIDTH);
+    Ok(Ownership {
+        tile_id,
+        country_id: country_id.to_string(),
+        timestamp_ns: timestamp.parse().map_err(|_| invalid())?,
+    })
+}
 
+/// Tile ownerships in Redis, written by a Lua script doing the timestamp compare-and-set and
+/// the country index update atomically.
+///
+/// The script derives the previous owner's tile set key itself, so it expects a single Redis
+/// instance rather than a cluster.
 pub struct RedisClickRepository {
     redis_pool: Arc<deadpool_redis::Pool>,
+    save_click_script: redis::Script,
 }
 
 #[derive(Error, Debug)]
@@ -46,6 +91,12 @@ impl From<RedisError> for ClickRepositoryError {
     }
 }
 
+impl From<RedisError> for LeaderboardError {
+    fn from(err: RedisError) -> Self {
+        LeaderboardError::StorageError(err.to_string())
+    }
+}
+
 impl RedisClickRepository {
     pub async fn new(redis_url: &str) -> Result<Self, RedisError> {
         let redis_cfg = RedisConfig::from_url(redis_url);
@@ -53,106 +104,158 @@ impl RedisClickRepository {
 
         Ok(Self {
             redis_pool: Arc::new(redis_pool),
+            save_click_script: redis::Script::new(SAVE_CLICK_SCRIPT),
         })
     }
-}
 
-#[async_trait]
-impl ClickRepository for RedisClickRepository {
-    async fn get_tile(
-        &self,
-        tile_id: u32,
-    ) -> Result<Option<Ownership>, ClickRepositoryError> {
+    async fn shard_keys(&self) -> Result<Vec<String>, RedisError> {
+        self.keys_with_prefix(TILE_SHARD_KEY_PREFIX).await
+    }
+
+    /// With `SCAN` rather than `KEYS`, which blocks the server until every key is listed.
+    async fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, RedisError> {
+        let mut redis_conn = self.redis_pool.get().await?;
+
+        let mut keys = Vec::new();
+        let mut iter: redis::AsyncIter<String> = redis_conn
+            .scan_match(format!("{}*", prefix))
+            .await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+
+        Ok(keys)
+    }
+
+    /// Reads whole shards and keeps the tiles accepted by `filter`, sorted by tile id.
+    async fn read_shards<F>(&self, shard_keys: Vec<String>, filter: F) -> Result<OwnershipState, ClickRepositoryError>
+    where
+        F: Fn(u32) -> bool,
+    {
+        if shard_keys.is_empty() {
+            return Ok(OwnershipState::default());
+        }
+
         let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
 
-        let tile_contents: Vec<String> = redis_conn
-            .zrangebyscore_limit::<String, u32, u32, Vec<String>>(TILES_KEY.to_string(), tile_id, tile_id, 0, 1)
+        let mut pipe = redis::pipe();
+        for key in &shard_keys {
+            pipe.hgetall(key);
+        }
+
+        let shards: Vec<HashMap<String, String>> = pipe
+            .query_async(&mut redis_conn)
             .await
             .map_err(RedisError::from)?;
 
-        if let Some(val) = tile_contents.first() {
-            let parts: Vec<&str> = val.split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(_) = parts[1].parse::<u64>() {
-                    return Ok(Some(Ownership {
-                        tile_id: tile_id as u32,
-                        country_id: parts[0].to_string(),
-                        timestamp_ns: parts[1].parse::<u64>().unwrap(),
-                    }));
-                }
+        let mut ownerships = Vec::new();
+        for (tile_id, packed) in shards.into_iter().flatten() {
+            let tile_id = tile_id.parse::<u32>()
+                .map_err(|e| ClickRepositoryError::InvalidDataError(e.to_string()))?;
+
+            if filter(tile_id) {
+                ownerships.push(unpack_ownership(tile_id, &packed)?);
             }
         }
 
-        Ok(None)
+        ownerships.sort_by_key(|ownership| ownership.tile_id);
+        Ok(OwnershipState { ownerships })
     }
 
-    async fn get_ownerships(&self) -> Result<OwnershipState, ClickRepositoryError> {
+    /// Rebuilds the leaderboard and the country tile sets from the tiles if they were never written,
+    /// e.g. for data persisted before the country index existed.
+    pub async fn ensure_country_index(&self) -> Result<(), ClickRepositoryError> {
         let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
 
-        // Get all tiles with their scores (-inf to +inf range)
-        let tile_contents: Vec<(String, String)> = redis_conn
-            .zrangebyscore_withscores(
-                TILES_KEY,
-                "-inf",  // Get from lowest possible score
-                "+inf",  // to highest possible score
-            )
+        let index_exists: bool = redis_conn
+            .exists(LEADERBOARD_KEY)
             .await
             .map_err(RedisError::from)?;
 
-        let mut ownerships = Vec::new();
+        if index_exists || self.shard_keys().await?.is_empty() {
+            return Ok(());
+        }
 
-        for (contents, tile_id) in tile_contents {
-            let parts: Vec<&str> = contents.split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(timestamp_ns) = parts[1].parse::<u64>() {
-                    let tile_id = tile_id.parse::<u32>()
-                        .map_err(|e| ClickRepositoryError::InvalidDataError(e.to_string()))?;
-
-                    ownerships.push(Ownership {
-                        tile_id,
-                        country_id: parts[0].to_string(),
-                        timestamp_ns,
-                    });
-                }
-            }
+        info!("Rebuilding country index from tiles");
+        self.rebuild_country_index().await
+    }
+
+    /// Replaces the leaderboard and the country tile sets with the ones counted from the tiles.
+    ///
+    /// The tiles are read before the index is replaced: captures saved in between are missing
+    /// from it, so nothing else may write the tiles while it runs.
+    pub async fn rebuild_country_index(&self) -> Result<(), ClickRepositoryError> {
+        let ownership_state = self.get_ownerships().await?;
+
+        let mut country_tiles: HashMap<String, Vec<u32>> = HashMap::new();
+        for ownership in ownership_state.ownerships.into_iter().filter(|ownership| !ownership.country_id.is_empty()) {
+            country_tiles.entry(ownership.country_id).or_default().push(ownership.tile_id);
         }
 
-        Ok(OwnershipState { ownerships })
+        let stale_keys = self.keys_with_prefix(COUNTRY_TILES_KEY_PREFIX).await?;
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(LEADERBOARD_KEY);
+        for key in stale_keys {
+            pipe.del(key);
+        }
+
+        for (country_id, tiles) in &country_tiles {
+            pipe.sadd(country_tiles_key(country_id), tiles)
+                .zadd(LEADERBOARD_KEY, country_id, tiles.len());
+        }
+
+        pipe.query_async::<_, ()>(&mut redis_conn)
+            .await
+            .map_err(RedisError::from)?;
+
+        info!("Country index rebuilt for {} countries", country_tiles.len());
+        Ok(())
     }
+}
 
-    async fn get_ownerships_by_batch(
+#[async_trait]
+impl ClickRepository for RedisClickRepository {
+    async fn get_tile(
         &self,
-        start_tile_id: u32,
-        end_tile_id: u32,
-    ) -> Result<OwnershipState, ClickRepositoryError> {
+        tile_id: u32,
+    ) -> Result<Option<Ownership>, ClickRepositoryError> {
+        let _timer = METRICS.redis_operation_seconds.with("get_tile").start_timer();
         let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
 
-        let tile_contents: Vec<(String, String)> = redis_conn
-            .zrangebyscore_withscores(
-                TILES_KEY,
-                start_tile_id as isize,
-                end_tile_id as isize,
-            )
+        let packed: Option<String> = redis_conn
+            .hget(tile_shard_key(tile_id), tile_id)
             .await
             .map_err(RedisError::from)?;
 
-        let mut ownerships = Vec::new();
+        packed
+            .map(|packed| unpack_ownership(tile_id, &packed))
+            .transpose()
+    }
 
-        for (contents, tile_id) in tile_contents {
-            let parts: Vec<&str> = contents.split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(timestamp_ns) = parts[1].parse::<u64>() {
-                    ownerships.push(Ownership {
-                        tile_id: tile_id.parse::<u32>()
-                            .map_err(|e| ClickRepositoryError::InvalidDataError(e.to_string()))?,
-                        country_id: parts[0].to_string(),
-                        timestamp_ns: timestamp_ns,
-                    });
-                }
-            }
+    async fn get_ownerships(&self) -> Result<OwnershipState, ClickRepositoryError> {
+        let _timer = METRICS.redis_operation_seconds.with("get_ownerships").start_timer();
+        let shard_keys = self.shard_keys().await?;
+        self.read_shards(shard_keys, |_| true).await
+    }
+
+    async fn get_ownerships_by_batch(
+        &self,
+        start_tile_id: u32,
+        end_tile_id: u32,
+    ) -> Result<OwnershipState, ClickRepositoryError> {
+        let _timer = METRICS.redis_operation_seconds.with("get_ownerships_by_batch").start_timer();
+
+        if start_tile_id > end_tile_id {
+            return Ok(OwnershipState::default());
         }
 
-        Ok(OwnershipState { ownerships })
+        let shard_keys = (start_tile_id / TILE_SHARD_SIZE..=end_tile_id / TILE_SHARD_SIZE)
+            .map(|shard| tile_shard_key(shard * TILE_SHARD_SIZE))
+            .collect();
+
+        self.read_shards(shard_keys, |tile_id| (start_tile_id..=end_tile_id).contains(&tile_id)).await
     }
 
     #[instrument(
@@ -167,6 +270,7 @@ impl ClickRepository for RedisClickRepository {
         )
     )]
     async fn save_click(&self, tile_id: u32, click: &Click) -> Result<Option<Ownership>, ClickRepositoryError> {
+        let _timer = METRICS.redis_operation_seconds.with("save_click").start_timer();
         let receive_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -179,77 +283,32 @@ impl ClickRepository for RedisClickRepository {
 
         let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
 
-        let current_value: Option<String> = redis_conn
-            .zrangebyscore::<String, u32, u32, Vec<String>>(TILES_KEY.to_string(), tile_id, tile_id)
+        let (applied, previous_value): (i32, String) = self.save_click_script
+            .key(tile_shard_key(tile_id))
+            .key(LEADERBOARD_KEY)
+            .arg(tile_id)
+            .arg(pack_ownership(&click.country_id, click.timestamp_ns))
+            .arg(&click.country_id)
+            .arg(COUNTRY_TILES_KEY_PREFIX)
+            .invoke_async(&mut redis_conn)
             .await
-            .map_err(RedisError::from)?
-            .get(0)
-            .cloned();
-
-        debug!(
-           "Current value for tile {} ({:?})",
-           tile_id, current_value
-        );
+            .map_err(RedisError::from)?;
 
-        // Create previous ownership object
-        let previous_ownership: Option<Ownership> = if let Some(current_val) = &current_value {
-            let parts: Vec<&str> = current_val.split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(current_ts) = parts[1].parse::<u64>() {
-                    let ownership = Some(Ownership {
-                        tile_id,
-                        country_id: parts[0].to_string(),
-                        timestamp_ns: current_ts,
-                    });
-
-                    if click.timestamp_ns <= current_ts {
-                        info!(
-                            "Ignoring outdated update for tile {} (current: {}, received: {})",
-                            tile_id, current_ts, click.timestamp_ns
-                        );
-
-                        return Ok(ownership);
-                    }
-
-                    ownership
-                } else {
-                    None
-                }
-            } else {
-                return Err(ClickRepositoryError::InvalidDataError(current_val.clone()));
-            }
-        } else {
-            debug!("No key for tile {}", tile_id);
+        let previous_ownership = if previous_value.is_empty() {
             None
+        } else {
+            Some(unpack_ownership(tile_id, &previous_value)?)
         };
 
-        let new_value = format!("{}:{}", click.country_id, click.timestamp_ns);
-
-        debug!(
-           "New value for tile {} ({:?})",
-           tile_id, new_value
-        );
-
-        let old_values: Vec<String> = redis_conn
-            .zrangebyscore(TILES_KEY, tile_id as f64, tile_id as f64)
-            .await
-            .map_err(RedisError::from)?;
-
-        let mut pipe = redis::pipe();
-        let mut pipe = pipe
-            .atomic()
-            .zadd(TILES_KEY, new_value.clone(), tile_id as f64);
+        if applied == 0 {
+            info!(
+                "Ignoring outdated update for tile {} (current: {:?}, received: {})",
+                tile_id, previous_ownership.as_ref().map(|o| o.timestamp_ns), click.timestamp_ns
+            );
 
-        for old_value in old_values {
-            if old_value != new_value {
-                pipe = pipe.zrem(TILES_KEY, old_value);
-            }
+            return Ok(previous_ownership);
         }
 
-        pipe.query_async(&mut redis_conn)
-            .await
-            .map_err(RedisError::from)?;
-
         let processing_time = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -269,9 +328,93 @@ impl ClickRepository for RedisClickRepository {
 
         Ok(previous_ownership)
     }
+
+    /// Runs the save script for every click in a single round trip.
+    async fn save_clicks(&self, clicks: &[Click]) -> Result<(), ClickRepositoryError> {
+        let _timer = METRICS.redis_operation_seconds.with("save_clicks").start_timer();
+        if clicks.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        // EVALSHA fails if the server lost its script cache
+        pipe.cmd("SCRIPT").arg("LOAD").arg(SAVE_CLICK_SCRIPT).ignore();
+        for click in clicks {
+            let tile_id = click.tile_id as u32;
+            pipe.cmd("EVALSHA")
+                .arg(self.save_click_script.get_hash())
+                .arg(2)
+                .arg(tile_shard_key(tile_id))
+                .arg(LEADERBOARD_KEY)
+                .arg(tile_id)
+                .arg(pack_ownership(&click.country_id, click.timestamp_ns))
+                .arg(&click.country_id)
+                .arg(COUNTRY_TILES_KEY_PREFIX);
+        }
+
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+        let results: Vec<(i32, String)> = pipe
+            .query_async(&mut redis_conn)
+            .await
+            .map_err(RedisError::from)?;
+
+        let applied = results.iter().filter(|(applied, _)| *applied == 1).count();
+        debug!("Saved {} clicks, {} outdated", applied, clicks.len() - applied);
+        Ok(())
+    }
 }
 
+#[async_trait]
+impl PersistenceCheckpoint for RedisClickRepository {
+    async fn save_checkpoint(&self, sequence: u64) -> Result<(), ClickRepositoryError> {
+        let _timer = METRICS.redis_operation_seconds.with("save_checkpoint").start_timer();
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+        redis_conn
+            .set::<_, _, ()>(CHECKPOINT_KEY, sequence)
+            .await
+            .map_err(RedisError::from)?;
+        Ok(())
+    }
 
+    async fn checkpoint(&self) -> Result<Option<u64>, ClickRepositoryError> {
+        let _timer = METRICS.redis_operation_seconds.with("checkpoint").start_timer();
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+        Ok(redis_conn
+            .get(CHECKPOINT_KEY)
+            .await
+            .map_err(RedisError::from)?)
+    }
+}
+
+#[async_trait]
+impl LeaderboardRepository for RedisClickRepository {
+    async fn get_score(&self, country_id: &str) -> Result<u32, LeaderboardError> {
+        let _timer = METRICS.redis_operation_seconds.with("get_score").start_timer();
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+
+        let score: Option<f64> = redis_conn
+            .zscore(LEADERBOARD_KEY, country_id)
+            .await
+            .map_err(RedisError::from)?;
+
+        Ok(score.unwrap_or(0.0) as u32)
+    }
+
+    async fn leaderboard(&self) -> Result<HashMap<String, u32>, LeaderboardError> {
+        let _timer = METRICS.redis_operation_seconds.with("leaderboard").start_timer();
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+
+        let scores: Vec<(String, f64)> = redis_conn
+            .zrangebyscore_withscores(LEADERBOARD_KEY, "(0", "+inf")
+            .await
+            .map_err(RedisError::from)?;
+
+        Ok(scores
+            .into_iter()
+            .map(|(country_id, score)| (country_id, score as u32))
+            .collect())
+    }
+}
 
 #[cfg(test)]
 mod click_tests {
@@ -305,6 +448,7 @@ mod click_tests {
             country_id: country_id.to_string(),
             timestamp_ns: now,
             click_id: format!("test_click_{}", tile_id),
+            session_id: String::new(),
         }
     }
 
@@ -421,6 +565,83 @@ mod click_tests {
         assert!(ownership.is_some());
     }
 
+    #[test]
+    fn test_packed_ownership() {
+        let packed = pack_ownership("fr", 1_700_000_000_123_456_789);
+        assert_eq!(packed, "01700000000123456789fr");
+        assert!(pack_ownership("fr", 99) < pack_ownership("de", 100));
+
+        let ownership = unpack_ownership(7, &packed).unwrap();
+        assert_eq!(ownership.tile_id, 7);
+        assert_eq!(ownership.country_id, "fr");
+        assert_eq!(ownership.timestamp_ns, 1_700_000_000_123_456_789);
+
+        assert!(unpack_ownership(7, "fr:1700000000").is_err());
+        assert!(unpack_ownership(7, "123").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_outdated_click_is_ignored() {
+        let (repo, _container) = create_test_repo().await;
+
+        let newer = create_test_click(1, "fr");
+        let mut older = create_test_click(1, "de");
+        older.timestamp_ns = newer.timestamp_ns - 1;
+
+        assert_eq!(repo.save_click(1, &newer).await.unwrap(), None);
+        let current = repo.save_click(1, &older).await.unwrap().unwrap();
+        assert_eq!(current.country_id, "fr");
+
+        assert_eq!(repo.get_tile(1).await.unwrap().unwrap().country_id, "fr");
+        assert_eq!(repo.get_score("de").await.unwrap(), 0);
+        assert_eq!(repo.get_ownerships_by_batch(0, 4096).await.unwrap().ownerships.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batched_clicks_and_checkpoint() {
+        let (repo, _container) = create_test_repo().await;
+
+        let newer = create_test_click(1, "fr");
+        let mut older = create_test_click(2, "de");
+        older.timestamp_ns = newer.timestamp_ns - 1;
+        repo.save_click(2, &newer).await.unwrap();
+
+        repo.save_clicks(&[newer.clone(), older, create_test_click(3, "de")]).await.unwrap();
+
+        assert_eq!(repo.get_tile(1).await.unwrap().unwrap().country_id, "fr");
+        assert_eq!(repo.get_tile(2).await.unwrap().unwrap().country_id, "fr");
+        assert_eq!(repo.leaderboard().await.unwrap(), HashMap::from([("fr".to_string(), 2), ("de".to_string(), 1)]));
+
+        assert_eq!(repo.checkpoint().await.unwrap(), None);
+        repo.save_checkpoint(42).await.unwrap();
+        assert_eq!(repo.checkpoint().await.unwrap(), Some(42));
+    }
+
+    #[tokio::test]
+    async fn test_leaderboard_follows_clicks() {
+        let (repo, _container) = create_test_repo().await;
+
+        repo.save_click(1, &create_test_click(1, "fr")).await.unwrap();
+        repo.save_click(2, &create_test_click(2, "fr")).await.unwrap();
+        repo.save_click(3, &create_test_click(3, "de")).await.unwrap();
+        repo.save_click(1, &create_test_click(1, "de")).await.unwrap();
+        // Same owner again, no score change
+        repo.save_click(1, &create_test_click(1, "de")).await.unwrap();
+
+        let leaderboard = repo.leaderboard().await.unwrap();
+        assert_eq!(leaderboard.len(), 2);
+        assert_eq!(leaderboard["de"], 2);
+        assert_eq!(leaderboard["fr"], 1);
+
+        repo.save_click(2, &create_test_click(2, "de")).await.unwrap();
+        assert_eq!(repo.get_score("fr").await.unwrap(), 0);
+        assert!(!repo.leaderboard().await.unwrap().contains_key("fr"));
+
+        // A rebuild from the tiles gives the same scores
+        repo.rebuild_country_index().await.unwrap();
+        assert_eq!(repo.leaderboard().await.unwrap(), HashMap::from([("de".to_string(), 3)]));
+    }
+
     #[tokio::test]
     async fn test_error_handling() {
         let (repo, container) = create_test_repo().await;
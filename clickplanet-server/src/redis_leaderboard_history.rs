@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use deadpool_redis::redis::AsyncCommands;
+use deadpool_redis::{Config as RedisConfig, Runtime};
+
+use crate::leaderboard_history::{LeaderboardHistoryError, LeaderboardHistoryStore, LeaderboardSnapshot};
+use crate::redis_click_persistence::RedisError;
+
+const LEADERBOARD_HISTORY_KEY: &str = "leaderboard_history";
+
+impl From<RedisError> for LeaderboardHistoryError {
+    fn from(err: RedisError) -> Self {
+        LeaderboardHistoryError::StorageError(err.to_string())
+    }
+}
+
+/// Snapshots stored as JSON members of a sorted set scored by their timestamp.
+pub struct RedisLeaderboardHistory {
+    redis_pool: Arc<deadpool_redis::Pool>,
+}
+
+impl RedisLeaderboardHistory {
+    pub async fn new(redis_url: &str) -> Result<Self, RedisError> {
+        let redis_cfg = RedisConfig::from_url(redis_url);
+        let redis_pool = redis_cfg.create_pool(Some(Runtime::Tokio1))?;
+
+        Ok(Self {
+            redis_pool: Arc::new(redis_pool),
+        })
+    }
+}
+
+#[async_trait]
+impl LeaderboardHistoryStore for RedisLeaderboardHistory {
+    async fn append(&self, snapshot: &LeaderboardSnapshot) -> Result<(), LeaderboardHistoryError> {
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+
+        let member = serde_json::to_string(snapshot)
+            .map_err(|e| LeaderboardHistoryError::InvalidDataError(e.to_string()))?;
+
+        redis_conn
+            .zadd::<_, _, _, ()>(LEADERBOARD_HISTORY_KEY, member, snapshot.timestamp_secs)
+            .await
+            .map_err(RedisError::from)?;
+
+        Ok(())
+    }
+
+    async fn range(&self, from_secs: u64, to_secs: u64) -> Result<Vec<LeaderboardSnapshot>, LeaderboardHistoryError> {
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+
+        let members: Vec<String> = redis_conn
+            .zrangebyscore(LEADERBOARD_HISTORY_KEY, from_secs, to_secs)
+            .await
+            .map_err(RedisError::from)?;
+
+        members
+            .iter()
+            .map(|member| {
+                serde_json::from_str(member).map_err(|e| LeaderboardHistoryError::InvalidDataError(e.to_string()))
+            })
+            .collect()
+    }
+
+    async fn prune_before(&self, timestamp_secs: u64) -> Result<(), LeaderboardHistoryError> {
+        let mut redis_conn = self.redis_pool.get().await.map_err(RedisError::from)?;
+
+        redis_conn
+            .zrembyscore::<_, _, _, ()>(LEADERBOARD_HISTORY_KEY, "-inf", format!("({}", timestamp_secs))
+            .await
+            .map_err(RedisError::from)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+-- Atomically records a click if it is newer than the current tile owner, and keeps the
+-- country index in sync.
+--
+-- KEYS[1]: tile shard hash
+-- KEYS[2]: leaderboard sorted set
+-- ARGV[1]: tile id
+-- ARGV[2]: packed ownership (20 digits zero-padded timestamp followed by the country id)
+-- ARGV[3]: country id, empty when the tile is cleared
+-- ARGV[4]: prefix of the per-country tile sets
+--
+-- Returns {applied, previous packed ownership or ""}.
+
+local current = redis.call('HGET', KEYS[1], ARGV[1])
+
+-- Fixed width timestamps compare like numbers
+if current and string.sub(current, 1, 20) >= string.sub(ARGV[2], 1, 20) then
+    return {0, current}
+end
+
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+
+local previous_country = current and string.sub(current, 21) or nil
+if previous_country ~= ARGV[3] then
+    if previous_country and previous_country ~= '' then
+        redis.call('SREM', ARGV[4] .. previous_country, ARGV[1])
+        if tonumber(redis.call('ZINCRBY', KEYS[2], -1, previous_country)) <= 0 then
+            redis.call('ZREM', KEYS[2], previous_country)
+        end
+    end
+
+    if ARGV[3] ~= '' then
+        redis.call('SADD', ARGV[4] .. ARGV[3], ARGV[1])
+        redis.call('ZINCRBY', KEYS[2], 1, ARGV[3])
+    end
+end
+
+return {1, current or ''}
@@ -0,0 +1,101 @@
+use clap::Parser;
+use clickplanet_telemetry::{init_telemetry, TelemetryArgs};
+use clickplanet_proto::clicks::Click;
+use deadpool_redis::redis::AsyncCommands;
+use deadpool_redis::{Config as RedisConfig, Runtime};
+use tracing::{info, warn};
+
+use clickplanet_server::click_persistence::ClickRepository;
+use clickplanet_server::redis_click_persistence::RedisClickRepository;
+
+/// Sorted set of `country:timestamp` members scored by tile id, written before tile shards
+const LEGACY_TILES_KEY: &str = "tiles";
+/// Tiles saved per round trip
+const MIGRATION_BATCH_SIZE: usize = 1000;
+
+/// Copies tiles from the legacy `tiles` sorted set to the tile shards.
+///
+/// Tiles go through the same compare-and-set script as live clicks, so they never overwrite a
+/// newer ownership. The country index is then rebuilt from the shards, which loses the
+/// captures saved meanwhile: stop the persisters while it runs.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(long, env = "REDIS_URL", default_value = "redis://localhost:6379")]
+    redis_url: String,
+
+    /// Deletes the legacy sorted set once every tile was copied
+    #[arg(long, default_value_t = false)]
+    delete_legacy: bool,
+
+    #[command(flatten)]
+    telemetry: TelemetryArgs,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let telemetry = init_telemetry(&args.telemetry, "redis-schema-migrator")?;
+
+    let result = migrate(&args).await;
+
+    telemetry.shutdown().await;
+    result
+}
+
+async fn migrate(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+
+    let redis_pool = RedisConfig::from_url(args.redis_url.as_str()).create_pool(Some(Runtime::Tokio1))?;
+    let mut redis_conn = redis_pool.get().await?;
+
+    let legacy_tiles: Vec<(String, u32)> = redis_conn
+        .zrangebyscore_withscores(LEGACY_TILES_KEY, "-inf", "+inf")
+        .await?;
+    info!("Found {} legacy tiles", legacy_tiles.len());
+
+    let repository = RedisClickRepository::new(args.redis_url.as_str()).await?;
+    let mut migrated = 0;
+    let mut skipped = 0;
+    let mut batch = Vec::with_capacity(MIGRATION_BATCH_SIZE);
+
+    for (member, tile_id) in &legacy_tiles {
+        let Some((country_id, timestamp_ns)) = member
+            .rsplit_once(':')
+            .and_then(|(country_id, timestamp)| Some((country_id, timestamp.parse::<u64>().ok()?)))
+        else {
+            warn!("Skipping malformed legacy tile {}: {:?}", tile_id, member);
+            skipped += 1;
+            continue;
+        };
+
+        batch.push(Click {
+            tile_id: *tile_id as i32,
+            country_id: country_id.to_string(),
+            timestamp_ns,
+            click_id: String::new(),
+            session_id: String::new(),
+        });
+        if batch.len() == MIGRATION_BATCH_SIZE {
+            repository.save_clicks(&batch).await?;
+            migrated += batch.len();
+            batch.clear();
+        }
+    }
+    repository.save_clicks(&batch).await?;
+    migrated += batch.len();
+
+    // The script counts a migrated tile as a new capture, start the index over from the shards
+    repository.rebuild_country_index().await?;
+    info!("Migrated {} tiles, skipped {}", migrated, skipped);
+
+    if args.delete_legacy {
+        if skipped > 0 {
+            warn!("Keeping {} since some tiles could not be migrated", LEGACY_TILES_KEY);
+        } else {
+            redis_conn.del::<_, ()>(LEGACY_TILES_KEY).await?;
+            info!("Deleted {}", LEGACY_TILES_KEY);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1323 @@
+use crate::click_service::{is_tile, ClickService};
+use axum::{
+    extract::{ConnectInfo, Json, Path, Query, State},
+    extract::ws::{Message as WebsocketMessage},
+    extract::ws::{close_code, CloseFrame, WebSocket},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::post,
+    routing::get,
+    Router,
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use serde_json::{json, Value};
+use tokio;
+use tokio::net::TcpListener;
+use tracing::{debug, error, info, warn};
+use base64::{encode};
+use futures_util::{SinkExt, StreamExt};
+use std::{future::IntoFuture, time::Duration};
+use axum::extract::WebSocketUpgrade;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, Uri};
+use prost::Message;
+use tokio::sync::Mutex;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::services::{ServeDir, ServeFile};
+use tower_http::trace::TraceLayer;
+use clickplanet_proto::clicks::{LeaderboardEntry, StateSnapshot, UpdateNotification};
+use clickplanet_proto::clicks::{ClickRequest, Resync, SessionRequest, SystemMessage, TerritoryResponse};
+use clickplanet_proto::clicks::server_message::Payload;
+use clickplanet_topology::TileTopology;
+
+use crate::click_persistence::{ClickRepository, LeaderboardError, LeaderboardRepository};
+use crate::in_memory_click_persistence::{PapayaClickRepository};
+use crate::ownership_service::{ConsumerError, OwnershipUpdateService, CONSUMER_NAME};
+use crate::redis_click_persistence::{RedisClickRepository};
+use crate::territory_analyzer::TerritoryAnalyzer;
+use crate::home_territory::{HomeControlScores, HomeTerritoryMap};
+use crate::ownership_listener::follow_updates;
+use crate::invasion_flows::{FlowWindow, InvasionFlowTracker};
+use crate::activity_heatmap::ActivityHeatmap;
+use crate::player_stats::PlayerStats;
+use crate::leaderboard_history::{now_secs, LeaderboardHistory};
+use crate::redis_leaderboard_history::RedisLeaderboardHistory;
+use crate::leaderboard_push::{envelope, LeaderboardPublisher};
+use crate::leaderboard_index::{LeaderboardIndex, LeaderboardQuery};
+use crate::embedded_click_persistence::{EmbeddedClickRepository, WriteThroughClickRepository};
+use crate::leaderboard_history::LeaderboardHistoryStore;
+use crate::click_publisher::ClickPublisher;
+use crate::jetstream_click_bus::JetStreamClickBus;
+use crate::in_process_click_bus::InProcessClickBus;
+use crate::click_bus::ClickBusError;
+use crate::config::{override_with, AdminConfig, Bus, Config, CorsConfig, GameConfig, Storage, TimeoutsConfig, ANY_ORIGIN};
+use crate::rate_limit::{client_of, RateLimiter};
+use crate::admin::{self, Admin, AdminError, AdminRequest, RestrictionStore};
+use crate::session::{Session, SessionSigner};
+use crate::state_snapshot::{now_ns, snapshot_periodically, write_snapshot, SequenceTracker, SnapshotStore};
+use crate::health::{handle_healthz, readiness, Health, ServiceStatus};
+use crate::metrics::{handle_metrics, METRICS};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ClickPayload {
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchRequestPayload {
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopInWindowQuery {
+    window: Option<String>,
+    top: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HeatmapQuery {
+    window: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaderboardParams {
+    limit: Option<usize>,
+    offset: Option<usize>,
+    /// Returns the countries ranked around this one instead of a page
+    around: Option<String>,
+    radius: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListenQuery {
+    /// Wraps frames in `ServerMessage` instead of sending bare `UpdateNotification`s
+    envelope: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TimeRangeQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+#[derive(Clone)]
+struct AppState<T: ClickRepository + Send + Sync> {
+    click_service: Arc<ClickService>,
+    click_repository: Arc<T>,
+    leaderboard_index: Arc<LeaderboardIndex>,
+    update_notifification_broadcaster: Arc<Sender<UpdateNotification>>,
+    territory_analyzer: Option<Arc<TerritoryAnalyzer>>,
+    home_control_scores: Option<Arc<HomeControlScores>>,
+    invasion_flows: Arc<InvasionFlowTracker>,
+    activity_heatmap: Arc<ActivityHeatmap>,
+    player_stats: Arc<PlayerStats>,
+    leaderboard_history: Arc<LeaderboardHistory>,
+    leaderboard_publisher: Arc<LeaderboardPublisher>,
+    health: Arc<Health>,
+    tile_count: usize,
+    game: Arc<GameConfig>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    trust_forwarded_for: bool,
+    sessions: Arc<SessionSigner>,
+    restrictions: Arc<RestrictionStore>,
+    system_messages: Arc<Sender<SystemMessage>>,
+    /// None when no admin token or signing key is configured
+    admin: Option<Arc<Admin>>,
+    timeouts: TimeoutsConfig,
+    shutdown: CancellationToken,
+    /// Held by every request until it completes, to wait for them on shutdown
+    _in_flight: mpsc::Sender<()>,
+}
+
+/// Options of the HTTP and WebSocket API, each overriding the setting of the same name.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ServerArgs {
+    /// Overrides `bus.kind`
+    #[arg(long, env = "CLICK_BUS", value_enum)]
+    pub click_bus: Option<Bus>,
+
+    /// Overrides `bus.click_log_file`
+    #[arg(long, env = "CLICK_LOG_FILE")]
+    pub click_log_file: Option<String>,
+
+    /// Overrides `storage.kind`
+    #[arg(long, env = "STORAGE", value_enum)]
+    pub storage: Option<Storage>,
+
+    /// Overrides `storage.file`
+    #[arg(long, env = "STORAGE_FILE")]
+    pub storage_file: Option<String>,
+
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+
+    #[arg(long, env = "TOPOLOGY_FILE")]
+    pub topology_file: Option<String>,
+
+    #[arg(long, env = "TILE_COUNTRIES_FILE")]
+    pub tile_countries_file: Option<String>,
+
+    #[arg(long, env = "INVASION_FLOWS_FILE")]
+    pub invasion_flows_file: Option<String>,
+
+    #[arg(long, env = "PLAYER_STATS_FILE")]
+    pub player_stats_file: Option<String>,
+
+    /// Overrides `game.tile_count`
+    #[arg(long, env = "TILE_COUNT")]
+    pub tile_count: Option<usize>,
+
+    #[arg(long, env = "LEADERBOARD_SNAPSHOT_SECS")]
+    pub leaderboard_snapshot_secs: Option<u64>,
+
+    #[arg(long, env = "LEADERBOARD_RETENTION_HOURS")]
+    pub leaderboard_retention_hours: Option<u64>,
+
+    #[arg(long, env = "LEADERBOARD_PUSH_MILLIS")]
+    pub leaderboard_push_millis: Option<u64>,
+
+    #[arg(long, env = "SNAPSHOT_DIR")]
+    pub snapshot_dir: Option<String>,
+
+    #[arg(long, env = "SNAPSHOT_SECS")]
+    pub snapshot_secs: Option<u64>,
+
+    #[arg(long, env = "SNAPSHOT_KEEP")]
+    pub snapshot_keep: Option<usize>,
+
+    #[arg(long, env = "READY_MAX_LAG")]
+    pub ready_max_lag: Option<u64>,
+}
+
+impl ServerArgs {
+    pub fn apply(&self, config: &mut Config) {
+        override_with(&mut config.bus.kind, &self.click_bus);
+        if self.click_log_file.is_some() {
+            config.bus.click_log_file = self.click_log_file.clone();
+        }
+        override_with(&mut config.storage.kind, &self.storage);
+        override_with(&mut config.storage.file, &self.storage_file);
+        override_with(&mut config.game.tile_count, &self.tile_count);
+
+        let server = &mut config.server;
+        override_with(&mut server.port, &self.port);
+        override_with(&mut server.topology_file, &self.topology_file);
+        override_with(&mut server.tile_countries_file, &self.tile_countries_file);
+        override_with(&mut server.invasion_flows_file, &self.invasion_flows_file);
+        override_with(&mut server.player_stats_file, &self.player_stats_file);
+        override_with(&mut server.leaderboard_snapshot_secs, &self.leaderboard_snapshot_secs);
+        override_with(&mut server.leaderboard_retention_hours, &self.leaderboard_retention_hours);
+        override_with(&mut server.leaderboard_push_millis, &self.leaderboard_push_millis);
+        override_with(&mut server.snapshot_dir, &self.snapshot_dir);
+        override_with(&mut server.snapshot_secs, &self.snapshot_secs);
+        override_with(&mut server.snapshot_keep, &self.snapshot_keep);
+        override_with(&mut server.ready_max_lag, &self.ready_max_lag);
+    }
+}
+
+/// The newest snapshot, unless the clicks applied since then may have left the click bus.
+fn recent_snapshot(store: &SnapshotStore, retention: Duration) -> Option<StateSnapshot> {
+    let snapshot = match store.load_latest() {
+        Ok(snapshot) => snapshot?,
+        Err(e) => {
+            warn!("Loading the state from storage, cannot read snapshots: {}", e);
+            return None;
+        }
+    };
+
+    let age = Duration::from_nanos(now_ns().saturating_sub(snapshot.timestamp_ns));
+    if age >= retention {
+        warn!("Loading the state from storage, the latest snapshot is {:?} old", age);
+        return None;
+    }
+
+    Some(snapshot)
+}
+
+async fn verify_snapshot_leaderboard(
+    papaya: &PapayaClickRepository,
+    entries: &[LeaderboardEntry],
+) -> Result<(), LeaderboardError> {
+    let restored = papaya.leaderboard().await?;
+    let saved: HashMap<String, u32> = entries
+        .iter()
+        .map(|entry| (entry.country_id.clone(), entry.score))
+        .collect();
+
+    if restored != saved {
+        warn!("Leaderboard of the state snapshot does not match its tiles, using the tiles");
+    }
+    Ok(())
+}
+
+pub async fn create_click_bus(config: &Config) -> Result<Arc<dyn ClickPublisher>, ClickBusError> {
+    let retention = config.stream.retention();
+    Ok(match (config.bus.kind, &config.bus.click_log_file) {
+        (Bus::Jetstream, _) => Arc::new(JetStreamClickBus::connect(&config.bus.nats_url, retention).await?),
+        (Bus::InProcess, None) => Arc::new(InProcessClickBus::with_retention(retention)),
+        (Bus::InProcess, Some(click_log_file)) => Arc::new(InProcessClickBus::with_log_file(click_log_file, retention)?),
+    })
+}
+
+// Settings only the server reads
+impl TimeoutsConfig {
+    fn click(&self) -> Duration {
+        Duration::from_secs(self.click_secs)
+    }
+
+    fn query(&self) -> Duration {
+        Duration::from_secs(self.query_secs)
+    }
+}
+
+impl CorsConfig {
+    fn allows_any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == ANY_ORIGIN)
+    }
+}
+
+impl GameConfig {
+    fn allows_country(&self, country_id: &str) -> bool {
+        self.countries.is_empty() || self.countries.iter().any(|country| country == country_id)
+    }
+}
+
+impl AdminConfig {
+    fn enabled(&self) -> bool {
+        self.token.is_some() || self.signing_key.is_some()
+    }
+}
+
+impl Config {
+    /// Whether the clicks keep their sequence across restarts, and can be replayed from one,
+    /// which the in-process bus cannot do without a log file.
+    fn bus_keeps_sequences(&self) -> bool {
+        !(self.bus.kind == Bus::InProcess && self.bus.click_log_file.is_none())
+    }
+
+    /// Only snapshots whose following clicks can be replayed are restored.
+    fn snapshots_enabled(&self) -> bool {
+        self.server.snapshot_secs > 0 && self.bus_keeps_sequences()
+    }
+}
+
+fn cors_layer(cors: &CorsConfig) -> CorsLayer {
+    let allow_origin = if cors.allows_any_origin() {
+        AllowOrigin::any()
+    } else {
+        // Checked when the configuration is loaded
+        AllowOrigin::list(cors.allowed_origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_headers([CONTENT_TYPE, AUTHORIZATION])
+}
+
+/// Serves the API until the server or the ownership update service stops, along with the
+/// files of `webapp_dir` when given.
+///
+/// Once `shutdown` is cancelled, clicks are refused, WebSockets are closed with a restart
+/// code, and the server returns after the clicks in progress are published and applied
+/// and a last snapshot is written.
+pub async fn run(
+    config: &Config,
+    click_bus: Arc<dyn ClickPublisher>,
+    webapp_dir: Option<&str>,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let args = &config.server;
+    let (click_sender, _) = broadcast::channel(config.bus.broadcast_capacity);
+    let click_sender_ref = Arc::new(click_sender);
+
+    let (update_notification_sender, _) = broadcast::channel(config.bus.broadcast_capacity);
+    let update_sender_ref: Arc<Sender<UpdateNotification>> = Arc::new(update_notification_sender);
+
+    let (cold_repository, history_store): (Arc<dyn ClickRepository>, Arc<dyn LeaderboardHistoryStore>) = match config.storage.kind {
+        Storage::Redis => {
+            let redis_url = config.storage.redis_url.as_str();
+            let repository = RedisClickRepository::new(redis_url).await?;
+            repository.ensure_country_index().await?;
+            (Arc::new(repository), Arc::new(RedisLeaderboardHistory::new(redis_url).await?))
+        }
+        Storage::Embedded => {
+            let repository = Arc::new(EmbeddedClickRepository::open(&config.storage.file)?);
+            (repository.clone(), repository)
+        }
+    };
+    let snapshot_store = Arc::new(SnapshotStore::new(&args.snapshot_dir, args.snapshot_keep));
+    let snapshot = if config.snapshots_enabled() { recent_snapshot(&snapshot_store, config.stream.retention()) } else { None };
+
+    let (papaya_honey, sequence_tracker, start_sequence) = match snapshot {
+        Some(snapshot) => {
+            let papaya = PapayaClickRepository::from_ownerships(snapshot.ownerships.unwrap_or_default()).await?;
+            verify_snapshot_leaderboard(&papaya, &snapshot.leaderboard).await?;
+            // Through a consumer of its own getting every click from there, which the health
+            // checks report instead of the durable one shared by servers started from storage
+            info!("Restored state snapshot, replaying clicks from sequence {}", snapshot.stream_sequence + 1);
+            (papaya, SequenceTracker::starting_at(snapshot.stream_sequence), Some(snapshot.stream_sequence + 1))
+        }
+        None => (PapayaClickRepository::populate_with(cold_repository.clone()).await?, SequenceTracker::default(), None),
+    };
+    let sequence_tracker = Arc::new(sequence_tracker);
+
+    let click_repository: Arc<PapayaClickRepository> = Arc::new(papaya_honey.clone());
+
+    // Without Redis nothing else persists the clicks
+    let ownership_repository: Arc<dyn ClickRepository> = match config.storage.kind {
+        Storage::Redis => click_repository.clone(),
+        Storage::Embedded => Arc::new(WriteThroughClickRepository::new(click_repository.clone(), cold_repository)),
+    };
+
+
+    let (delivered_click_sender, _) = broadcast::channel(config.bus.broadcast_capacity);
+    let delivered_clicks = Arc::new(delivered_click_sender);
+
+    let update_service = Arc::new(OwnershipUpdateService::new(
+        ownership_repository,
+        click_repository.clone(),
+        click_sender_ref.clone(),
+        update_sender_ref.clone(),
+        click_bus.clone().as_click_bus(),
+        Some(args.consumer.consumer_config(start_sequence)),
+        sequence_tracker.clone(),
+    ).with_delivered_clicks(delivered_clicks.clone()));
+
+    if config.snapshots_enabled() {
+        tokio::spawn(snapshot_periodically(
+            snapshot_store.clone(),
+            sequence_tracker.clone(),
+            click_repository.clone(),
+            Duration::from_secs(args.snapshot_secs),
+        ));
+    }
+
+    let topology = match TileTopology::read_from_file(&args.topology_file) {
+        Ok(topology) => Some(Arc::new(topology)),
+        Err(e) => {
+            warn!("Territory analytics disabled, cannot load topology {}: {}", args.topology_file, e);
+            None
+        }
+    };
+
+    let homes = match HomeTerritoryMap::from_file(&args.tile_countries_file) {
+        Ok(homes) => Some(Arc::new(homes)),
+        Err(e) => {
+            warn!("Home control scores disabled, cannot load {}: {}", args.tile_countries_file, e);
+            None
+        }
+    };
+
+    let tile_count = match (&topology, &homes) {
+        (Some(topology), _) => topology.tile_count(),
+        (None, Some(homes)) => homes.tile_count(),
+        (None, None) => config.game.tile_count,
+    };
+
+    let leaderboard_updates = update_sender_ref.subscribe();
+    let leaderboard_index = Arc::new(LeaderboardIndex::populate_with(tile_count as u32, click_repository.clone()).await?);
+    tokio::spawn(follow_updates(leaderboard_index.clone(), leaderboard_updates, click_repository.clone()));
+    let leaderboard_repo: Arc<dyn LeaderboardRepository> = leaderboard_index.clone();
+
+    let territory_analyzer = match &topology {
+        Some(topology) => {
+            let updates = update_sender_ref.subscribe();
+            let analyzer = Arc::new(TerritoryAnalyzer::populate_with(topology.clone(), click_repository.clone()).await?);
+            tokio::spawn(follow_updates(analyzer.clone(), updates, click_repository.clone()));
+            Some(analyzer)
+        }
+        None => None,
+    };
+
+    let home_control_scores = match &homes {
+        Some(homes) => {
+            let updates = update_sender_ref.subscribe();
+            let scores = Arc::new(HomeControlScores::populate_with(homes.clone(), click_repository.clone()).await?);
+            tokio::spawn(follow_updates(scores.clone(), updates, click_repository.clone()));
+            Some(scores)
+        }
+        None => None,
+    };
+
+    let invasion_flows = Arc::new(InvasionFlowTracker::load_from_file(&args.invasion_flows_file)
+        .unwrap_or_else(|e| {
+            warn!("Starting with empty invasion flows, cannot load {}: {}", args.invasion_flows_file, e);
+            InvasionFlowTracker::new()
+        }));
+    tokio::spawn(invasion_flows.clone().run(update_sender_ref.subscribe()));
+    tokio::spawn(invasion_flows.clone().persist_periodically(args.invasion_flows_file.clone(), Duration::from_secs(60)));
+
+    let mut player_stats = match PlayerStats::load_from_file(&args.player_stats_file, homes.clone()) {
+        Ok(stats) => stats,
+        Err(e) => {
+            warn!("Starting with empty player statistics, cannot load {}: {}", args.player_stats_file, e);
+            PlayerStats::populate_with(homes.clone(), click_repository.clone()).await?
+        }
+    };
+    if !config.bus_keeps_sequences() {
+        player_stats.forget_sequence();
+    }
+    let player_stats = Arc::new(player_stats);
+    tokio::spawn(player_stats.clone().run(delivered_clicks.subscribe()));
+    tokio::spawn(player_stats.clone().persist_periodically(
+        args.player_stats_file.clone(),
+        Duration::from_secs(60),
+        Duration::from_secs(config.session.ttl_secs),
+    ));
+
+    let activity_heatmap = Arc::new(ActivityHeatmap::new(tile_count));
+    tokio::spawn(activity_heatmap.clone().run(click_sender_ref.subscribe()));
+
+    let leaderboard_history = Arc::new(LeaderboardHistory::new(
+        history_store,
+        leaderboard_repo.clone(),
+        Duration::from_secs(args.leaderboard_retention_hours * 60 * 60),
+    ));
+    tokio::spawn(leaderboard_history.clone().snapshot_periodically(Duration::from_secs(args.leaderboard_snapshot_secs)));
+
+    let leaderboard_publisher = Arc::new(LeaderboardPublisher::new(leaderboard_repo.clone()));
+    tokio::spawn(leaderboard_publisher.clone().run(Duration::from_millis(args.leaderboard_push_millis)));
+
+    // Behind a load balancer, each server would refuse the sessions the others opened
+    if config.session.signing_key.is_none() && config.bus.kind == Bus::Jetstream {
+        error!("No session.signing_key set while sharing the JetStream bus: set the same key on every server, or players get 401 on the servers that did not open their session");
+    }
+
+    let rate_limiter = RateLimiter::new(&config.rate_limit).map(Arc::new);
+    if let Some(rate_limiter) = &rate_limiter {
+        tokio::spawn(rate_limiter.clone().sweep_periodically());
+    }
+
+    let restrictions = Arc::new(RestrictionStore::load(&config.admin.state_file)
+        .map_err(|e| format!("Cannot load admin restrictions {}: {}", config.admin.state_file, e))?);
+    let (system_messages, _) = broadcast::channel(16);
+    let system_messages = Arc::new(system_messages);
+    tokio::spawn(admin::follow_restrictions(restrictions.clone(), click_bus.clone()));
+    tokio::spawn(admin::follow_system_messages(click_bus.clone(), system_messages.clone()));
+
+    let admin = if config.admin.enabled() {
+        Some(Arc::new(Admin::new(
+            &config.admin,
+            restrictions.clone(),
+            click_bus.clone(),
+            click_repository.clone(),
+            homes,
+            tile_count,
+        )?))
+    } else {
+        None
+    };
+
+    let (in_flight, mut in_flight_done) = mpsc::channel::<()>(1);
+
+    let state = AppState {
+        click_service: Arc::new(ClickService::new(click_bus.clone(), click_sender_ref.clone())),
+        click_repository: click_repository.clone(),
+        leaderboard_index,
+        update_notifification_broadcaster: update_sender_ref.clone(),
+        territory_analyzer,
+        home_control_scores,
+        invasion_flows: invasion_flows.clone(),
+        activity_heatmap,
+        player_stats: player_stats.clone(),
+        leaderboard_history,
+        leaderboard_publisher,
+        health: Arc::new(Health::new(click_bus.clone().as_click_bus(), CONSUMER_NAME, args.ready_max_lag, shutdown.clone())),
+        tile_count,
+        game: Arc::new(config.game.clone()),
+        rate_limiter,
+        trust_forwarded_for: config.rate_limit.trust_forwarded_for,
+        sessions: Arc::new(SessionSigner::new(&config.session)),
+        restrictions,
+        system_messages,
+        admin,
+        timeouts: config.timeouts,
+        shutdown: shutdown.clone(),
+        _in_flight: in_flight,
+    };
+
+    let app = Router::new()
+        .route("/api/click", post(handle_click))
+        .route("/v2/rpc/click", post(handle_session_click))
+        .route("/v2/rpc/session", post(handle_open_session))
+        .route("/v2/rpc/me/stats", get(handle_get_my_stats))
+        .route("/api/ownerships-by-batch", post(handle_get_ownerships_by_batch))
+        .route("/v2/rpc/ownerships-by-batch", post(handle_get_ownerships_by_batch))
+        .route("/v2/rpc/ownerships", get(handle_get_ownerships))
+        .route("/v2/rpc/leaderboard", get(handle_get_leaderboard))
+        .route("/v2/rpc/leaderboard/history/:country_id", get(handle_get_leaderboard_history))
+        .route("/v2/rpc/leaderboard/movers", get(handle_get_leaderboard_movers))
+        .route("/v2/rpc/countries/:country_id/territory", get(handle_get_country_territory))
+        .route("/v2/rpc/countries/:country_id/standing", get(handle_get_country_standing))
+        .route("/v2/rpc/invasions", get(handle_get_invasion_flows))
+        .route("/v2/rpc/heatmap", get(handle_get_heatmap))
+        .route("/healthz", get(handle_healthz))
+        .route("/readyz", get(handle_readyz))
+        .route("/v2/status", get(handle_status))
+        .route("/metrics", get(handle_metrics))
+        .route("/ws/listen", get(handle_ws_upgrade))
+        .route("/v2/ws/listen", get(handle_ws_upgrade))
+        .route("/admin/restrictions", get(handle_get_restrictions))
+        .route("/admin/:action", post(handle_admin_action))
+        .layer(cors_layer(&config.cors))
+        .layer(TraceLayer::new_for_http()
+            .make_span_with(|request: &Request<_>| {
+                tracing::info_span!(
+                    "http-request",
+                    method = ?request.method(),
+                    uri = ?request.uri(),
+                    version = ?request.version(),
+                )
+            })
+        )
+        .with_state(state);
+
+    // Unknown paths are routes of the single page app
+    let app = match webapp_dir {
+        Some(webapp_dir) => app.fallback_service(
+            ServeDir::new(webapp_dir).fallback(ServeFile::new(std::path::Path::new(webapp_dir).join("index.html")))
+        ),
+        None => app,
+    };
+
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
+    println!("Server listening on 0.0.0.0:{}", args.port);
+
+    // Clients are told apart by address when rate limited
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>());
+    let mut server_handle = tokio::spawn(server.with_graceful_shutdown(shutdown.clone().cancelled_owned()).into_future());
+    let mut update_service_handle = tokio::spawn({
+        let update_service = update_service.clone();
+        let shutdown = shutdown.clone();
+        async move { update_service.run(shutdown).await }
+    });
+
+    tokio::select! {
+        result = &mut server_handle => {
+            match result {
+                Ok(Err(e)) => {
+                    error!("Server error: {:?}", e);
+                    return Err(e.to_string().into());
+                }
+                Err(e) => return Err(e.to_string().into()),
+                Ok(Ok(())) => error!("Unexpected server exit"),
+            }
+        }
+        result = &mut update_service_handle => {
+            match result {
+                Ok(Err(e)) => {
+                    error!("Ownership update service error: {:?}", e);
+                    return Err(e.to_string().into());
+                }
+                Err(e) => return Err(e.to_string().into()),
+                Ok(Ok(())) => error!("Unexpected update service exit"),
+            }
+        }
+        _ = shutdown.cancelled() => {
+            info!("Draining the server");
+            drain(server_handle, update_service_handle, &mut in_flight_done, click_bus.as_ref(), config.timeouts.drain()).await;
+
+            if let Err(e) = invasion_flows.save_to_file(&args.invasion_flows_file) {
+                error!("Failed to persist invasion flows to {}: {}", args.invasion_flows_file, e);
+            }
+            if let Err(e) = player_stats.save_to_file(&args.player_stats_file) {
+                error!("Failed to persist player statistics to {}: {}", args.player_stats_file, e);
+            }
+
+            if config.snapshots_enabled() {
+                match write_snapshot(snapshot_store, &sequence_tracker, click_repository.as_ref()).await {
+                    Ok(path) => info!("Wrote final state snapshot {}", path.display()),
+                    Err(e) => error!("Failed to write final state snapshot: {}", e),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits, up to `timeout` each, for the requests in progress, the clicks they published and
+/// the clicks being applied.
+async fn drain(
+    server_handle: tokio::task::JoinHandle<std::io::Result<()>>,
+    update_service_handle: tokio::task::JoinHandle<Result<(), ConsumerError>>,
+    in_flight_done: &mut mpsc::Receiver<()>,
+    click_bus: &dyn ClickPublisher,
+    timeout: Duration,
+) {
+    let requests = async {
+        let _ = server_handle.await;
+        // Upgraded WebSockets outlive the server
+        in_flight_done.recv().await;
+    };
+    if tokio::time::timeout(timeout, requests).await.is_err() {
+        warn!("Requests still in progress after {:?}", timeout);
+    }
+
+    if let Err(e) = click_bus.flush().await {
+        error!("Failed to flush published clicks: {}", e);
+    }
+
+    if tokio::time::timeout(timeout, update_service_handle).await.is_err() {
+        warn!("Clicks still being applied after {:?}", timeout);
+    }
+
+    // Acknowledgments of the last clicks
+    if let Err(e) = click_bus.flush().await {
+        error!("Failed to flush click acknowledgments: {}", e);
+    }
+}
+
+/// Anonymous clicks of the original API, unless `game.anonymous_clicks` is off: sessions can be
+/// banned, anonymous clicks only by client address.
+async fn handle_click<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<ClickPayload>,
+) -> Result<Json<Value>, StatusCode> {
+    if !state.game.anonymous_clicks {
+        METRICS.clicks.with("unauthenticated").inc();
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    accept_click(&state, peer, &headers, payload, None).await
+}
+
+/// Clicks made in a session, for the country bound to it if any.
+async fn handle_session_click<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<ClickPayload>,
+) -> Result<Json<Value>, StatusCode> {
+    let session = state.sessions.authenticate(&headers, now_secs()).map_err(|e| {
+        METRICS.clicks.with("unauthenticated").inc();
+        debug!("Refused click: {}", e);
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    accept_click(&state, peer, &headers, payload, Some(session)).await
+}
+
+async fn accept_click<T: ClickRepository>(
+    state: &AppState<T>,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    payload: ClickPayload,
+    session: Option<Session>,
+) -> Result<Json<Value>, StatusCode> {
+    // Clients retry on another instance
+    if state.shutdown.is_cancelled() {
+        METRICS.clicks.with("unavailable").inc();
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let client = client_of(peer, headers, state.trust_forwarded_for);
+    if let Some(rate_limiter) = &state.rate_limiter {
+        if !rate_limiter.try_acquire(client, Instant::now()) {
+            METRICS.clicks.with("limited").inc();
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
+
+    let bound_country = session.as_ref().map_or("", |session| session.country_id.as_str());
+    let click_request = ClickRequest::decode(Bytes::from(payload.data))
+        .ok()
+        .map(|click_request| match click_request.country_id.is_empty() {
+            true => ClickRequest { country_id: bound_country.to_string(), ..click_request },
+            false => click_request,
+        })
+        .filter(|click_request| {
+            is_tile(click_request.tile_id.into(), state.tile_count)
+                // Clicks without a country would clear the tile
+                && !click_request.country_id.is_empty()
+                && state.game.allows_country(&click_request.country_id)
+        })
+        .ok_or_else(|| {
+            METRICS.clicks.with("invalid").inc();
+            StatusCode::BAD_REQUEST
+        })?;
+
+    if !bound_country.is_empty() && click_request.country_id != bound_country {
+        METRICS.clicks.with("forbidden").inc();
+        debug!("Refused click for {} in a session bound to {}", click_request.country_id, bound_country);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let session_id = session.map(|session| session.session_id).unwrap_or_default();
+    if let Some(reason) = state.restrictions.refusal(click_request.tile_id as u32, &click_request.country_id, client, &session_id) {
+        METRICS.clicks.with("forbidden").inc();
+        debug!("Refused click on tile {} from {}: {}", click_request.tile_id, client, reason);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    tokio::time::timeout(
+        state.timeouts.click(),
+        state.click_service.process_click(click_request, session_id)
+    )
+        .await
+        .map_err(|e| {
+            METRICS.clicks.with("failed").inc();
+            error!("Timeout error while clicking: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map_err(|e| {
+            METRICS.clicks.with("failed").inc();
+            error!("Error while processing click: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+
+    Ok(axum::Json(serde_json::json!({})))
+}
+
+/// Opens or renews a session, see [`SessionSigner::open`].
+async fn handle_open_session<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Json(payload): Json<ClickPayload>,
+) -> Result<Json<Value>, StatusCode> {
+    let session_request = SessionRequest::decode(Bytes::from(payload.data))
+        .ok()
+        .filter(|session_request| session_request.country_id.is_empty() || state.game.allows_country(&session_request.country_id))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let response = state.sessions.open(&session_request, now_secs());
+
+    Ok(Json(json!({
+        "data": encode(response.encode_to_vec()),
+    })))
+}
+
+async fn handle_get_ownerships<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+) -> Result<Json<Value>, StatusCode> {
+    let response = tokio::time::timeout(
+        state.timeouts.query(),
+        state.click_repository.get_ownerships(),
+    )
+        .await
+        .map_err(|e| {
+            error!("Timeout error while calling get_ownerships: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map_err(|e| {
+            error!("Error while processing get_ownerships: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut response_bytes = Vec::new();
+
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+async fn handle_get_ownerships_by_batch<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Json(payload): Json<BatchRequestPayload>,
+) -> Result<Json<Value>, StatusCode> {
+    let batch_request = clickplanet_proto::clicks::BatchRequest::decode(Bytes::from(payload.data))
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let response = tokio::time::timeout(
+        state.timeouts.query(),
+        state.click_repository.get_ownerships_by_batch(
+            batch_request.start_tile_id as u32,
+            batch_request.end_tile_id as u32,
+        ),
+    )
+        .await
+        .map_err(|e| {
+            error!("Timeout error while calling get_ownerships_by_batch: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map_err(|e| {
+            error!("Error while processing get_ownerships_by_batch: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut response_bytes = Vec::new();
+
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+
+async fn handle_ws_upgrade<T: ClickRepository+ 'static>(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState<T>>,
+    Query(query): Query<ListenQuery>,
+) -> impl IntoResponse {
+    let use_envelope = query.envelope.unwrap_or(false);
+    ws.on_upgrade(move |socket| handle_ws_connection(socket, state, use_envelope))
+}
+
+/// Counts a WebSocket client for as long as it is connected.
+struct ConnectedClient;
+
+impl ConnectedClient {
+    fn new() -> Self {
+        METRICS.websocket_connections.inc();
+        Self
+    }
+}
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        METRICS.websocket_connections.dec();
+    }
+}
+
+async fn handle_ws_connection<T: ClickRepository>(socket: WebSocket, state: AppState<T>, use_envelope: bool) {
+    let _connected = ConnectedClient::new();
+    let (sender, mut receiver) = socket.split();
+    let sender_arc = Arc::new(Mutex::new(sender));
+
+    let update_notification_subscription: Receiver<UpdateNotification> = state.update_notifification_broadcaster.subscribe();
+    let sender_arc_clone = sender_arc.clone();
+    let close_sender = sender_arc.clone();
+
+    let mut send_task = if use_envelope {
+        tokio::spawn(send_envelopes(
+            sender_arc,
+            update_notification_subscription,
+            state.leaderboard_publisher.clone(),
+            state.system_messages.subscribe(),
+        ))
+    } else {
+        tokio::spawn(send_update_notifications(sender_arc, update_notification_subscription))
+    };
+
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(Ok(message)) = receiver.next().await {
+            match message {
+                WebsocketMessage::Ping(payload) => {
+                    let mut sender = sender_arc_clone.lock().await;
+                    if let Err(e) = sender.send(WebsocketMessage::Pong(payload)).await {
+                        eprintln!("Error sending pong: {}", e);
+                        break;
+                    }
+                }
+                WebsocketMessage::Close(_) => break,
+                _ => {}
+            }
+        }
+    });
+
+    tokio::select! {
+        _ = &mut send_task => recv_task.abort(),
+        _ = &mut recv_task => send_task.abort(),
+        _ = state.shutdown.cancelled() => {
+            send_task.abort();
+            recv_task.abort();
+
+            // Tells clients to reconnect, to another instance
+            let close_frame = CloseFrame {
+                code: close_code::RESTART,
+                reason: "Server restarting, please reconnect".into(),
+            };
+            let mut sender = close_sender.lock().await;
+            if let Err(e) = sender.send(WebsocketMessage::Close(Some(close_frame))).await {
+                warn!("Error sending close frame: {}", e);
+            }
+        }
+    }
+}
+
+type WebSocketSender = Arc<Mutex<futures_util::stream::SplitSink<WebSocket, WebsocketMessage>>>;
+
+/// Legacy mode: bare `UpdateNotification` frames.
+async fn send_update_notifications(sender_arc: WebSocketSender, mut update_notification_subscription: Receiver<UpdateNotification>) {
+    loop {
+        let notification = match update_notification_subscription.recv().await {
+            Ok(notification) => notification,
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                METRICS.broadcast_lag_events.with("websocket").inc();
+                break;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let mut buf = Vec::new();
+        if notification.encode(&mut buf).is_ok() {
+            let mut sender = sender_arc.lock().await;
+            if let Err(e) = sender.send(WebsocketMessage::Binary(buf)).await {
+                eprintln!("Error sending WebSocket message: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Envelope mode: the full leaderboard first, then updates, leaderboard deltas, resync requests
+/// and system messages.
+async fn send_envelopes(
+    sender_arc: WebSocketSender,
+    mut update_notification_subscription: Receiver<UpdateNotification>,
+    leaderboard_publisher: Arc<LeaderboardPublisher>,
+    mut system_messages: Receiver<SystemMessage>,
+) {
+    let mut leaderboard_deltas = leaderboard_publisher.subscribe();
+
+    let initial = match leaderboard_publisher.leaderboard().await {
+        Ok(leaderboard) => Payload::Leaderboard(leaderboard),
+        Err(e) => {
+            error!("Error while fetching leaderboard for new listener: {:?}", e);
+            Payload::Resync(Resync { missed_messages: 0 })
+        }
+    };
+    let mut next_payload = Some(initial);
+
+    loop {
+        let payload = match next_payload.take() {
+            Some(payload) => payload,
+            None => tokio::select! {
+                update = update_notification_subscription.recv() => match update {
+                    Ok(notification) => Payload::Update(notification),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        METRICS.broadcast_lag_events.with("websocket").inc();
+                        Payload::Resync(Resync { missed_messages: missed })
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                delta = leaderboard_deltas.recv() => match delta {
+                    Ok(delta) => Payload::LeaderboardDelta(delta),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        METRICS.broadcast_lag_events.with("websocket_leaderboard").inc();
+                        Payload::Resync(Resync { missed_messages: missed })
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                message = system_messages.recv() => match message {
+                    Ok(message) => Payload::SystemMessage(message),
+                    // Announcements are rare, and not worth a resync
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            },
+        };
+
+        let buf = envelope(payload).encode_to_vec();
+        let mut sender = sender_arc.lock().await;
+        if let Err(e) = sender.send(WebsocketMessage::Binary(buf)).await {
+            eprintln!("Error sending WebSocket message: {}", e);
+            break;
+        }
+    }
+}
+
+async fn handle_get_leaderboard<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Query(params): Query<LeaderboardParams>,
+) -> Result<Json<Value>, StatusCode> {
+    let query = match params.around {
+        Some(country_id) => LeaderboardQuery::Around {
+            country_id: country_id.to_lowercase(),
+            radius: params.radius.unwrap_or(5),
+        },
+        None => LeaderboardQuery::Page {
+            offset: params.offset.unwrap_or(0),
+            limit: params.limit,
+        },
+    };
+
+    let mut response = state.leaderboard_index
+        .query(&query)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if let Some(scores) = &state.home_control_scores {
+        for entry in response.entries.iter_mut() {
+            entry.home_control_ratio = scores.home_control_ratio(&entry.country_id);
+        }
+    }
+
+    let mut response_bytes = Vec::new();
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+async fn handle_get_country_territory<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Path(country_id): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let analyzer = state.territory_analyzer
+        .as_ref()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let response: TerritoryResponse = analyzer.territory_stats(&country_id.to_lowercase()).into();
+
+    let mut response_bytes = Vec::new();
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+async fn handle_get_country_standing<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Path(country_id): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let scores = state.home_control_scores
+        .as_ref()
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let response = scores.standing(&country_id.to_lowercase());
+
+    let mut response_bytes = Vec::new();
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+async fn handle_get_invasion_flows<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Query(query): Query<TopInWindowQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let window: FlowWindow = query.window
+        .as_deref()
+        .unwrap_or("1h")
+        .parse()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let response = state.invasion_flows.flows(window, query.top.unwrap_or(10));
+
+    let mut response_bytes = Vec::new();
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+async fn service_status<T: ClickRepository>(state: &AppState<T>) -> ServiceStatus {
+    ServiceStatus {
+        tile_count: Some(state.tile_count),
+        websocket_clients: Some(METRICS.websocket_connections.get().max(0) as usize),
+        ..state.health.status().await
+    }
+}
+
+async fn handle_readyz<T: ClickRepository>(State(state): State<AppState<T>>) -> impl IntoResponse {
+    readiness(service_status(&state).await)
+}
+
+async fn handle_status<T: ClickRepository>(State(state): State<AppState<T>>) -> Json<ServiceStatus> {
+    Json(service_status(&state).await)
+}
+
+async fn handle_get_heatmap<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Query(query): Query<HeatmapQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let window: FlowWindow = query.window
+        .as_deref()
+        .unwrap_or("1h")
+        .parse()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let response = state.activity_heatmap.snapshot(window);
+
+    let mut response_bytes = Vec::new();
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+async fn handle_get_leaderboard_history<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Path(country_id): Path<String>,
+    Query(query): Query<TimeRangeQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let to = query.to.unwrap_or_else(now_secs);
+    let from = query.from.unwrap_or(to.saturating_sub(24 * 60 * 60));
+    if from > to {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let response = state.leaderboard_history
+        .country_series(&country_id.to_lowercase(), from, to)
+        .await
+        .map_err(|e| {
+            error!("Error while fetching leaderboard history: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut response_bytes = Vec::new();
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+async fn handle_get_leaderboard_movers<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    Query(query): Query<TopInWindowQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let window: FlowWindow = query.window
+        .as_deref()
+        .unwrap_or("1h")
+        .parse()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let response = state.leaderboard_history
+        .movers(Duration::from_secs(window.duration_secs()), query.top.unwrap_or(10))
+        .await
+        .map_err(|e| {
+            error!("Error while computing leaderboard movers: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut response_bytes = Vec::new();
+    response
+        .encode(&mut response_bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let base64_data = encode(&response_bytes);
+
+    let payload = json!({
+        "data": base64_data,
+    });
+
+    Ok(axum::Json(payload))
+}
+
+fn admin_error_response(e: AdminError) -> (StatusCode, Json<Value>) {
+    let status = match &e {
+        AdminError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        AdminError::UnknownAction(_) => StatusCode::NOT_FOUND,
+        AdminError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        AdminError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        AdminError::Storage(_) | AdminError::ClickBus(_) | AdminError::Restrictions(_) => {
+            error!("Admin action failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, Json(json!({ "error": e.to_string() })))
+}
+
+async fn handle_admin_action<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Path(action): Path<String>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let admin = state.admin.as_ref().ok_or((StatusCode::NOT_FOUND, Json(json!({}))))?;
+    let request = AdminRequest {
+        method,
+        path: uri.path(),
+        headers: &headers,
+        body: &body,
+        client: client_of(peer, &headers, state.trust_forwarded_for),
+    };
+
+    admin.handle(&action, &request).await.map(Json).map_err(admin_error_response)
+}
+
+async fn handle_get_restrictions<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let admin = state.admin.as_ref().ok_or((StatusCode::NOT_FOUND, Json(json!({}))))?;
+    let request = AdminRequest {
+        method,
+        path: uri.path(),
+        headers: &headers,
+        body: &[],
+        client: client_of(peer, &headers, state.trust_forwarded_for),
+    };
+
+    admin.authenticate(&request).map_err(admin_error_response)?;
+    Ok(Json(json!(state.restrictions.current())))
+}
+
+/// Statistics of the session of the bearer token.
+async fn handle_get_my_stats<T: ClickRepository>(
+    State(state): State<AppState<T>>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let session = state.sessions.authenticate(&headers, now_secs()).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let response = state.player_stats.stats(&session.session_id);
+
+    Ok(Json(json!({
+        "data": encode(response.encode_to_vec()),
+    })))
+}
@@ -0,0 +1,163 @@
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use clickplanet_proto::clicks::{SessionRequest, SessionResponse};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::SessionConfig;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SessionError {
+    #[error("Missing session token")]
+    Missing,
+    #[error("Malformed session token")]
+    Malformed,
+    #[error("Invalid session token signature")]
+    InvalidSignature,
+    #[error("Expired session token")]
+    Expired,
+}
+
+/// What a session token vouches for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    #[serde(rename = "sid")]
+    pub session_id: String,
+    /// Country the clicks of the session are for, any when empty
+    #[serde(rename = "country")]
+    pub country_id: String,
+    /// Unix time the token was issued or renewed at
+    #[serde(rename = "iat")]
+    pub issued_at_secs: u64,
+}
+
+/// Issues and checks the session tokens, `<claims>.<signature>` where the claims are the JSON
+/// of a [`Session`] and the signature their HMAC-SHA256, both in unpadded base64url.
+pub struct SessionSigner {
+    key: Vec<u8>,
+    ttl_secs: u64,
+}
+
+impl SessionSigner {
+    pub fn new(config: &SessionConfig) -> Self {
+        let key = match &config.signing_key {
+            Some(signing_key) => signing_key.as_bytes().to_vec(),
+            None => {
+                warn!("No session.signing_key set, sessions will not survive a restart nor be shared with other servers");
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+
+        Self { key, ttl_secs: config.ttl_secs }
+    }
+
+    fn mac(&self, claims: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(claims.as_bytes());
+        mac
+    }
+
+    pub fn issue(&self, session: &Session) -> SessionResponse {
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(session).expect("sessions serialize"));
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&claims).finalize().into_bytes());
+
+        SessionResponse {
+            token: format!("{}.{}", claims, signature),
+            session_id: session.session_id.clone(),
+            country_id: session.country_id.clone(),
+            expires_at_secs: session.issued_at_secs + self.ttl_secs,
+        }
+    }
+
+    pub fn verify(&self, token: &str, now_secs: u64) -> Result<Session, SessionError> {
+        let (claims, signature) = token.split_once('.').ok_or(SessionError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| SessionError::Malformed)?;
+        self.mac(claims).verify_slice(&signature).map_err(|_| SessionError::InvalidSignature)?;
+
+        let session: Session = URL_SAFE_NO_PAD
+            .decode(claims)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(SessionError::Malformed)?;
+        if now_secs >= session.issued_at_secs.saturating_add(self.ttl_secs) {
+            return Err(SessionError::Expired);
+        }
+
+        Ok(session)
+    }
+
+    /// The session of the `Authorization: Bearer <token>` header.
+    pub fn authenticate(&self, headers: &HeaderMap, now_secs: u64) -> Result<Session, SessionError> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(SessionError::Missing)?;
+        self.verify(token, now_secs)
+    }
+
+    /// Renews the session of a still valid token, bound to the requested country, or else
+    /// opens a new one.
+    pub fn open(&self, request: &SessionRequest, now_secs: u64) -> SessionResponse {
+        let session_id = self
+            .verify(&request.token, now_secs)
+            .map(|session| session.session_id)
+            .unwrap_or_else(|_| Uuid::new_v4().to_string());
+
+        self.issue(&Session {
+            session_id,
+            country_id: request.country_id.clone(),
+            issued_at_secs: now_secs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer(signing_key: &str) -> SessionSigner {
+        SessionSigner::new(&SessionConfig { signing_key: Some(signing_key.to_string()), ttl_secs: 100 })
+    }
+
+    #[test]
+    fn test_tokens_are_signed_and_expire() {
+        let signer = signer("a-long-enough-session-key");
+        let opened = signer.open(&SessionRequest { token: String::new(), country_id: "fr".to_string() }, 1000);
+        assert_eq!(opened.expires_at_secs, 1100);
+
+        let session = signer.verify(&opened.token, 1099).unwrap();
+        assert_eq!((session.session_id.as_str(), session.country_id.as_str()), (opened.session_id.as_str(), "fr"));
+        assert_eq!(signer.verify(&opened.token, 1100), Err(SessionError::Expired));
+
+        // Another key, or claims edited to switch country, break the signature
+        assert_eq!(self::signer("another-session-signing-key").verify(&opened.token, 1000), Err(SessionError::InvalidSignature));
+        let (_, signature) = opened.token.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&Session { country_id: "de".to_string(), ..session }).unwrap());
+        assert_eq!(signer.verify(&format!("{}.{}", forged, signature), 1000), Err(SessionError::InvalidSignature));
+        assert_eq!(signer.verify("not a token", 1000), Err(SessionError::Malformed));
+    }
+
+    #[test]
+    fn test_renewal_keeps_the_session() {
+        let signer = signer("a-long-enough-session-key");
+        let opened = signer.open(&SessionRequest { token: String::new(), country_id: "fr".to_string() }, 1000);
+
+        let renewed = signer.open(&SessionRequest { token: opened.token.clone(), country_id: "de".to_string() }, 1050);
+        assert_eq!((renewed.session_id.as_str(), renewed.country_id.as_str()), (opened.session_id.as_str(), "de"));
+        assert_eq!(renewed.expires_at_secs, 1150);
+
+        // Expired sessions are not renewed
+        let reopened = signer.open(&SessionRequest { token: opened.token, country_id: "fr".to_string() }, 1200);
+        assert_ne!(reopened.session_id, opened.session_id);
+    }
+}
@@ -0,0 +1,20 @@
+use crate::config::{override_with, Config};
+
+/// Connections used by both the API and the persister.
+#[derive(clap::Args, Debug, Clone)]
+pub struct SharedArgs {
+    /// Overrides `bus.nats_url`
+    #[arg(long, global = true, env = "NATS_URL")]
+    pub nats_url: Option<String>,
+
+    /// Overrides `storage.redis_url`
+    #[arg(long, global = true, env = "REDIS_URL")]
+    pub redis_url: Option<String>,
+}
+
+impl SharedArgs {
+    pub fn apply(&self, config: &mut Config) {
+        override_with(&mut config.bus.nats_url, &self.nats_url);
+        override_with(&mut config.storage.redis_url, &self.redis_url);
+    }
+}
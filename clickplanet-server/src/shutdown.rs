@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// How long in-flight requests and clicks are waited for once shutting down by default, see
+/// `timeouts.drain_secs`.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Token cancelled on the first SIGINT or SIGTERM. A second signal exits right away.
+pub fn on_signals() -> CancellationToken {
+    let shutdown = CancellationToken::new();
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            wait_for_signal().await;
+            info!("Shutting down, send the signal again to exit now");
+            shutdown.cancel();
+
+            wait_for_signal().await;
+            error!("Exiting without draining");
+            std::process::exit(1);
+        }
+    });
+
+    shutdown
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Cannot listen to SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
@@ -1,68 +1,48 @@
-use std::time::Duration;
+use std::sync::Arc;
 use clap::Parser;
-use tracing::info;
-use crate::redis_click_persistence::{RedisClickRepository};
 
-mod jetstream_click_streamer;
-mod nats_commons;
-mod telemetry;
-mod redis_click_persistence;
-mod click_persistence;
-mod in_memory_click_persistence;
+use clickplanet_telemetry::{init_telemetry, TelemetryArgs};
 
-use crate::nats_commons::ConsumerConfig;
-use crate::jetstream_click_streamer::{ClickConsumer};
-use crate::telemetry::{init_telemetry, TelemetryConfig};
+use clickplanet_server::{persister, shutdown};
+use clickplanet_server::config::ConfigArgs;
+use clickplanet_server::jetstream_click_bus::JetStreamClickBus;
+use clickplanet_server::persister::PersisterArgs;
+use clickplanet_server::shared_args::SharedArgs;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(long, env = "NATS_URL", default_value = "nats://localhost:4222")]
-    nats_url: String,
+    #[command(flatten)]
+    config: ConfigArgs,
 
-    #[arg(long, env = "REDIS_URL", default_value = "redis://localhost:6379")]
-    redis_url: String,
+    #[command(flatten)]
+    shared: SharedArgs,
 
-    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT", default_value = "http://localhost:4317")]
-    otlp_endpoint: String,
+    #[command(flatten)]
+    persister: PersisterArgs,
 
-    #[arg(long, env = "SERVICE_NAME", default_value = "click-persister")]
-    service_name: String,
-
-    #[arg(long, env = "CONCURRENT_PROCESSORS", default_value = "8")]
-    concurrent_processors: u16,
-
-    #[arg(long, env = "ACK_WAIT_SECS", default_value = "10")]
-    ack_wait_secs: u64,
+    #[command(flatten)]
+    telemetry: TelemetryArgs,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-
-    let telemetry_config = TelemetryConfig {
-        otlp_endpoint: args.otlp_endpoint,
-        service_name: args.service_name,
-    };
-
-    init_telemetry(telemetry_config).await?;
-
-    let click_persister = RedisClickRepository::new(&args.redis_url).await?;
-
-    let consumer = ClickConsumer::new(
-        &args.nats_url,
-        Some(ConsumerConfig {
-            concurrent_processors: args.concurrent_processors as usize,
-            ack_wait: Duration::from_secs(args.ack_wait_secs),
-            ..Default::default()
-        }),
-        click_persister
-    )
-        .await?;
-
-    info!("Starting click consumer...");
-    consumer.run().await?;
-
-    Ok(())
+    let config = args.config.load(|config| {
+        args.shared.apply(config);
+        args.persister.apply(config);
+    })?;
+    if args.config.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+
+    let telemetry = init_telemetry(&args.telemetry, "click-persister")?;
+    let shutdown = shutdown::on_signals();
+
+    let click_bus = Arc::new(JetStreamClickBus::connect(&config.bus.nats_url, config.stream.retention()).await?);
+    let result = persister::run(&config, click_bus, shutdown).await;
+
+    telemetry.shutdown().await;
+    result
 }
-
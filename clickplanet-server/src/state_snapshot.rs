@@ -0,0 +1,336 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clickplanet_proto::clicks::{LeaderboardEntry, StateSnapshot};
+use prost::Message;
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::click_persistence::{ClickRepository, ClickRepositoryError, LeaderboardError};
+use crate::leaderboard_history::rank_scores;
+use crate::metrics::METRICS;
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_EXTENSION: &str = "pb";
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Snapshot file error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to decode snapshot: {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error(transparent)]
+    Repository(#[from] ClickRepositoryError),
+    #[error(transparent)]
+    Leaderboard(#[from] LeaderboardError),
+    #[error("Snapshot task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+#[derive(Default)]
+struct TrackedSequences {
+    in_flight: BTreeSet<u64>,
+    max_completed: u64,
+}
+
+/// Follows the click bus deliveries being applied concurrently, to know the sequence up to
+/// which every click is reflected in the state.
+#[derive(Default)]
+pub struct SequenceTracker {
+    sequences: Mutex<TrackedSequences>,
+}
+
+impl SequenceTracker {
+    /// Tracker of a state already containing every click up to `sequence`.
+    pub fn starting_at(sequence: u64) -> Self {
+        Self {
+            sequences: Mutex::new(TrackedSequences {
+                in_flight: BTreeSet::new(),
+                max_completed: sequence,
+            }),
+        }
+    }
+
+    pub fn start(&self, sequence: u64) {
+        self.sequences.lock().unwrap().in_flight.insert(sequence);
+    }
+
+    pub fn complete(&self, sequence: u64) {
+        let mut sequences = self.sequences.lock().unwrap();
+        sequences.in_flight.remove(&sequence);
+        sequences.max_completed = sequences.max_completed.max(sequence);
+    }
+
+    /// Deliveries come in sequence order, so everything before the oldest one still being
+    /// applied is done.
+    pub fn applied(&self) -> u64 {
+        let sequences = self.sequences.lock().unwrap();
+        match sequences.in_flight.first() {
+            Some(oldest) => oldest.saturating_sub(1),
+            None => sequences.max_completed,
+        }
+    }
+}
+
+/// Reads the state to snapshot. The sequence is taken first: clicks applied while the
+/// tiles are read are replayed on restart, which the timestamp checks make harmless. The
+/// leaderboard is counted from the tiles read, so that both agree.
+pub async fn capture(
+    tracker: &SequenceTracker,
+    click_repository: &dyn ClickRepository,
+    timestamp_ns: u64,
+) -> Result<StateSnapshot, SnapshotError> {
+    let stream_sequence = tracker.applied();
+    let ownerships = click_repository.get_ownerships().await?;
+
+    let mut scores: HashMap<String, u32> = HashMap::new();
+    for ownership in ownerships.ownerships.iter().filter(|ownership| !ownership.country_id.is_empty()) {
+        *scores.entry(ownership.country_id.clone()).or_default() += 1;
+    }
+
+    Ok(StateSnapshot {
+        timestamp_ns,
+        stream_sequence,
+        ownerships: Some(ownerships),
+        leaderboard: leaderboard_entries(&scores),
+    })
+}
+
+pub fn leaderboard_entries(scores: &HashMap<String, u32>) -> Vec<LeaderboardEntry> {
+    let mut entries: Vec<LeaderboardEntry> = rank_scores(scores)
+        .into_iter()
+        .map(|(country_id, rank)| LeaderboardEntry {
+            score: scores[&country_id],
+            country_id,
+            rank,
+            ..Default::default()
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.rank);
+    entries
+}
+
+/// Snapshots kept as `snapshot-<timestamp_ns>.pb` files in a directory, newest last.
+pub struct SnapshotStore {
+    directory: PathBuf,
+    keep: usize,
+}
+
+impl SnapshotStore {
+    /// Keeps the `keep` newest snapshots of `directory`, at least one.
+    pub fn new(directory: impl Into<PathBuf>, keep: usize) -> Self {
+        Self {
+            directory: directory.into(),
+            keep: keep.max(1),
+        }
+    }
+
+    pub fn save(&self, snapshot: &StateSnapshot) -> Result<PathBuf, SnapshotError> {
+        std::fs::create_dir_all(&self.directory)?;
+
+        // Zero padded so that names sort like timestamps
+        let path = self.directory.join(format!("{}{:020}.{}", SNAPSHOT_PREFIX, snapshot.timestamp_ns, SNAPSHOT_EXTENSION));
+        let tmp_path = path.with_extension("tmp");
+        let bytes = snapshot.encode_to_vec();
+        METRICS.snapshot_bytes.observe(bytes.len() as f64);
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        let snapshots = self.list()?;
+        for outdated in &snapshots[..snapshots.len().saturating_sub(self.keep)] {
+            std::fs::remove_file(outdated)?;
+        }
+
+        Ok(path)
+    }
+
+    /// The newest readable snapshot, skipping corrupted files.
+    pub fn load_latest(&self) -> Result<Option<StateSnapshot>, SnapshotError> {
+        for path in self.list()?.iter().rev() {
+            match read_snapshot(path) {
+                Ok(snapshot) => return Ok(Some(snapshot)),
+                Err(e) => warn!("Ignoring snapshot {}: {}", path.display(), e),
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>, SnapshotError> {
+        let entries = match std::fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let is_snapshot = path.extension().is_some_and(|extension| extension == SNAPSHOT_EXTENSION)
+                && path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(SNAPSHOT_PREFIX));
+            if is_snapshot {
+                paths.push(path);
+            }
+        }
+
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+fn read_snapshot(path: &Path) -> Result<StateSnapshot, SnapshotError> {
+    Ok(StateSnapshot::decode(std::fs::read(path)?.as_slice())?)
+}
+
+/// Captures the state and saves it off the async runtime.
+/// Captures the state and saves it off the async runtime.
+pub async fn write_snapshot(
+    store: Arc<SnapshotStore>,
+    tracker: &SequenceTracker,
+    click_repository: &dyn ClickRepository,
+) -> Result<PathBuf, SnapshotError> {
+    let snapshot = capture(tracker, click_repository, now_ns()).await?;
+    tokio::task::spawn_blocking(move || store.save(&snapshot)).await?
+}
+
+/// Writes a snapshot of the in-memory state every `interval`.
+pub async fn snapshot_periodically(
+    store: Arc<SnapshotStore>,
+    tracker: Arc<SequenceTracker>,
+    click_repository: Arc<dyn ClickRepository>,
+    interval: Duration,
+) {
+    info!("Writing state snapshots every {:?}", interval);
+    let mut ticker = tokio::time::interval(interval);
+    // Right after startup the state is the one just loaded
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        match write_snapshot(store.clone(), &tracker, click_repository.as_ref()).await {
+            Ok(path) => info!("Wrote state snapshot {}", path.display()),
+            Err(e) => error!("Failed to write state snapshot: {}", e),
+        }
+    }
+}
+
+pub fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickplanet_proto::clicks::{Click, Ownership, OwnershipState};
+    use crate::click_persistence::{LeaderboardMaintainer, LeaderboardRepository};
+    use crate::in_memory_click_persistence::PapayaClickRepository;
+
+    fn snapshot(timestamp_ns: u64, stream_sequence: u64) -> StateSnapshot {
+        StateSnapshot {
+            timestamp_ns,
+            stream_sequence,
+            ownerships: Some(OwnershipState {
+                ownerships: vec![Ownership {
+                    tile_id: 1,
+                    country_id: "fr".to_string(),
+                    timestamp_ns,
+                }],
+            }),
+            leaderboard: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_sequence_tracker() {
+        let tracker = SequenceTracker::starting_at(10);
+        assert_eq!(tracker.applied(), 10);
+
+        tracker.start(11);
+        tracker.start(12);
+        tracker.start(13);
+        tracker.complete(12);
+        assert_eq!(tracker.applied(), 10);
+
+        tracker.complete(11);
+        assert_eq!(tracker.applied(), 12);
+
+        tracker.complete(13);
+        assert_eq!(tracker.applied(), 13);
+    }
+
+    #[test]
+    fn test_store_keeps_newest_snapshots() {
+        let directory = std::env::temp_dir().join(format!("snapshots-{}", uuid::Uuid::new_v4()));
+        let store = SnapshotStore::new(&directory, 2);
+        assert_eq!(store.load_latest().unwrap(), None);
+
+        for (timestamp_ns, sequence) in [(100, 1), (300, 3), (200, 2)] {
+            store.save(&snapshot(timestamp_ns, sequence)).unwrap();
+        }
+
+        assert_eq!(store.list().unwrap().len(), 2);
+        assert_eq!(store.load_latest().unwrap().unwrap().stream_sequence, 3);
+
+        // A corrupted newest snapshot falls back to the previous one
+        std::fs::write(directory.join("snapshot-00000000000000000400.pb"), b"\xff\xff").unwrap();
+        assert_eq!(store.load_latest().unwrap().unwrap().stream_sequence, 3);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    async fn click(repository: &PapayaClickRepository, tile_id: u32, country_id: &str, timestamp_ns: u64) {
+        let previous = repository.save_click(tile_id, &Click {
+            tile_id: tile_id as i32,
+            country_id: country_id.to_string(),
+            timestamp_ns,
+            click_id: String::new(),
+            session_id: String::new(),
+        }).await.unwrap();
+        let previous_country = previous.as_ref().map(|ownership| ownership.country_id.as_str());
+        repository.update_country_index(tile_id, country_id, previous_country).await;
+    }
+
+    fn scores(snapshot: &StateSnapshot) -> Vec<(&str, u32, u32)> {
+        snapshot.leaderboard
+            .iter()
+            .map(|entry| (entry.country_id.as_str(), entry.score, entry.rank))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let repository = PapayaClickRepository::new();
+        for (tile_id, country_id) in [(1, "fr"), (2, "fr"), (3, "de")] {
+            click(&repository, tile_id, country_id, 10).await;
+        }
+
+        let tracker = SequenceTracker::starting_at(5);
+        tracker.start(6);
+        let snapshot = capture(&tracker, &repository, 42).await.unwrap();
+
+        assert_eq!(snapshot.stream_sequence, 5);
+        assert_eq!(snapshot.ownerships.as_ref().unwrap().ownerships.len(), 3);
+        assert_eq!(scores(&snapshot), vec![("fr", 2, 1), ("de", 1, 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_capture_after_tiles_change_owner() {
+        let repository = PapayaClickRepository::new();
+        for (tile_id, country_id) in [(1, "fr"), (2, "fr"), (3, "de")] {
+            click(&repository, tile_id, country_id, 10).await;
+        }
+        click(&repository, 1, "de", 20).await;
+        click(&repository, 3, "", 20).await;
+
+        let snapshot = capture(&SequenceTracker::starting_at(5), &repository, 42).await.unwrap();
+
+        assert_eq!(scores(&snapshot), vec![("de", 1, 1), ("fr", 1, 2)]);
+        assert_eq!(repository.leaderboard().await.unwrap(), HashMap::from([("de".to_string(), 1), ("fr".to_string(), 1)]));
+    }
+}
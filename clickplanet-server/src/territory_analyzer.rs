@@ -0,0 +1,281 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use clickplanet_proto::clicks::{OwnershipState, TerritoryResponse, UpdateNotification};
+use clickplanet_topology::TileTopology;
+
+use crate::click_persistence::{ClickRepository, ClickRepositoryError};
+use crate::ownership_listener::{resync, OwnershipListener};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerritoryStats {
+    pub country_id: String,
+    pub tile_count: usize,
+    /// Sizes of the connected territories, largest first
+    pub territory_sizes: Vec<usize>,
+    pub largest_territory: Vec<u32>,
+    /// Owned tiles touching a tile owned by another country
+    pub frontier_tiles: Vec<u32>,
+}
+
+impl From<TerritoryStats> for TerritoryResponse {
+    fn from(stats: TerritoryStats) -> Self {
+        TerritoryResponse {
+            country_id: stats.country_id,
+            tile_count: stats.tile_count as u32,
+            territory_count: stats.territory_sizes.len() as u32,
+            largest_territory_size: stats.largest_territory.len() as u32,
+            territory_sizes: stats.territory_sizes.into_iter().map(|size| size as u32).collect(),
+            largest_territory_tile_ids: stats.largest_territory,
+            frontier_tile_ids: stats.frontier_tiles,
+        }
+    }
+}
+
+#[derive(Default)]
+struct CountryTerritory {
+    tiles: HashSet<u32>,
+    frontier: HashSet<u32>,
+    // Connected components, recomputed lazily after the country gained or lost a tile
+    territories: Option<Vec<Vec<u32>>>,
+}
+
+#[derive(Default)]
+struct TerritoryState {
+    owners: HashMap<u32, String>,
+    countries: HashMap<String, CountryTerritory>,
+}
+
+/// Keeps per-country contiguity and frontier information up to date from ownership updates.
+///
+/// Frontiers are maintained incrementally (a capture only changes the frontier status of the
+/// captured tile and its neighbours); connected territories are cached per country and only
+/// recomputed for countries whose tiles changed since the last query.
+pub struct TerritoryAnalyzer {
+    topology: Arc<TileTopology>,
+    state: Mutex<TerritoryState>,
+}
+
+impl TerritoryAnalyzer {
+    pub fn new(topology: Arc<TileTopology>) -> Self {
+        Self {
+            topology,
+            state: Mutex::new(TerritoryState::default()),
+        }
+    }
+
+    pub async fn populate_with(
+        topology: Arc<TileTopology>,
+        repository: Arc<dyn ClickRepository>,
+    ) -> Result<Self, ClickRepositoryError> {
+        let analyzer = Self::new(topology);
+        resync(&analyzer, repository.as_ref()).await?;
+        Ok(analyzer)
+    }
+
+    fn refresh_frontier(topology: &TileTopology, state: &mut TerritoryState, tile_id: u32) {
+        let Some(owner) = state.owners.get(&tile_id) else {
+            return;
+        };
+
+        let is_frontier = topology.neighbours(tile_id).iter().any(|neighbour| {
+            state.owners
+                .get(neighbour)
+                .is_some_and(|neighbour_owner| neighbour_owner != owner)
+        });
+
+        if let Some(territory) = state.countries.get_mut(owner) {
+            if is_frontier {
+                territory.frontier.insert(tile_id);
+            } else {
+                territory.frontier.remove(&tile_id);
+            }
+        }
+    }
+
+    pub fn territory_stats(&self, country_id: &str) -> TerritoryStats {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(territory) = state.countries.get_mut(country_id) else {
+            return TerritoryStats {
+                country_id: country_id.to_string(),
+                tile_count: 0,
+                territory_sizes: Vec::new(),
+                largest_territory: Vec::new(),
+                frontier_tiles: Vec::new(),
+            };
+        };
+
+        let topology = &self.topology;
+        let territories = territory.territories
+            .get_or_insert_with(|| topology.components_within(&territory.tiles));
+
+        let mut frontier_tiles: Vec<u32> = territory.frontier.iter().copied().collect();
+        frontier_tiles.sort_unstable();
+
+        TerritoryStats {
+            country_id: country_id.to_string(),
+            tile_count: territory.tiles.len(),
+            territory_sizes: territories.iter().map(|component| component.len()).collect(),
+            largest_territory: territories.first().cloned().unwrap_or_default(),
+            frontier_tiles,
+        }
+    }
+}
+
+impl OwnershipListener for TerritoryAnalyzer {
+    fn name(&self) -> &'static str {
+        "territory analyzer"
+    }
+
+    fn load(&self, ownership_state: OwnershipState) {
+        let mut state = TerritoryState::default();
+
+        for ownership in ownership_state.ownerships {
+            state.countries
+                .entry(ownership.country_id.clone())
+                .or_default()
+                .tiles
+                .insert(ownership.tile_id);
+            state.owners.insert(ownership.tile_id, ownership.country_id);
+        }
+
+        let tile_ids: Vec<u32> = state.owners.keys().copied().collect();
+        for tile_id in tile_ids {
+            Self::refresh_frontier(&self.topology, &mut state, tile_id);
+        }
+
+        *self.state.lock().unwrap() = state;
+    }
+
+    fn apply_update(&self, update: &UpdateNotification) {
+        let tile_id = update.tile_id as u32;
+        let mut state = self.state.lock().unwrap();
+
+        let previous = if update.country_id.is_empty() {
+            state.owners.remove(&tile_id)
+        } else {
+            state.owners.insert(tile_id, update.country_id.clone())
+        };
+        if previous.as_deref().unwrap_or_default() == update.country_id {
+            return;
+        }
+
+        if let Some(previous_country) = previous {
+            if let Some(territory) = state.countries.get_mut(&previous_country) {
+                territory.tiles.remove(&tile_id);
+                territory.frontier.remove(&tile_id);
+                territory.territories = None;
+
+                if territory.tiles.is_empty() {
+                    state.countries.remove(&previous_country);
+                }
+            }
+        }
+
+        if !update.country_id.is_empty() {
+            let territory = state.countries.entry(update.country_id.clone()).or_default();
+            territory.tiles.insert(tile_id);
+            territory.territories = None;
+        }
+
+        Self::refresh_frontier(&self.topology, &mut state, tile_id);
+        for &neighbour in self.topology.neighbours(tile_id) {
+            Self::refresh_frontier(&self.topology, &mut state, neighbour);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clickplanet_proto::clicks::Ownership;
+    use clickplanet_topology::TopologyBuilder;
+    use std::f64::consts::PI;
+
+    // Tiles 0..12 on a ring: each tile touches tile_id - 1 and tile_id + 1 (mod 12)
+    fn ring_topology() -> Arc<TileTopology> {
+        let positions: Vec<f64> = (0..12)
+            .flat_map(|i| {
+                let angle = 2.0 * PI * i as f64 / 12.0;
+                [angle.cos(), angle.sin(), 0.0]
+            })
+            .collect();
+
+        Arc::new(TopologyBuilder::new().build(&positions).unwrap())
+    }
+
+    fn update(tile_id: i32, country_id: &str, previous_country_id: &str) -> UpdateNotification {
+        UpdateNotification {
+            tile_id,
+            country_id: country_id.to_string(),
+            previous_country_id: previous_country_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_territories_and_frontier() {
+        let analyzer = TerritoryAnalyzer::new(ring_topology());
+
+        for tile_id in [0, 1, 2, 5, 6] {
+            analyzer.apply_update(&update(tile_id, "fr", ""));
+        }
+        analyzer.apply_update(&update(3, "de", ""));
+
+        let stats = analyzer.territory_stats("fr");
+        assert_eq!(stats.tile_count, 5);
+        assert_eq!(stats.territory_sizes, vec![3, 2]);
+        assert_eq!(stats.largest_territory, vec![0, 1, 2]);
+        assert_eq!(stats.frontier_tiles, vec![2]);
+
+        let stats = analyzer.territory_stats("de");
+        assert_eq!(stats.territory_sizes, vec![1]);
+        assert_eq!(stats.frontier_tiles, vec![3]);
+    }
+
+    #[test]
+    fn test_capture_splits_territory() {
+        let analyzer = TerritoryAnalyzer::new(ring_topology());
+
+        for tile_id in 0..5 {
+            analyzer.apply_update(&update(tile_id, "fr", ""));
+        }
+        assert_eq!(analyzer.territory_stats("fr").territory_sizes, vec![5]);
+
+        analyzer.apply_update(&update(2, "de", "fr"));
+
+        let stats = analyzer.territory_stats("fr");
+        assert_eq!(stats.tile_count, 4);
+        assert_eq!(stats.territory_sizes, vec![2, 2]);
+        assert_eq!(stats.frontier_tiles, vec![1, 3]);
+        assert_eq!(analyzer.territory_stats("de").frontier_tiles, vec![2]);
+
+        // Taking the tile back heals the territory and clears the frontier
+        analyzer.apply_update(&update(2, "fr", "de"));
+
+        let stats = analyzer.territory_stats("fr");
+        assert_eq!(stats.territory_sizes, vec![5]);
+        assert!(stats.frontier_tiles.is_empty());
+        assert_eq!(analyzer.territory_stats("de").tile_count, 0);
+    }
+
+    #[test]
+    fn test_load_from_ownerships() {
+        let analyzer = TerritoryAnalyzer::new(ring_topology());
+
+        analyzer.load(OwnershipState {
+            ownerships: (0..12)
+                .map(|tile_id| Ownership {
+                    tile_id,
+                    country_id: if tile_id < 6 { "fr" } else { "de" }.to_string(),
+                    timestamp_ns: 0,
+                })
+                .collect(),
+        });
+
+        let stats = analyzer.territory_stats("fr");
+        assert_eq!(stats.territory_sizes, vec![6]);
+        assert_eq!(stats.frontier_tiles, vec![0, 5]);
+        assert_eq!(analyzer.territory_stats("de").frontier_tiles, vec![6, 11]);
+    }
+}
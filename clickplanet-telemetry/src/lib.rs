@@ -0,0 +1,181 @@
+mod json_log;
+mod stdout_exporter;
+
+use clap::ValueEnum;
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::Resource;
+use thiserror::Error;
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Layer, Registry};
+
+pub use json_log::JsonFormat;
+pub use stdout_exporter::StdoutJsonExporter;
+
+const DEFAULT_GRPC_ENDPOINT: &str = "http://localhost:4317";
+const DEFAULT_HTTP_ENDPOINT: &str = "http://localhost:4318";
+const HTTP_TRACES_PATH: &str = "v1/traces";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum TraceExporter {
+    /// Spans are not recorded
+    None,
+    /// One JSON object per span on the standard output
+    StdoutJson,
+    OtlpGrpc,
+    /// OTLP protobuf over HTTP
+    OtlpHttp,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Pretty,
+    /// One JSON object per event
+    Json,
+}
+
+#[derive(Error, Debug)]
+pub enum TelemetryError {
+    #[error("Failed to build the span exporter: {0}")]
+    Exporter(#[from] TraceError),
+    #[error("Invalid log level: {0}")]
+    LogLevel(#[from] ParseError),
+    #[error("Trace sample ratio must be between 0 and 1, got {0}")]
+    SampleRatio(f64),
+    #[error("Failed to install the subscriber: {0}")]
+    Subscriber(#[from] TryInitError),
+}
+
+/// Logging and tracing options, the same for every binary. Global, to follow subcommands.
+#[derive(clap::Args, Debug, Clone)]
+pub struct TelemetryArgs {
+    #[arg(long, global = true, env = "TRACE_EXPORTER", value_enum, default_value_t = TraceExporter::OtlpGrpc)]
+    pub trace_exporter: TraceExporter,
+
+    /// Collector receiving the spans, `http://localhost:4317` for gRPC and
+    /// `http://localhost:4318` for HTTP by default. Over HTTP, spans are posted to its
+    /// `/v1/traces` path as the OpenTelemetry specification requires
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Name of the service in the traces, the binary name by default
+    #[arg(long, global = true, env = "SERVICE_NAME")]
+    pub service_name: Option<String>,
+
+    /// Share of the traces started by this service that are recorded, the others following
+    /// the decision of their parent
+    #[arg(long, global = true, env = "TRACE_SAMPLE_RATIO", default_value = "1.0")]
+    pub trace_sample_ratio: f64,
+
+    #[arg(long, global = true, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+
+    /// Level or `tracing` filter directives, e.g. `info,clickplanet_server=debug`
+    #[arg(long, global = true, env = "RUST_LOG", default_value = "info")]
+    pub log_level: String,
+}
+
+/// Keeps exporting spans until shut down.
+pub struct Telemetry {
+    tracer_provider: Option<TracerProvider>,
+}
+
+impl Telemetry {
+    /// Exports the spans still buffered, to call before exiting.
+    pub async fn shutdown(self) {
+        let Some(tracer_provider) = self.tracer_provider else {
+            return;
+        };
+
+        // The batch processor blocks until the export is done
+        let result = tokio::task::spawn_blocking(move || tracer_provider.shutdown()).await;
+        match result {
+            Ok(Err(e)) => eprintln!("Failed to flush traces: {}", e),
+            Err(e) => eprintln!("Failed to flush traces: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+/// Installs the global subscriber, logging events and exporting spans as configured.
+/// Exporters other than `none` need a Tokio runtime.
+pub fn init_telemetry(args: &TelemetryArgs, default_service_name: &str) -> Result<Telemetry, TelemetryError> {
+    if !(0.0..=1.0).contains(&args.trace_sample_ratio) {
+        return Err(TelemetryError::SampleRatio(args.trace_sample_ratio));
+    }
+    let service_name = args.service_name.clone().unwrap_or_else(|| default_service_name.to_string());
+
+    let tracer_provider = build_tracer_provider(args, &service_name)?;
+    let trace_layer = tracer_provider
+        .as_ref()
+        .map(|tracer_provider| tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(service_name)));
+
+    let log_layer = match args.log_format {
+        LogFormat::Pretty => fmt::layer()
+            .with_target(false)
+            .with_thread_ids(true)
+            .with_thread_names(true)
+            .with_file(true)
+            .with_line_number(true)
+            .boxed(),
+        LogFormat::Json => fmt::layer().event_format(JsonFormat).boxed(),
+    };
+
+    Registry::default()
+        .with(EnvFilter::try_new(&args.log_level)?)
+        .with(log_layer)
+        .with(trace_layer)
+        .try_init()?;
+
+    Ok(Telemetry { tracer_provider })
+}
+
+fn build_tracer_provider(args: &TelemetryArgs, service_name: &str) -> Result<Option<TracerProvider>, TraceError> {
+    let builder = TracerProvider::builder()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(args.trace_sample_ratio))))
+        .with_resource(Resource::new([KeyValue::new("service.name", service_name.to_string())]));
+
+    let builder = match args.trace_exporter {
+        TraceExporter::None => return Ok(None),
+        TraceExporter::StdoutJson => builder.with_batch_exporter(StdoutJsonExporter, opentelemetry_sdk::runtime::Tokio),
+        TraceExporter::OtlpGrpc => builder.with_batch_exporter(
+            opentelemetry_otlp::SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(args.otlp_endpoint.as_deref().unwrap_or(DEFAULT_GRPC_ENDPOINT))
+                .with_protocol(opentelemetry_otlp::Protocol::Grpc)
+                .build()?,
+            opentelemetry_sdk::runtime::Tokio,
+        ),
+        TraceExporter::OtlpHttp => builder.with_batch_exporter(
+            opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(http_traces_endpoint(args.otlp_endpoint.as_deref().unwrap_or(DEFAULT_HTTP_ENDPOINT)))
+                .with_protocol(opentelemetry_otlp::Protocol::HttpBinary)
+                .build()?,
+            opentelemetry_sdk::runtime::Tokio,
+        ),
+    };
+
+    Ok(Some(builder.build()))
+}
+
+/// The signal path appended to the base URL of `OTEL_EXPORTER_OTLP_ENDPOINT`, which the
+/// exporter takes verbatim.
+fn http_traces_endpoint(endpoint: &str) -> String {
+    format!("{}/{}", endpoint.trim_end_matches('/'), HTTP_TRACES_PATH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_traces_endpoint() {
+        assert_eq!(http_traces_endpoint("http://collector:4318"), "http://collector:4318/v1/traces");
+        assert_eq!(http_traces_endpoint("http://collector:4318/"), "http://collector:4318/v1/traces");
+        assert_eq!(http_traces_endpoint("https://example.com/otlp"), "https://example.com/otlp/v1/traces");
+    }
+}
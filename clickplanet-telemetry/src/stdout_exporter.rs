@@ -0,0 +1,48 @@
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures_util::future::BoxFuture;
+use opentelemetry::trace::TraceError;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use serde_json::{json, Map, Value};
+
+/// Writes finished spans to the standard output, one JSON object per line.
+#[derive(Debug, Default)]
+pub struct StdoutJsonExporter;
+
+impl SpanExporter for StdoutJsonExporter {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let mut stdout = std::io::stdout().lock();
+        let result = batch
+            .iter()
+            .try_for_each(|span| writeln!(stdout, "{}", span_json(span)))
+            .and_then(|_| stdout.flush())
+            .map_err(|e| TraceError::from(e.to_string()));
+
+        Box::pin(std::future::ready(result))
+    }
+}
+
+fn span_json(span: &SpanData) -> Value {
+    let attributes: Map<String, Value> = span
+        .attributes
+        .iter()
+        .map(|attribute| (attribute.key.to_string(), json!(attribute.value.to_string())))
+        .collect();
+
+    json!({
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": span.parent_span_id.to_string(),
+        "name": span.name,
+        "kind": format!("{:?}", span.span_kind),
+        "start_time_unix_nano": unix_nanos(span.start_time),
+        "end_time_unix_nano": unix_nanos(span.end_time),
+        "attributes": attributes,
+        "status": format!("{:?}", span.status),
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos() as u64)
+}
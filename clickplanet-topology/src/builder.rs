@@ -0,0 +1,144 @@
+use rstar::primitives::GeomWithData;
+use rstar::RTree;
+
+use crate::format::{coordinates_checksum, TopologyError};
+use crate::topology::TileTopology;
+
+type IndexedPoint = GeomWithData<[f64; 3], u32>;
+
+/// Builds the tile neighbour graph from tile centre positions.
+///
+/// Each tile is linked to its `max_neighbours` nearest tiles, keeping only the ones
+/// no further than `distance_tolerance` times its closest neighbour: hexagons get 6
+/// links, the 12 icosphere pentagons get 5. Links are then made symmetric.
+#[derive(Debug, Clone)]
+pub struct TopologyBuilder {
+    max_neighbours: usize,
+    distance_tolerance: f64,
+}
+
+impl Default for TopologyBuilder {
+    fn default() -> Self {
+        Self {
+            max_neighbours: 6,
+            distance_tolerance: 1.5,
+        }
+    }
+}
+
+impl TopologyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_neighbours(mut self, max_neighbours: usize) -> Self {
+        self.max_neighbours = max_neighbours;
+        self
+    }
+
+    pub fn with_distance_tolerance(mut self, distance_tolerance: f64) -> Self {
+        self.distance_tolerance = distance_tolerance;
+        self
+    }
+
+    /// Builds the graph from flat `x, y, z` triplets; tile ids are triplet indexes.
+    pub fn build(&self, positions: &[f64]) -> Result<TileTopology, TopologyError> {
+        if !positions.chunks_exact(3).remainder().is_empty() {
+            return Err(TopologyError::InvalidCoordinates(
+                "Positions length is not a multiple of 3".to_string(),
+            ));
+        }
+
+        if self.distance_tolerance < 1.0 {
+            return Err(TopologyError::InvalidCoordinates(format!(
+                "Distance tolerance must be at least 1.0, got {}",
+                self.distance_tolerance
+            )));
+        }
+
+        let points: Vec<IndexedPoint> = positions
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(tile_id, p)| GeomWithData::new([p[0], p[1], p[2]], tile_id as u32))
+            .collect();
+
+        let tree = RTree::bulk_load(points.clone());
+        let mut adjacency: Vec<Vec<u32>> = vec![Vec::new(); points.len()];
+        let tolerance_2 = self.distance_tolerance * self.distance_tolerance;
+
+        for point in &points {
+            let tile_id = point.data;
+
+            let candidates: Vec<(u32, f64)> = tree
+                .nearest_neighbor_iter_with_distance_2(point.geom())
+                .filter(|(other, distance_2)| other.data != tile_id && *distance_2 > 0.0)
+                .take(self.max_neighbours)
+                .map(|(other, distance_2)| (other.data, distance_2))
+                .collect();
+
+            let Some(&(_, closest_2)) = candidates.first() else {
+                continue;
+            };
+
+            for (neighbour, distance_2) in candidates {
+                if distance_2 <= closest_2 * tolerance_2 {
+                    adjacency[tile_id as usize].push(neighbour);
+                    adjacency[neighbour as usize].push(tile_id);
+                }
+            }
+        }
+
+        Ok(TileTopology::from_adjacency(adjacency, coordinates_checksum(positions)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn equator_ring(count: usize) -> Vec<f64> {
+        (0..count)
+            .flat_map(|i| {
+                let angle = 2.0 * PI * i as f64 / count as f64;
+                [angle.cos(), angle.sin(), 0.0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ring_neighbours() {
+        let positions = equator_ring(12);
+        let topology = TopologyBuilder::new().build(&positions).unwrap();
+
+        assert_eq!(topology.tile_count(), 12);
+        assert_eq!(topology.edge_count(), 12);
+        assert_eq!(topology.neighbours(0), &[1, 11]);
+        assert_eq!(topology.neighbours(5), &[4, 6]);
+        assert_eq!(topology.bfs_distance(0, 6), Some(6));
+        assert_eq!(topology.bfs_distance(2, 10), Some(4));
+        assert_eq!(topology.connected_components().len(), 1);
+        assert!(topology.verify_source(&positions).is_ok());
+    }
+
+    #[test]
+    fn test_disjoint_clusters() {
+        let mut positions = equator_ring(8);
+        // A tight triangle near the north pole, far from the ring
+        positions.extend([0.0, 0.0, 1.0, 0.01, 0.0, 1.0, 0.0, 0.01, 1.0]);
+
+        let topology = TopologyBuilder::new().build(&positions).unwrap();
+        let components = topology.connected_components();
+
+        assert_eq!(components.len(), 2);
+        assert_eq!(components[0], (0..8).collect::<Vec<u32>>());
+        assert_eq!(components[1], vec![8, 9, 10]);
+    }
+
+    #[test]
+    fn test_invalid_positions() {
+        assert!(TopologyBuilder::new().build(&[0.0, 1.0]).is_err());
+        assert!(TopologyBuilder::new().with_distance_tolerance(0.5).build(&[]).is_err());
+        assert!(TopologyBuilder::new().build(&[]).unwrap().is_empty());
+    }
+}
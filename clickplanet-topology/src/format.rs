@@ -0,0 +1,254 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::topology::TileTopology;
+
+const MAGIC: &[u8; 4] = b"CPTG";
+pub const TOPOLOGY_FORMAT_VERSION: u16 = 1;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+#[derive(Error, Debug)]
+pub enum TopologyError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid coordinates: {0}")]
+    InvalidCoordinates(String),
+    #[error("Not a tile topology file")]
+    InvalidMagic,
+    #[error("Unsupported topology format version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Corrupted topology data: {0}")]
+    Corrupted(String),
+    #[error("Tile {tile_id} has {degree} neighbours, more than the format allows")]
+    DegreeOverflow { tile_id: u32, degree: usize },
+    #[error("Coordinates checksum mismatch: topology built from {expected:016x}, got {actual:016x}")]
+    ChecksumMismatch { expected: u64, actual: u64 },
+}
+
+/// FNV-1a checksum of flat `x, y, z` tile positions, as stored in `coordinates.json`.
+pub fn coordinates_checksum(positions: &[f64]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+
+    let length = positions.len() as u64;
+    for byte in length.to_le_bytes() {
+        hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+    }
+
+    for value in positions {
+        for byte in value.to_bits().to_le_bytes() {
+            hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    hash
+}
+
+// Layout (little endian):
+//   magic "CPTG" | version u16 | reserved u16 | tile count u32 | neighbour entries u32 | checksum u64
+//   degree u8 * tile count
+//   neighbour id u32 * neighbour entries
+impl TileTopology {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), TopologyError> {
+        let offsets = self.offsets();
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&TOPOLOGY_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(&(self.tile_count() as u32).to_le_bytes())?;
+        writer.write_all(&(self.raw_neighbours().len() as u32).to_le_bytes())?;
+        writer.write_all(&self.source_checksum().to_le_bytes())?;
+
+        let mut degrees = Vec::with_capacity(self.tile_count());
+        for (tile_id, window) in offsets.windows(2).enumerate() {
+            let degree = (window[1] - window[0]) as usize;
+            let degree = u8::try_from(degree).map_err(|_| TopologyError::DegreeOverflow {
+                tile_id: tile_id as u32,
+                degree,
+            })?;
+            degrees.push(degree);
+        }
+        writer.write_all(&degrees)?;
+
+        for neighbour in self.raw_neighbours() {
+            writer.write_all(&neighbour.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, TopologyError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(TopologyError::InvalidMagic);
+        }
+
+        let version = read_u16(reader)?;
+        if version != TOPOLOGY_FORMAT_VERSION {
+            return Err(TopologyError::UnsupportedVersion(version));
+        }
+        let _reserved = read_u16(reader)?;
+
+        let tile_count = read_u32(reader)? as usize;
+        let entry_count = read_u32(reader)? as usize;
+        let source_checksum = read_u64(reader)?;
+
+        let degrees = read_bytes(reader, tile_count as u64)?;
+
+        let mut offsets = Vec::with_capacity(tile_count + 1);
+        let mut total = 0u32;
+        offsets.push(total);
+        for degree in degrees {
+            total = total
+                .checked_add(degree as u32)
+                .ok_or_else(|| TopologyError::Corrupted("more neighbour entries than the format allows".to_string()))?;
+            offsets.push(total);
+        }
+
+        if total as usize != entry_count {
+            return Err(TopologyError::Corrupted(format!(
+                "degrees sum to {} but header announces {} neighbour entries",
+                total, entry_count
+            )));
+        }
+
+        let raw = read_bytes(reader, entry_count as u64 * 4)?;
+        let neighbours: Vec<u32> = raw
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+
+        if let Some(invalid) = neighbours.iter().find(|&&n| n as usize >= tile_count) {
+            return Err(TopologyError::Corrupted(format!(
+                "neighbour {} is out of range for {} tiles",
+                invalid, tile_count
+            )));
+        }
+
+        Ok(Self::from_parts(offsets, neighbours, source_checksum))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TopologyError> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, TopologyError> {
+        Self::read_from(&mut bytes)
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), TopologyError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Self, TopologyError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::read_from(&mut reader)
+    }
+
+    /// Checks that this graph was generated from the given tile positions.
+    pub fn verify_source(&self, positions: &[f64]) -> Result<(), TopologyError> {
+        let actual = coordinates_checksum(positions);
+        if actual != self.source_checksum() {
+            return Err(TopologyError::ChecksumMismatch {
+                expected: self.source_checksum(),
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads `len` bytes, growing the buffer with the data actually read rather than trusting the
+/// length announced by the header.
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, TopologyError> {
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, TopologyError> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, TopologyError> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, TopologyError> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_topology() -> TileTopology {
+        TileTopology::from_adjacency(vec![vec![1, 2], vec![0], vec![0], vec![]], 0xdead_beef)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let topology = sample_topology();
+        let bytes = topology.to_bytes().unwrap();
+
+        // header (24) + degrees (4) + neighbour entries (4 * 4)
+        assert_eq!(bytes.len(), 24 + 4 + 16);
+        assert_eq!(TileTopology::from_bytes(&bytes).unwrap(), topology);
+    }
+
+    #[test]
+    fn test_rejects_invalid_data() {
+        let bytes = sample_topology().to_bytes().unwrap();
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(TileTopology::from_bytes(&bad_magic), Err(TopologyError::InvalidMagic)));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 42;
+        assert!(matches!(TileTopology::from_bytes(&bad_version), Err(TopologyError::UnsupportedVersion(42))));
+
+        assert!(matches!(TileTopology::from_bytes(&bytes[..bytes.len() - 1]), Err(TopologyError::Io(_))));
+
+        // A header announcing more tiles than the file holds
+        let mut bad_tile_count = bytes.clone();
+        bad_tile_count[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(TileTopology::from_bytes(&bad_tile_count), Err(TopologyError::Io(_))));
+    }
+
+    #[test]
+    fn test_checksum() {
+        let positions = [0.0, 1.0, 0.0, 1.0, 0.0, 0.0];
+        let checksum = coordinates_checksum(&positions);
+
+        assert_eq!(checksum, coordinates_checksum(&positions));
+        assert_ne!(checksum, coordinates_checksum(&positions[..3]));
+        assert_ne!(checksum, coordinates_checksum(&[0.0, 1.0, 0.0, 1.0, 0.0, 1e-9]));
+
+        let topology = TileTopology::from_adjacency(vec![vec![1], vec![0]], checksum);
+        assert!(topology.verify_source(&positions).is_ok());
+        assert!(matches!(
+            topology.verify_source(&[0.0; 6]),
+            Err(TopologyError::ChecksumMismatch { .. })
+        ));
+    }
+}
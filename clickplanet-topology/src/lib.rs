@@ -0,0 +1,12 @@
+mod builder;
+mod format;
+mod topology;
+
+pub use builder::TopologyBuilder;
+pub use format::{coordinates_checksum, TopologyError, TOPOLOGY_FORMAT_VERSION};
+pub use topology::TileTopology;
+
+pub mod prelude {
+    pub use super::TileTopology;
+    pub use super::TopologyBuilder;
+}
@@ -0,0 +1,260 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Undirected neighbour graph over tile ids, stored as compressed adjacency lists
+/// (`neighbours[offsets[i]..offsets[i + 1]]` are the sorted neighbours of tile `i`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileTopology {
+    offsets: Vec<u32>,
+    neighbours: Vec<u32>,
+    source_checksum: u64,
+}
+
+impl TileTopology {
+    pub(crate) fn from_adjacency(adjacency: Vec<Vec<u32>>, source_checksum: u64) -> Self {
+        let mut offsets = Vec::with_capacity(adjacency.len() + 1);
+        let mut neighbours = Vec::with_capacity(adjacency.iter().map(|n| n.len()).sum());
+
+        offsets.push(0);
+        for mut tile_neighbours in adjacency {
+            tile_neighbours.sort_unstable();
+            tile_neighbours.dedup();
+            neighbours.extend(tile_neighbours);
+            offsets.push(neighbours.len() as u32);
+        }
+
+        Self {
+            offsets,
+            neighbours,
+            source_checksum,
+        }
+    }
+
+    pub(crate) fn from_parts(offsets: Vec<u32>, neighbours: Vec<u32>, source_checksum: u64) -> Self {
+        Self {
+            offsets,
+            neighbours,
+            source_checksum,
+        }
+    }
+
+    pub(crate) fn offsets(&self) -> &[u32] {
+        &self.offsets
+    }
+
+    pub(crate) fn raw_neighbours(&self) -> &[u32] {
+        &self.neighbours
+    }
+
+    pub fn tile_count(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tile_count() == 0
+    }
+
+    /// Number of undirected edges in the graph.
+    pub fn edge_count(&self) -> usize {
+        self.neighbours.len() / 2
+    }
+
+    /// Checksum of the tile coordinates this graph was built from.
+    pub fn source_checksum(&self) -> u64 {
+        self.source_checksum
+    }
+
+    pub fn contains(&self, tile_id: u32) -> bool {
+        (tile_id as usize) < self.tile_count()
+    }
+
+    /// Sorted neighbours of a tile, empty for unknown tiles.
+    pub fn neighbours(&self, tile_id: u32) -> &[u32] {
+        if !self.contains(tile_id) {
+            return &[];
+        }
+
+        let start = self.offsets[tile_id as usize] as usize;
+        let end = self.offsets[tile_id as usize + 1] as usize;
+        &self.neighbours[start..end]
+    }
+
+    pub fn degree(&self, tile_id: u32) -> usize {
+        self.neighbours(tile_id).len()
+    }
+
+    pub fn are_neighbours(&self, tile_a: u32, tile_b: u32) -> bool {
+        self.neighbours(tile_a).binary_search(&tile_b).is_ok()
+    }
+
+    /// Number of hops between two tiles, `None` if they are not connected.
+    pub fn bfs_distance(&self, from: u32, to: u32) -> Option<u32> {
+        if !self.contains(from) || !self.contains(to) {
+            return None;
+        }
+
+        if from == to {
+            return Some(0);
+        }
+
+        let mut visited = vec![false; self.tile_count()];
+        let mut queue = VecDeque::new();
+        visited[from as usize] = true;
+        queue.push_back((from, 0u32));
+
+        while let Some((tile_id, distance)) = queue.pop_front() {
+            for &neighbour in self.neighbours(tile_id) {
+                if neighbour == to {
+                    return Some(distance + 1);
+                }
+
+                if !visited[neighbour as usize] {
+                    visited[neighbour as usize] = true;
+                    queue.push_back((neighbour, distance + 1));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Every tile reachable from `source` in at most `max_distance` hops, with its distance.
+    pub fn tiles_within(&self, source: u32, max_distance: u32) -> HashMap<u32, u32> {
+        let mut distances = HashMap::new();
+        if !self.contains(source) {
+            return distances;
+        }
+
+        let mut queue = VecDeque::new();
+        distances.insert(source, 0);
+        queue.push_back(source);
+
+        while let Some(tile_id) = queue.pop_front() {
+            let distance = distances[&tile_id];
+            if distance == max_distance {
+                continue;
+            }
+
+            for &neighbour in self.neighbours(tile_id) {
+                if let Entry::Vacant(entry) = distances.entry(neighbour) {
+                    entry.insert(distance + 1);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+
+        distances
+    }
+
+    /// Tiles connected to `start` through tiles accepted by `member` (including `start`).
+    pub fn component_containing<F>(&self, start: u32, member: F) -> Vec<u32>
+    where
+        F: Fn(u32) -> bool,
+    {
+        if !self.contains(start) || !member(start) {
+            return Vec::new();
+        }
+
+        let mut visited = HashSet::new();
+        let mut component = Vec::new();
+        let mut queue = VecDeque::new();
+        visited.insert(start);
+        queue.push_back(start);
+
+        while let Some(tile_id) = queue.pop_front() {
+            component.push(tile_id);
+
+            for &neighbour in self.neighbours(tile_id) {
+                if member(neighbour) && visited.insert(neighbour) {
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+
+        component.sort_unstable();
+        component
+    }
+
+    /// Connected components of the subgraph induced by `tiles`, largest first.
+    pub fn components_within(&self, tiles: &HashSet<u32>) -> Vec<Vec<u32>> {
+        let mut remaining: HashSet<u32> = tiles.iter().copied().filter(|&t| self.contains(t)).collect();
+        let mut components = Vec::new();
+
+        while let Some(&start) = remaining.iter().next() {
+            let component = self.component_containing(start, |tile_id| tiles.contains(&tile_id));
+            for tile_id in &component {
+                remaining.remove(tile_id);
+            }
+            components.push(component);
+        }
+
+        components.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a[0].cmp(&b[0])));
+        components
+    }
+
+    /// Connected components of the whole graph, largest first.
+    pub fn connected_components(&self) -> Vec<Vec<u32>> {
+        let all_tiles: HashSet<u32> = (0..self.tile_count() as u32).collect();
+        self.components_within(&all_tiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0 - 1 - 2 - 3    4 - 5
+    fn path_topology() -> TileTopology {
+        TileTopology::from_adjacency(
+            vec![vec![1], vec![0, 2], vec![1, 3], vec![2], vec![5], vec![4]],
+            0,
+        )
+    }
+
+    #[test]
+    fn test_neighbours_and_degree() {
+        let topology = path_topology();
+
+        assert_eq!(topology.tile_count(), 6);
+        assert_eq!(topology.edge_count(), 4);
+        assert_eq!(topology.neighbours(1), &[0, 2]);
+        assert_eq!(topology.degree(3), 1);
+        assert!(topology.are_neighbours(4, 5));
+        assert!(!topology.are_neighbours(3, 4));
+        assert!(topology.neighbours(42).is_empty());
+    }
+
+    #[test]
+    fn test_bfs_distance() {
+        let topology = path_topology();
+
+        assert_eq!(topology.bfs_distance(0, 0), Some(0));
+        assert_eq!(topology.bfs_distance(0, 3), Some(3));
+        assert_eq!(topology.bfs_distance(3, 1), Some(2));
+        assert_eq!(topology.bfs_distance(0, 5), None);
+        assert_eq!(topology.bfs_distance(0, 42), None);
+    }
+
+    #[test]
+    fn test_tiles_within() {
+        let topology = path_topology();
+        let within = topology.tiles_within(1, 1);
+
+        assert_eq!(within.len(), 3);
+        assert_eq!(within.get(&1), Some(&0));
+        assert_eq!(within.get(&0), Some(&1));
+        assert_eq!(within.get(&2), Some(&1));
+    }
+
+    #[test]
+    fn test_components() {
+        let topology = path_topology();
+
+        assert_eq!(topology.connected_components(), vec![vec![0, 1, 2, 3], vec![4, 5]]);
+
+        let owned: HashSet<u32> = [0, 2, 3, 5].into_iter().collect();
+        assert_eq!(topology.components_within(&owned), vec![vec![2, 3], vec![0], vec![5]]);
+        assert_eq!(topology.component_containing(3, |t| owned.contains(&t)), vec![2, 3]);
+        assert!(topology.component_containing(1, |t| owned.contains(&t)).is_empty());
+    }
+}
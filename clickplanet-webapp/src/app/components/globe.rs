@@ -1,7 +1,16 @@
 use dioxus::prelude::*;
 
+#[derive(Props, PartialEq, Clone)]
+pub struct GlobeMockProps {
+    /// Sends a click on the tile for the selected country
+    pub on_click_tile: Callback<u32>,
+}
+
 // Updated for Dioxus 0.6.x compatibility
-pub fn GlobeMock() -> Element {
+#[component]
+pub fn GlobeMock(props: GlobeMockProps) -> Element {
+    let mut tile_id = use_signal(|| None::<u32>);
+
     rsx! {
         div { 
             class: "globe-container",
@@ -12,6 +21,24 @@ pub fn GlobeMock() -> Element {
                 h2 { "Globe Component" }
                 p { "This is a temporary placeholder while the interactive globe is being fixed." }
                 p { "The full interactive globe will be restored soon." }
+                p { "Until then, tiles can be clicked by id." }
+                input {
+                    r#type: "number",
+                    min: "0",
+                    max: "{i32::MAX}",
+                    placeholder: "Tile id",
+                    // Tile ids are sent as an i32, larger ones are not taken
+                    oninput: move |event| tile_id.set(event.value().parse().ok().filter(|tile_id| i32::try_from(*tile_id).is_ok())),
+                }
+                button {
+                    disabled: tile_id().is_none(),
+                    onclick: move |_| {
+                        if let Some(tile_id) = tile_id() {
+                            props.on_click_tile.call(tile_id);
+                        }
+                    },
+                    "Click"
+                }
             }
         }
     }
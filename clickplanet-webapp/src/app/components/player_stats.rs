@@ -0,0 +1,92 @@
+use dioxus::prelude::*;
+use clickplanet_proto::clicks::PlayerStatsResponse;
+use crate::app::components::modal_manager::ModalManager;
+use crate::app::components::block_button::BlockButtonProps;
+use crate::app::countries::Country;
+use crate::backends::http_backend::ClickServiceClient;
+
+#[derive(Props, PartialEq, Clone)]
+pub struct PlayerStatsProps {
+    /// Client of the globe, so that the stats are those of the session clicking
+    pub client: ClickServiceClient,
+    pub country: Country,
+}
+
+/// Hours, minutes and seconds, leaving out the leading zeros
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m {}s", minutes, seconds),
+        (hours, minutes, _) => format!("{}h {}m", hours, minutes),
+    }
+}
+
+/// What the player achieved in their session, fetched each time the modal is opened
+#[component]
+pub fn PlayerStats(props: PlayerStatsProps) -> Element {
+    let mut stats = use_signal(|| None::<Result<PlayerStatsResponse, String>>);
+
+    let client = props.client.clone();
+    let country_code = props.country.code.clone();
+    let load_stats = move |_| {
+        let client = client.clone();
+        let country_code = country_code.clone();
+        stats.set(None);
+        spawn(async move {
+            let result = client.player_stats(&country_code).await.map_err(|e| e.to_string());
+            stats.set(Some(result));
+        });
+    };
+
+    rsx! {
+        ModalManager {
+            open_by_default: false,
+            modal_title: "My stats".to_string(),
+            button_props: BlockButtonProps {
+                on_click: Callback::new(load_stats),
+                text: "My stats".to_string(),
+                image_url: String::new(),
+                class_name: Some("button-stats".to_string()),
+            },
+            close_button_text: Some("Back".to_string()),
+            modal_children: match stats() {
+                None => rsx! { p { class: "center-align", "Loading..." } },
+                Some(Err(e)) => rsx! { p { class: "center-align", "Could not load your stats: {e}" } },
+                Some(Ok(stats)) => rsx! {
+                    table { class: "player-stats-table",
+                        tbody {
+                            tr { td { "Clicks" } td { class: "leaderboard-table-number", "{stats.clicks}" } }
+                            tr { td { "Tiles captured" } td { class: "leaderboard-table-number", "{stats.captures}" } }
+                            tr { td { "Tiles held" } td { class: "leaderboard-table-number", "{stats.tiles_held}" } }
+                            if stats.longest_held_secs > 0 {
+                                tr {
+                                    td { "Longest held tile" }
+                                    td { class: "leaderboard-table-number",
+                                        "#{stats.longest_held_tile_id}, {format_duration(stats.longest_held_secs)}"
+                                        if stats.longest_hold_ongoing { " and counting" }
+                                    }
+                                }
+                            }
+                            if !stats.favourite_battleground.is_empty() {
+                                tr {
+                                    td { "Favourite battleground" }
+                                    td { class: "leaderboard-table-number",
+                                        img {
+                                            class: "country-flag",
+                                            src: "/public/static/countries/svg/{stats.favourite_battleground}.svg",
+                                            alt: "{stats.favourite_battleground} flag",
+                                            width: "20px",
+                                            height: "auto",
+                                            style: "margin-right: 8px;"
+                                        }
+                                        "{stats.favourite_battleground_clicks} clicks"
+                                    }
+                                }
+                            }
+                        }
+                    }
+                },
+            },
+        }
+    }
+}
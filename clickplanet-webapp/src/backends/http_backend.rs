@@ -1,11 +1,12 @@
 use base64::Engine;
 use std::collections::HashMap;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 
 use crate::backends::backend::{Ownerships, OwnershipsGetter, TileClicker, Update, UpdatesListener};
 use anyhow::Result;
 use base64::engine::general_purpose;
-use clickplanet_proto::clicks::{BatchRequest, ClickRequest, OwnershipState};
+use clickplanet_proto::clicks::{BatchRequest, ClickRequest, OwnershipState, PlayerStatsResponse, SessionRequest, SessionResponse};
 use prost::Message;
 use uuid::Uuid;
 
@@ -17,18 +18,36 @@ use wasm_bindgen_futures::spawn_local;
 use web_sys::{MessageEvent, WebSocket};
 use js_sys::{Uint8Array, ArrayBuffer};
 
-#[derive(Clone)]
+/// Local storage key of the session token, kept across visits
+const SESSION_STORAGE_KEY: &str = "clickplanet-session";
+/// Sessions are renewed this long before they expire
+const SESSION_RENEWAL_MARGIN_SECS: u64 = 3600;
+
+/// A 401: the session token was refused, e.g. signed by a server that restarted since
+#[derive(Debug)]
+struct Unauthorized;
+
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Session refused")
+    }
+}
+
+impl std::error::Error for Unauthorized {}
+
+#[derive(Clone, PartialEq)]
 pub struct ClickServiceClient {
     pub config: Config,
 }
 
 impl ClickServiceClient {
-    pub async fn fetch(&self, verb: &str, path: &str, body: Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+    /// `token` is the session token, for the calls that need one.
+    pub async fn fetch(&self, verb: &str, path: &str, body: Option<&[u8]>, token: Option<&str>) -> Result<Option<Vec<u8>>> {
         let url = format!("{}{}", self.config.base_url, path);
         let config = self.config.timeout_ms;
         // retry loop
         for _ in 0..5 {
-            let res = self.make_request(verb, &url, body, config).await;
+            let res = self.make_request(verb, &url, body, token, config).await;
             if let Ok(r) = res {
                 return r;
             }
@@ -38,24 +57,29 @@ impl ClickServiceClient {
     }
 
 
-    async fn make_request(&self, verb: &str, url: &str, body: Option<&[u8]>, _timeout_ms: u32) -> Result<Result<Option<Vec<u8>>>> {
-        let request_builder = match verb {
+    async fn make_request(&self, verb: &str, url: &str, body: Option<&[u8]>, token: Option<&str>, _timeout_ms: u32) -> Result<Result<Option<Vec<u8>>>> {
+        let mut request_builder = match verb {
             "POST" => Request::post(url),
             _ => Request::get(url),
         };
-        
-        // Handle body if present - convert bytes to JS ArrayBuffer
+
+        if let Some(token) = token {
+            request_builder = request_builder.header("Authorization", &format!("Bearer {}", token));
+        }
+
+        // The server takes the protobuf bytes as the data of a JSON object
         let request = if let Some(b) = body {
-            // Create a Uint8Array from the byte slice
-            let uint8_array = js_sys::Uint8Array::new(&unsafe { js_sys::Uint8Array::view(b) }.into());
-            // Convert to ArrayBuffer which can be used with gloo-net
-            let array_buffer = uint8_array.buffer();
-            request_builder.body(array_buffer)?
+            request_builder
+                .header("Content-Type", "application/json")
+                .body(serde_json::json!({ "data": b }).to_string())?
         } else {
             request_builder.build()?
         };
 
         let res = request.send().await?;
+        if res.status() == 401 {
+            return Ok(Err(Unauthorized.into()));
+        }
         if !res.ok() {
             return Ok(Err(anyhow::anyhow!(
                 "Failed to fetch: {}",
@@ -72,6 +96,73 @@ impl ClickServiceClient {
             general_purpose::STANDARD.decode(s).unwrap()
         })))
     }
+
+    /// Token of the session kept in the local storage, renewed for `country_id` when about to
+    /// expire or bound to another country. A new session is opened when none is kept.
+    pub async fn session_token(&self, country_id: &str) -> Result<String> {
+        let stored = load_session();
+        let now_secs = (js_sys::Date::now() / 1000.0) as u64;
+
+        if let Some(session) = &stored {
+            if session.country_id == country_id && session.expires_at_secs > now_secs + SESSION_RENEWAL_MARGIN_SECS {
+                return Ok(session.token.clone());
+            }
+        }
+
+        let request = SessionRequest {
+            token: stored.map(|session| session.token).unwrap_or_default(),
+            country_id: country_id.to_string(),
+        };
+        let response = self.fetch("POST", "/v2/rpc/session", Some(&request.encode_to_vec()), None).await?
+            .ok_or_else(|| anyhow::anyhow!("Empty session response"))?;
+
+        let session = SessionResponse::decode(response.as_slice())?;
+        save_session(&session);
+        Ok(session.token)
+    }
+
+    /// Calls `path` with the session token of `country_id`. A refused token is dropped and the
+    /// call made once more with a new session, as it would be refused until it expires.
+    pub async fn fetch_with_session(&self, verb: &str, path: &str, body: Option<&[u8]>, country_id: &str) -> Result<Option<Vec<u8>>> {
+        let token = self.session_token(country_id).await?;
+        match self.fetch(verb, path, body, Some(&token)).await {
+            Err(e) if e.is::<Unauthorized>() => {
+                forget_session();
+                let token = self.session_token(country_id).await?;
+                self.fetch(verb, path, body, Some(&token)).await
+            }
+            result => result,
+        }
+    }
+
+    /// Statistics of the session playing for `country_id`, the one the clicks are sent with.
+    pub async fn player_stats(&self, country_id: &str) -> Result<PlayerStatsResponse> {
+        let response = self.fetch_with_session("GET", "/v2/rpc/me/stats", None, country_id).await?
+            .ok_or_else(|| anyhow::anyhow!("Empty statistics response"))?;
+
+        Ok(PlayerStatsResponse::decode(response.as_slice())?)
+    }
+}
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+fn load_session() -> Option<SessionResponse> {
+    let encoded = local_storage()?.get_item(SESSION_STORAGE_KEY).ok()??;
+    SessionResponse::decode(general_purpose::STANDARD.decode(encoded).ok()?.as_slice()).ok()
+}
+
+fn save_session(session: &SessionResponse) {
+    if let Some(storage) = local_storage() {
+        let _ = storage.set_item(SESSION_STORAGE_KEY, &general_purpose::STANDARD.encode(session.encode_to_vec()));
+    }
+}
+
+fn forget_session() {
+    if let Some(storage) = local_storage() {
+        let _ = storage.remove_item(SESSION_STORAGE_KEY);
+    }
 }
 
 #[derive(Clone)]
@@ -119,15 +210,26 @@ impl HTTPBackend {
 
 impl TileClicker for HTTPBackend {
     fn click_tile(&mut self, tile_id: u32, country_id: String) -> () {
+        // Tile ids are sent as an i32
+        let Ok(tile_id) = i32::try_from(tile_id) else {
+            log::warn!("Cannot click tile {}: tile ids go up to {}", tile_id, i32::MAX);
+            return;
+        };
         let payload = ClickRequest {
-            tile_id: tile_id.try_into().unwrap(),
+            tile_id,
             country_id,
         };
         let mut buf = Vec::new();
 
         payload.encode(&mut buf).unwrap();
         // dbg!(&buf);
-        let _ = self.client.fetch("POST", "/v2/rpc/click", Some(buf.as_slice()));
+        let client = self.client.clone();
+        spawn_local(async move {
+            let clicked = client.fetch_with_session("POST", "/v2/rpc/click", Some(buf.as_slice()), &payload.country_id).await;
+            if let Err(e) = clicked {
+                log::warn!("Failed to click tile {}: {}", tile_id, e);
+            }
+        });
     }
 }
 
@@ -241,7 +343,7 @@ fn init_websocket(url: &str, mut callback: Box<dyn FnMut(Vec<u8>)>) -> WebSocket
     ws
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Config {
     pub base_url: String,
     pub timeout_ms: u32,
@@ -5,10 +5,10 @@ mod app {
     mod countries;
 }
 
+// The HTTP backend is only used by the app
 mod backends {
     mod backend;
     mod fake_backend;
-    mod http_backend;
 }
 
 use wasm_bindgen::prelude::*;
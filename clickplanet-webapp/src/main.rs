@@ -2,6 +2,8 @@ use dioxus::prelude::*;
 use dioxus_web::launch;
 use std::cell::RefCell;
 use crate::app::countries::Country;
+use crate::backends::http_backend::{ClickServiceClient, Config, HTTPBackend};
+use crate::backends::TileClicker;
 
 mod app {
     pub mod components {
@@ -17,6 +19,7 @@ mod app {
         pub mod settings;
         pub mod leaderboard;
         pub mod about;
+        pub mod player_stats;
     }
     pub mod countries;
     pub mod viewer;
@@ -24,6 +27,13 @@ mod app {
 
 mod backends;
 
+/// Server of the API calls, set when building with `CLICKPLANET_API_URL`. The paths are
+/// relative to the origin serving the app otherwise, as the all-in-one server does.
+const API_BASE_URL: &str = match option_env!("CLICKPLANET_API_URL") {
+    Some(base_url) => base_url,
+    None => "",
+};
+
 fn main() {
     console_log::init_with_level(log::Level::Debug).expect("Unable to initialize console_log");
     
@@ -126,6 +136,15 @@ fn HomeScreen() -> Element {
     let set_country = move |new_country: Country| {
         country.set(new_country);
     };
+
+    // One client for the clicks and the stats, which are those of its session
+    let client = use_hook(|| ClickServiceClient {
+        config: Config { base_url: API_BASE_URL.to_string(), timeout_ms: 5000 },
+    });
+    let backend = use_hook(|| HTTPBackend::new(client.clone(), 0));
+    let click_tile = move |tile_id: u32| {
+        backend.clone().click_tile(tile_id, country().code);
+    };
     
     rsx! {
         div { class: "container",
@@ -155,7 +174,9 @@ fn HomeScreen() -> Element {
             }
             
             // Main globe container
-            app::components::globe::GlobeMock {}
+            app::components::globe::GlobeMock {
+                on_click_tile: Callback::new(click_tile),
+            }
             
             // Menu with leaderboard and settings
             div { class: "menu",
@@ -165,6 +186,10 @@ fn HomeScreen() -> Element {
                         country: country(),
                         set_country: Callback::new(set_country),
                     }
+                    app::components::player_stats::PlayerStats {
+                        client: client.clone(),
+                        country: country(),
+                    }
                     app::components::about::About {}
                     app::components::discord_button::DiscordButton {
                         message: Some("Join us on Discord".to_string()),